@@ -23,14 +23,12 @@ pub fn extract_dominant_frequencies(
         panic!("ERROR: End time exceeds the length of the audio file");
     }
 
-    let samples: &[f32];
-
-    if (start_ms.is_none() && end_ms.is_none()) || (start_ms == Some(0) && end_ms == Some(0)) {
+    let samples: &[f32] = if (start_ms.is_none() && end_ms.is_none()) || (start_ms == Some(0) && end_ms == Some(0)) {
         println!("WARNING: No start or end time provided, processing the entire audio file.");
-        samples = &all_samples;
+        &all_samples
     }  else {
-        samples = &all_samples[start_sample..end_sample];
-    }
+        &all_samples[start_sample..end_sample]
+    };
 
     // Calculate the number of samples per segment
     let samples_per_segment = (sample_rate as f32 * segment_ms as f32 / 1000.0) as usize;
@@ -57,7 +55,7 @@ pub fn extract_dominant_frequencies(
 
         let freqs = indexed.iter()
             .take(top_n)
-            .map(|&(i, _)| (i as f32 * sample_rate as f32 / samples_per_segment as f32))
+            .map(|&(i, _)| i as f32 * sample_rate as f32 / samples_per_segment as f32)
             .collect::<Vec<f32>>();
 
         dominant_freqs_per_segment.push(freqs);
@@ -65,7 +63,7 @@ pub fn extract_dominant_frequencies(
         let least_freqs = indexed.iter()
             .rev()
             .take(top_n)
-            .map(|&(i, _)| (i as f32 * sample_rate as f32 / samples_per_segment as f32))
+            .map(|&(i, _)| i as f32 * sample_rate as f32 / samples_per_segment as f32)
             .collect::<Vec<f32>>();
         least_dominant_freqs_per_segment.push(least_freqs);
     }
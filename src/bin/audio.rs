@@ -1,7 +1,7 @@
 use std::fs;
 use argparse::{ArgumentParser, Store};
 
-use tai_projects::{audio_reader, finite_context_model::FiniteContextModel, ncd};
+use tai_projects::{audio_reader, finite_context_model::FiniteContextModel, model_saver_loader, ncd};
 
 fn flatten_freqs(freqs: Vec<Vec<f32>>) -> String {
     freqs.iter()
@@ -19,6 +19,8 @@ fn main() {
     let mut compressor = "gz".to_string();
     let mut start_ms = 0;
     let mut end_ms = 0;
+    let mut save_model_path = String::new();
+    let mut load_model_path = String::new();
 
     {
         let mut argument_parser: ArgumentParser<'_> = ArgumentParser::new();
@@ -48,7 +50,7 @@ fn main() {
 
         // Compressor
         argument_parser.refer(&mut compressor)
-            .add_option(&["-c"], Store, "Compressor to use (gz, bz2, xz, zstd, lzma) (default: gz)");
+            .add_option(&["-c"], Store, "Compressor to use (gz, bz2, xz, zstd, lzma, fcm) (default: gz)");
 
         // Start time of the sample in milliseconds
         argument_parser.refer(&mut start_ms)
@@ -58,42 +60,55 @@ fn main() {
         argument_parser.refer(&mut end_ms)
             .add_option(&["--end"], Store, "End time (in milliseconds) of the sample segment");
 
+        // Model persistence (fcm compressor only)
+        argument_parser.refer(&mut save_model_path)
+            .add_option(&["--save-model"], Store, "Save the model trained on the sample to this path (only with -c fcm)");
+
+        argument_parser.refer(&mut load_model_path)
+            .add_option(&["--load-model"], Store, "Load the model from this path instead of training it on the sample (only with -c fcm)");
+
         argument_parser.parse_args_or_exit();
     }
 
     // Check if the sample path is a valid .wav file and it exists
-    if !sample_path.ends_with(".wav") && !fs::metadata(&sample_path).is_ok() {
+    if !sample_path.ends_with(".wav") && fs::metadata(&sample_path).is_err() {
         println!("ERROR: Sample path must be a .wav file");
         return;
     }
 
     // Check if the music directory exists and is a directory
-    if !fs::metadata(&musics_dir).is_ok() {
+    if fs::metadata(&musics_dir).is_err() {
         println!("ERROR: Music directory does not exist or is not a directory");
         return;
     }
 
     // Check if the segment length is a valid positive integer
-    if segment_ms <= 0 {
+    if segment_ms == 0 {
         println!("ERROR: Segment length must be a positive integer");
         return;
     }
 
     // Check if the top N frequencies is a valid positive integer
-    if top_n <= 0 {
+    if top_n == 0 {
         println!("ERROR: Top N frequencies must be a positive integer");
         return;
     }
 
     // Check if the top K closest music files is a valid positive integer
-    if top_k <= 0 {
+    if top_k == 0 {
         println!("ERROR: Top K closest music files must be a positive integer");
         return;
     }
 
     // Check if the compressor is valid
-    if !["gz", "bz2", "xz", "zstd", "lzma"].contains(&compressor.as_str()) {
-        println!("ERROR: Compressor must be one of gz, bz2, xz, zstd, lzma");
+    if !["gz", "bz2", "xz", "zstd", "lzma", "fcm"].contains(&compressor.as_str()) {
+        println!("ERROR: Compressor must be one of gz, bz2, xz, zstd, lzma, fcm");
+        return;
+    }
+
+    // Check if the model options are used with the fcm compressor
+    if (!save_model_path.is_empty() || !load_model_path.is_empty()) && &compressor != "fcm" {
+        println!("ERROR: --save-model and --load-model can only be used with the fcm compressor");
         return;
     }
 
@@ -113,13 +128,36 @@ fn main() {
     let query_std = flatten_freqs(samples_freqs.get("dominant").unwrap().clone());
 
     let mut model: Option<FiniteContextModel> = None;
-    if &compressor == "fcm" {
+    if &compressor == "fcm" && !load_model_path.is_empty() {
+        match model_saver_loader::load_model::<FiniteContextModel>(&load_model_path) {
+            Ok(fcm) => {
+                if let Ok(metadata) = model_saver_loader::load_metadata(&load_model_path) {
+                    if metadata.was_trained_on(&sample_path) {
+                        println!("WARNING: The model was trained on the same sample it is now scoring");
+                    }
+                }
+                model = Some(fcm);
+            }
+            Err(e) => {
+                println!("ERROR: Failed to load model: {}", e);
+                return;
+            }
+        }
+    } else if &compressor == "fcm" {
         let k: usize = 8;
         let alpha = 0.5;
         let mut fcm = FiniteContextModel::new(k, alpha);
         for char in query_std.chars() {
             fcm.train_char(char);
         }
+        if !save_model_path.is_empty() {
+            let saved = model_saver_loader::ModelMetadata::new(&fcm, Some(&sample_path))
+                .and_then(|metadata| model_saver_loader::save_model_with_metadata(&fcm, &save_model_path, false, &metadata));
+            match saved {
+                Ok(()) => println!("Model saved as {}", save_model_path),
+                Err(e) => println!("ERROR: Failed to save model: {}", e),
+            }
+        }
         model = Some(fcm);
     }
 
@@ -1,5 +1,6 @@
 extern crate argparse;
 
+use std::io;
 use tai_projects::{chart_generator::ChartGenerator, file_reader};
 use argparse::{ArgumentParser, Store};
 
@@ -14,7 +15,7 @@ fn main() {
 
         // File path
         argument_parser.refer(&mut file_path)
-            .add_argument("File Path", Store, "Path to the file to be analysed")
+            .add_argument("File Path", Store, "Path to the file to be analysed (- for stdin, .gz, .bz2, .xz and .zst are decompressed)")
             .required();
 
         // Smoothing parameter - alpha
@@ -28,23 +29,26 @@ fn main() {
         argument_parser.parse_args_or_exit();
     }
 
-    let mut file_reader_struct = file_reader::FileReader{
-        filename:String::from(file_path),
-        reader:Option::None,
-        buffer: Vec::new(),
-    };
+    let mut file_reader_struct = file_reader::FileReader::new(&file_path);
 
-    if !file_reader::open_file(&mut file_reader_struct).is_ok(){
+    if file_reader::open_file(&mut file_reader_struct).is_err(){
         println!("error Reading File");
         return;
     }
 
     let mut v: Vec<char> = Vec::new();
-    
+
+    // stdin can only be read once, so its text is kept for the second pass
+    let from_stdin = file_reader_struct.filename == "-";
+    let mut stdin_text = String::new();
+
     while let Ok(Some(symbol)) = file_reader::read_char(&mut file_reader_struct) {
         if !v.contains(&symbol) {
             v.push(symbol);
         }
+        if from_stdin {
+            stdin_text.push(symbol);
+        }
     }
 
     println!("{}", v.len());
@@ -53,7 +57,11 @@ fn main() {
     file_reader_struct.reader = None;
     let mut prev_symbol: Option<char> = None;
 
-    file_reader::open_file(&mut file_reader_struct).unwrap();
+    if from_stdin {
+        file_reader::open_reader(&mut file_reader_struct, io::Cursor::new(stdin_text.into_bytes())).unwrap();
+    } else {
+        file_reader::open_file(&mut file_reader_struct).unwrap();
+    }
 
     while let Ok(Some(symbol)) = file_reader::read_char(&mut file_reader_struct) {
         if let Some(prev) = prev_symbol {
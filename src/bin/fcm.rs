@@ -2,15 +2,20 @@ extern crate argparse;
 extern crate serde;
 extern crate serde_json;
 
-use tai_projects::{finite_context_model::FiniteContextModel, *};
-use argparse::{ArgumentParser, Store};
-use model_saver_loader::save_model;
+use std::borrow::Cow;
+use tai_projects::{file_reader::InvalidUtf8Policy, finite_context_model::FiniteContextModel, symbol_encoder::{SymbolEncoder, SymbolOptions}, *};
+use argparse::{ArgumentParser, Store, StoreTrue};
+use model_saver_loader::{save_model_with_metadata, ModelMetadata};
 
 fn main() {
 
     let mut file_path: String = "".to_string();
     let mut k_value: usize = 3;
     let mut alpha: f64 = 0.01;
+    let mut model_output = String::new();
+    let mut compress_model = false;
+    let mut invalid_utf8 = InvalidUtf8Policy::Error;
+    let mut symbol_options = SymbolOptions::default();
 
     {
         let mut argument_parser: ArgumentParser<'_> = ArgumentParser::new();
@@ -18,7 +23,7 @@ fn main() {
 
         // File path
         argument_parser.refer(&mut file_path)
-            .add_argument("File Path", Store, "Path to the file to be analysed")
+            .add_argument("File Path", Store, "Path to the file to be analysed (- for stdin, .gz, .bz2, .xz and .zst are decompressed)")
             .required();
 
         // Size of the sliding window - k
@@ -29,51 +34,82 @@ fn main() {
         argument_parser.refer(&mut alpha)
             .add_option(&["-a"], Store, "Smoothing parameter");
 
+        // Model output path, the format is given by the extension
+        argument_parser.refer(&mut model_output)
+            .add_option(&["-o"], Store, "Path to save the trained model: .json, .bson or binary for any other extension (default: models/trained_model.json and models/trained_model.bson)");
+
+        argument_parser.refer(&mut compress_model)
+            .add_option(&["--zstd"], StoreTrue, "Compress the binary model with zstd");
+
+        // What to do with bytes that are not valid UTF-8
+        argument_parser.refer(&mut invalid_utf8)
+            .add_option(&["--invalid-utf8"], Store, "Invalid UTF-8 bytes: error, skip or replace with U+FFFD (default: error)");
+
+        // Symbols of the model
+        argument_parser.refer(&mut symbol_options.normalization)
+            .add_option(&["--normalize"], Store, "Unicode normalization of the text: none, nfc or nfd (default: none)");
+
+        argument_parser.refer(&mut symbol_options.case_fold)
+            .add_option(&["--case-fold"], StoreTrue, "Lowercase the text before training");
+
+        argument_parser.refer(&mut symbol_options.graphemes)
+            .add_option(&["--graphemes"], StoreTrue, "Use grapheme clusters as symbols instead of Unicode scalar values");
+
         argument_parser.parse_args_or_exit();
     }
 
-    let mut file_reader_struct = file_reader::FileReader{
-        filename: String::from(file_path),
-        reader: Option::None,
-        buffer: Vec::new(),
+    let mut file_reader_struct = file_reader::FileReader::new(&file_path);
+    file_reader_struct.invalid_utf8 = invalid_utf8;
+
+    let text = match file_reader::read_text(&mut file_reader_struct) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Error reading file: {}", e);
+            return;
+        }
     };
 
-    if !file_reader::open_file(&mut file_reader_struct).is_ok(){
-        println!("error ReadingFile");
-        return;
-    }
+    let text = text.as_str();
 
     let mut model = FiniteContextModel::new(k_value, alpha);
-    let mut text_length: usize = 0;
-    let mut total_info: f64 = 0.0;
-
-    loop {
-        match file_reader::read_char(&mut file_reader_struct) {
-            Ok(Some(char)) => {
-                model.train_char(char);
-            }
-            Ok(None) => break,
-            Err(e) => {
-                eprintln!("Error reading file: {}", e);
-                break;
-            }
-        }
-    }
+    let mut encoder = SymbolEncoder::new(symbol_options);
 
-    let output_filename = "trained_model";
-    save_model(&model, output_filename);
+    // Without symbol options the text is used as it is, so a mapped file is never copied
+    let symbols: Cow<str> = if encoder.is_identity() {
+        Cow::Borrowed(text)
+    } else {
+        Cow::Owned(encoder.encode_all(text))
+    };
 
-    file_reader_struct.reader = None;
-    file_reader::open_file(&mut file_reader_struct).unwrap();
-    let mut text_buff = String::new();
-    let size = 200;
+    symbols.chars().for_each(|symbol| model.train_char(symbol));
+
+    if encoder.get_cluster_count() > 0 {
+        println!("Grapheme clusters with their own symbol: {}", encoder.get_cluster_count());
+    }
 
-    while let Ok(bytes_read) = file_reader::read_buff(&mut file_reader_struct, &mut text_buff, size) {
-        if bytes_read == 0 {
-            break;
+    // Without -o the model is saved as both JSON and BSON, as it always was
+    let output_paths = if model_output.is_empty() {
+        vec!["models/trained_model.json".to_string(), "models/trained_model.bson".to_string()]
+    } else {
+        vec![model_output]
+    };
+    for output_path in &output_paths {
+        let saved = ModelMetadata::new(&model, Some(&file_reader_struct.filename))
+            .and_then(|mut metadata| {
+                metadata.clusters = encoder.get_clusters().to_vec();
+                save_model_with_metadata(&model, output_path, compress_model, &metadata)
+            });
+        match saved {
+            Ok(()) => println!("Model saved as {}", output_path),
+            Err(e) => eprintln!("Failed to save model: {}", e),
         }
-        total_info += model.calculate_information_content(&text_buff);
-        text_length += text_buff.chars().count();
+    }
+
+    let total_info = model.calculate_information_content(&symbols);
+    let text_length = symbols.chars().count();
+
+    if let Some(offset) = file_reader_struct.get_first_invalid_offset() {
+        println!("Invalid UTF-8 sequences handled: {} (first at byte offset {})", file_reader_struct.get_invalid_sequences(), offset);
     }
 
     println!("Average information content: {}", total_info / text_length as f64);
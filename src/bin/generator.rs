@@ -1,25 +1,43 @@
 extern crate argparse;
 extern crate rand;
 use std::collections::HashMap;
-use tai_projects::{file_reader::FileReader, finite_context_model::FiniteContextModel,finite_context_model_words::FiniteContextModelWords, text_generator, *};
-use argparse::{ArgumentParser, Store};
+use tai_projects::{file_reader::FileReader, finite_context_model_backoff::FiniteContextModelBackoff,finite_context_model_words::FiniteContextModelWords, model_saver_loader::{ModelMetadata, PersistentModel}, symbol_encoder::{SymbolEncoder, SymbolOptions}, text_generator::{self, GenerationConstraints, WeightSchedule}, *};
+use argparse::{ArgumentParser, Collect, List, Store, StoreOption, StoreTrue};
 
 fn main() {
-    let mut file_path: String = "".to_string();
+    let mut file_paths: Vec<String> = Vec::new();
+    let mut weights: Vec<f64> = Vec::new();
+    let mut end_weights: Vec<f64> = Vec::new();
     let mut k_value: usize = 3;
     let mut alpha: f64 = 0.01;
     let mut prior: String = String::new();
     let mut sequence_length: usize = 500;
     let mut mode = "normal".to_string();
+    let mut beam_width: usize = 0;
+    let mut continuations: usize = 5;
+    let mut stop_sequences: Vec<String> = Vec::new();
+    let mut max_sentences: Option<usize> = None;
+    let mut max_lines: Option<usize> = None;
+    let mut forbidden_symbols = String::new();
+    let mut alphabet = String::new();
+    let mut save_model_path = String::new();
+    let mut load_model_paths: Vec<String> = Vec::new();
+    let mut symbol_options = SymbolOptions::default();
 
     {
         let mut argument_parser: ArgumentParser<'_> = ArgumentParser::new();
         argument_parser.set_description("Algorithmic Theory of Information First Project");
 
-        // File path
-        argument_parser.refer(&mut file_path)
-        .add_argument("File Path", Store, "Path to the file to be analysed")
-        .required();
+        // File paths
+        argument_parser.refer(&mut file_paths)
+        .add_argument("File Path", List, "Path to the file to be analysed (several files blend their styles)");
+
+        // Mixture weights
+        argument_parser.refer(&mut weights)
+            .add_option(&["-w", "--weight"], Collect, "Weight of each file in the mixture, in the same order (default: equal weights)");
+
+        argument_parser.refer(&mut end_weights)
+            .add_option(&["--end-weight"], Collect, "Weight of each file at the end of the output, interpolated linearly from -w");
     
         // Size of the sliding window - k
         argument_parser.refer(&mut k_value)
@@ -41,6 +59,48 @@ fn main() {
         argument_parser.refer(&mut mode)
             .add_option(&["-m"], Store, "Mode of context \"normal\":chars, \"words\":words");
 
+        // Beam width - enables beam search instead of random sampling
+        argument_parser.refer(&mut beam_width)
+            .add_option(&["-b", "--beam-width"], Store, "Beam width for the most probable continuations (default: 0, random sampling)");
+
+        // Number of continuations reported by beam search
+        argument_parser.refer(&mut continuations)
+            .add_option(&["-n", "--continuations"], Store, "Number of most probable continuations to report (default: 5)");
+
+        // Stop conditions
+        argument_parser.refer(&mut stop_sequences)
+            .add_option(&["--stop"], Collect, "Stop generating once this sequence is produced (can be repeated)");
+
+        argument_parser.refer(&mut max_sentences)
+            .add_option(&["--max-sentences"], StoreOption, "Stop generating after this number of sentences");
+
+        argument_parser.refer(&mut max_lines)
+            .add_option(&["--max-lines"], StoreOption, "Stop generating after this number of lines");
+
+        // Symbol constraints
+        argument_parser.refer(&mut forbidden_symbols)
+            .add_option(&["--forbid"], Store, "Symbols that must never be generated, e.g. \"#@\"");
+
+        argument_parser.refer(&mut alphabet)
+            .add_option(&["--alphabet"], Store, "Only generate symbols from this alphabet, e.g. \"ACGT\"");
+
+        // Model persistence
+        argument_parser.refer(&mut save_model_path)
+            .add_option(&["--save-model"], Store, "Save the model trained on the file to this path (.json, .bson or binary)");
+
+        argument_parser.refer(&mut load_model_paths)
+            .add_option(&["--load-model"], Collect, "Generate from a saved model instead of training on a file (can be repeated to blend models)");
+
+        // Symbols of the character models
+        argument_parser.refer(&mut symbol_options.normalization)
+            .add_option(&["--normalize"], Store, "Unicode normalization of the text: none, nfc or nfd (default: none)");
+
+        argument_parser.refer(&mut symbol_options.case_fold)
+            .add_option(&["--case-fold"], StoreTrue, "Lowercase the text before training");
+
+        argument_parser.refer(&mut symbol_options.graphemes)
+            .add_option(&["--graphemes"], StoreTrue, "Use grapheme clusters as symbols instead of Unicode scalar values");
+
         argument_parser.parse_args_or_exit();
     }
 
@@ -49,76 +109,179 @@ fn main() {
         return;
     }
     
-    // Vector of models
-    let mut models_n: HashMap<usize, FiniteContextModel> = HashMap::new();
-    let mut models_words: HashMap<usize, FiniteContextModelWords> = HashMap::new();
-
-    for k in {
-        if mode == "normal"{
-            if k_value > prior.len(){
-                vec![k_value,prior.len()]
-            }else {
-                vec![k_value]
+    if file_paths.is_empty() == load_model_paths.is_empty() {
+        println!("Error: give either the files to train on or --load-model");
+        return;
+    }
+
+    let mut encoder = SymbolEncoder::new(symbol_options);
+
+    if mode == "words" && !encoder.is_identity() {
+        println!("Error: --normalize, --case-fold and --graphemes only apply to the normal mode");
+        return;
+    }
+
+    // The symbols given to grapheme clusters are saved in the metadata of the model
+    if !load_model_paths.is_empty() {
+        let metadata: Vec<Option<ModelMetadata>> = load_model_paths.iter()
+            .map(|path| model_saver_loader::load_metadata(path).ok())
+            .collect();
+
+        if symbol_options.graphemes {
+            if load_model_paths.len() > 1 {
+                println!("Error: --graphemes can only be used with a single --load-model");
+                return;
+            }
+            match metadata.into_iter().next().flatten() {
+                Some(metadata) => encoder.set_clusters(metadata.clusters),
+                None => {
+                    println!("Error: the metadata of {} is missing, so its grapheme clusters are unknown", load_model_paths[0]);
+                    return;
+                }
             }
         } else {
+            for (path, metadata) in load_model_paths.iter().zip(&metadata) {
+                if metadata.as_ref().is_some_and(|metadata| !metadata.clusters.is_empty()) {
+                    println!("Error: {} was trained with --graphemes, which must be given to load it", path);
+                    return;
+                }
+            }
+        }
+    }
+
+    let source_count = file_paths.len() + load_model_paths.len();
+
+    // Stop conditions and symbol constraints only apply to sampling characters from a single model
+    let constrained = !stop_sequences.is_empty() || max_sentences.is_some() || max_lines.is_some() || !forbidden_symbols.is_empty() || !alphabet.is_empty();
+    if constrained && (mode != "normal" || beam_width > 0 || source_count > 1) {
+        println!("Error: --stop, --max-sentences, --max-lines, --forbid and --alphabet only apply to the normal mode with one model and without beam search");
+        return;
+    }
+
+    if !save_model_path.is_empty() && file_paths.len() != 1 {
+        println!("Error: --save-model needs exactly one file to train on");
+        return;
+    }
+
+    if weights.is_empty() {
+        weights = vec![1.0; source_count];
+    }
+
+    if weights.len() != source_count || (!end_weights.is_empty() && end_weights.len() != source_count) {
+        println!("Error: one weight must be given for each file");
+        return;
+    }
+
+    if weights.iter().chain(&end_weights).any(|w| *w < 0.0) {
+        println!("Error: weights must not be negative");
+        return;
+    }
+
+    if source_count > 1 {
+        let schedule = if end_weights.is_empty() {
+            WeightSchedule::Fixed(weights)
+        } else {
+            WeightSchedule::Linear { start: weights, end: end_weights }
+        };
+
+        let generated_text = if mode == "normal" {
+            let models: Vec<FiniteContextModelBackoff> = if load_model_paths.is_empty() {
+                file_paths.iter()
+                    .map(|file_path| train_char_model(file_path, k_value, alpha, &mut encoder))
+                    .collect()
+            } else {
+                match load_models(&load_model_paths) {
+                    Some(models) => models,
+                    None => return,
+                }
+            };
+            println!("Models created successfully\nGenerating text from the mixture...");
+            let prior = encoder.encode_all(&prior);
+            encoder.decode(&text_generator::generate_text_mixture(&models, &schedule, &prior, sequence_length))
+        } else {
+            let models: Vec<FiniteContextModelWords> = if load_model_paths.is_empty() {
+                file_paths.iter()
+                    .map(|file_path| train_word_model(file_path, k_value, alpha))
+                    .collect()
+            } else {
+                match load_models(&load_model_paths) {
+                    Some(models) => models,
+                    None => return,
+                }
+            };
+            println!("Models created successfully\nGenerating text from the mixture...");
+            text_generator::generate_text_words_mixture(&models, &schedule, &prior, sequence_length)
+        };
+        println!("Generated Text:\n{}", generated_text);
+        return;
+    }
+
+    let generated_text = if mode == "normal" {
+        // Chain of models of orders 0..=k, trained in a single pass
+        let model = if load_model_paths.is_empty() {
+            let model = train_char_model(&file_paths[0], k_value, alpha, &mut encoder);
+            save_trained_model(&model, &save_model_path, &file_paths[0], encoder.get_clusters());
+            model
+        } else {
+            match load_models::<FiniteContextModelBackoff>(&load_model_paths) {
+                Some(mut models) => models.remove(0),
+                None => return,
+            }
+        };
+        let symbol_prior = encoder.encode_all(&prior);
+
+        println!("Model created successfully\nGenerating text...");
+        if beam_width > 0 {
+            let results = text_generator::beam_search(&model, &symbol_prior, sequence_length, beam_width, continuations);
+            print_continuations(&prior, &results, &encoder);
+            return;
+        }
+        let constraints = GenerationConstraints {
+            stop_sequences: stop_sequences.iter().map(|stop| encoder.encode_all(stop)).collect(),
+            max_sentences,
+            max_lines,
+            forbidden_symbols: encoder.encode_all(&forbidden_symbols).chars().collect(),
+            alphabet: if alphabet.is_empty() { None } else { Some(encoder.encode_all(&alphabet).chars().collect()) },
+        };
+        let result = text_generator::generate_text_constrained(&model, &symbol_prior, sequence_length, &constraints);
+        println!("Generation stopped: {}", result.stop_reason);
+        encoder.decode(&result.text)
+    } else {
+        // Vector of models
+        let mut models_words: HashMap<usize, FiniteContextModelWords> = HashMap::new();
+
+        let k_value = if load_model_paths.is_empty() {
             let mut context: Vec<String> = Vec::new();
             for word in prior.rsplit(' '){
                 context.push(String::from(word));
             };
-            if k_value > context.len(){
+            let orders = if k_value > context.len(){
                 vec![k_value,context.len()]
             }else {
                 vec![k_value]
+            };
+
+            for k in orders {
+                models_words.insert(k, train_word_model(&file_paths[0], k, alpha));
             }
-        }
-    }{
-        if mode == "normal"{
-            let mut model = FiniteContextModel::new(k, alpha);
-            let mut file_reader_struct = open_new_file(file_path.clone());
-        
-            println!("Training model with k {}",k);
-            loop {
-                match file_reader::read_char(&mut file_reader_struct) {
-                    Ok(Some(char)) => {
-                        model.train_char(char);
-                    }
-                    Ok(None) => break,
-                    Err(e) => {
-                        eprintln!("Error reading file: {}", e);
-                        break;
-                    }
-                }
-            }
-            models_n.insert(k, model);
+            save_trained_model(models_words.get(&k_value).unwrap(), &save_model_path, &file_paths[0], &[]);
+            k_value
         } else {
-            let mut model = FiniteContextModelWords::new(k, alpha);
-            let mut file_reader_struct = open_new_file(file_path.clone());
-        
-            println!("Training model with k {}",k);
-            loop {
-                match file_reader::read_word(&mut file_reader_struct) {
-                    Ok(Some(char)) => {
-                        model.train_word(&char);
-                    }
-                    Ok(None) => break,
-                    Err(e) => {
-                        eprintln!("Error reading file: {}", e);
-                        break;
-                    }
-                }
-            }
+            let model: FiniteContextModelWords = match load_models(&load_model_paths) {
+                Some(mut models) => models.remove(0),
+                None => return,
+            };
+            let k = model.get_k();
             models_words.insert(k, model);
-        }
-    }
-    // if prior.len() > k_value {
-    //     eprint!("Prior sequence length must be less than or equal to k");
-    //     return;
-    // }
+            k
+        };
 
-    println!("Model created successfully\nGenerating text...");
-    let generated_text = if mode == "normal" {
-        text_generator::generate_text(models_n, &prior, sequence_length,k_value)
-    }else{
+        println!("Model created successfully\nGenerating text...");
+        if beam_width > 0 {
+            let results = text_generator::beam_search_words(models_words.get(&k_value).unwrap(), &prior, sequence_length, beam_width, continuations);
+            print_continuations(&format!("{} ", prior), &results, &encoder);
+            return;
+        }
         text_generator::generate_text_words(models_words, &prior, sequence_length,k_value)
     };
     println!("Generated Text:\n{}", generated_text);
@@ -127,14 +290,100 @@ fn main() {
 
 
 
-fn open_new_file(file_path: String) -> FileReader{
-    let mut file_reader_struct = FileReader{
-        filename:String::from(file_path),
-        reader:Option::None,
-        buffer:Vec::new(),
+/*
+ * Loads every saved model, reporting the
+ * first one that fails to load
+*/
+fn load_models<M: PersistentModel>(paths: &[String]) -> Option<Vec<M>> {
+    let mut models = Vec::with_capacity(paths.len());
+    for path in paths {
+        println!("Loading model from {}", path);
+        match model_saver_loader::load_model(path) {
+            Ok(model) => models.push(model),
+            Err(e) => {
+                eprintln!("Failed to load model {}: {}", path, e);
+                return None;
+            }
+        }
+    }
+    Some(models)
+}
+
+fn save_trained_model<M: PersistentModel>(model: &M, path: &str, training_file: &str, clusters: &[String]) {
+    if path.is_empty() {
+        return;
+    }
+    let saved = ModelMetadata::new(model, Some(training_file))
+        .and_then(|mut metadata| {
+            metadata.clusters = clusters.to_vec();
+            model_saver_loader::save_model_with_metadata(model, path, false, &metadata)
+        });
+    match saved {
+        Ok(()) => println!("Model saved as {}", path),
+        Err(e) => eprintln!("Failed to save model: {}", e),
+    }
+}
+
+fn print_continuations(prior: &str, results: &[text_generator::Continuation], encoder: &SymbolEncoder) {
+    println!("Most probable continuations:");
+    for (i, continuation) in results.iter().enumerate() {
+        println!("{:>2}. {}{} ({:.4} bits)", i + 1, prior, encoder.decode(&continuation.text), continuation.bits);
+    }
+}
+
+/*
+ * Trains the chain of models on the symbols given by the
+ * encoder, which is shared so that every file gives the
+ * same symbol to the same grapheme cluster
+*/
+fn train_char_model(file_path: &str, k: usize, alpha: f64, encoder: &mut SymbolEncoder) -> FiniteContextModelBackoff {
+    let mut model = FiniteContextModelBackoff::new(k, alpha);
+    let mut file_reader_struct = FileReader::new(file_path);
+
+    println!("Training models with k from 0 to {} on {}",k,file_path);
+    let input = match file_reader::read_text(&mut file_reader_struct) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Error reading file: {}", e);
+            return model;
+        }
     };
+    let text = input.as_str();
+
+    if encoder.is_identity() {
+        text.chars().for_each(|symbol| model.train_char(symbol));
+    } else {
+        encoder.encode_all(text).chars().for_each(|symbol| model.train_char(symbol));
+    }
+
+    model
+}
+
+fn train_word_model(file_path: &str, k: usize, alpha: f64) -> FiniteContextModelWords {
+    let mut model = FiniteContextModelWords::new(k, alpha);
+    let mut file_reader_struct = open_new_file(file_path.to_string());
+
+    println!("Training model with k {} on {}",k,file_path);
+    loop {
+        match file_reader::read_word(&mut file_reader_struct) {
+            Ok(Some(char)) => {
+                model.train_word(&char);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Error reading file: {}", e);
+                break;
+            }
+        }
+    }
+
+    model
+}
+
+fn open_new_file(file_path: String) -> FileReader{
+    let mut file_reader_struct = FileReader::new(&file_path);
 
-    if !file_reader::open_file(&mut file_reader_struct).is_ok(){
+    if file_reader::open_file(&mut file_reader_struct).is_err(){
         println!("error ReadingFile");
     }
 
@@ -9,6 +9,7 @@ use opencv::{
 use tai_projects::image_processor::{quantize_image, ImageProcessor};
 
 use tai_projects::finite_context_model_image::FiniteContextModelImage;
+use tai_projects::model_saver_loader;
 
 fn main() -> Result<()>{
     let mut alpha = 0.5;
@@ -16,6 +17,8 @@ fn main() -> Result<()>{
     let mut image_path = "".to_string();
     let mut top_sequences = 10;
     let mut levels:i32 = 256;
+    let mut save_model_path = String::new();
+    let mut load_model_path = String::new();
 
     {
         let mut argument_parser: ArgumentParser<'_> = ArgumentParser::new();
@@ -23,8 +26,7 @@ fn main() -> Result<()>{
 
         // Meta file path
         argument_parser.refer(&mut image_path)
-            .add_option(&["-i"], Store, "Path to the image file (required)")
-            .required();
+            .add_option(&["-i"], Store, "Path to the image file (required unless --load-model is given)");
 
         // Database file path
         argument_parser.refer(&mut database_path)
@@ -42,27 +44,69 @@ fn main() -> Result<()>{
         argument_parser.refer(&mut levels)
             .add_option(&["-l"], Store, "Number of levels to quantize images (default: 255)");
 
+        // Model persistence, one file per context size with _k2, _k4 and _k6 before the extension
+        argument_parser.refer(&mut save_model_path)
+            .add_option(&["--save-model"], Store, "Save the models trained on the image to this path (.json, .bson or binary)");
+
+        argument_parser.refer(&mut load_model_path)
+            .add_option(&["--load-model"], Store, "Load the models saved with --save-model instead of training on an image");
+
         argument_parser.parse_args_or_exit();
     }
 
+    if image_path.is_empty() == load_model_path.is_empty() {
+        println!("Error: give either the image to train on (-i) or --load-model");
+        return Ok(());
+    }
+
     let images = ImageProcessor::new(database_path.as_str());
 
-    
-    let mut image = imgcodecs::imread(image_path.as_str(), imgcodecs::IMREAD_GRAYSCALE)?;
-    
-    quantize_image(&mut image, levels);
-    
-    if image.empty() {
-        panic!("Could not open or find the image!");
-    }
-    
     let mut models: HashMap<u8,FiniteContextModelImage> = HashMap::new();
-    for k in [2,4,6]{
-        let mut model = FiniteContextModelImage::new(alpha);
-        model.train_mat_image(image.clone(),k);
-        models.insert(k,model);
+    if load_model_path.is_empty() {
+        let mut image = imgcodecs::imread(image_path.as_str(), imgcodecs::IMREAD_GRAYSCALE)?;
+
+        quantize_image(&mut image, levels);
+
+        if image.empty() {
+            panic!("Could not open or find the image!");
+        }
+
+        for k in [2,4,6]{
+            let mut model = FiniteContextModelImage::new(alpha);
+            model.train_mat_image(image.clone(),k);
+            if !save_model_path.is_empty() {
+                let model_path = model_path_for_k(&save_model_path, k);
+                let saved = model_saver_loader::ModelMetadata::new(&model, Some(&image_path))
+                    .and_then(|mut metadata| {
+                        // The image model does not store k, it is given when training
+                        metadata.k = k as usize;
+                        model_saver_loader::save_model_with_metadata(&model, &model_path, false, &metadata)
+                    });
+                match saved {
+                    Ok(()) => println!("Model saved as {}", model_path),
+                    Err(e) => eprintln!("Failed to save model: {}", e),
+                }
+            }
+            models.insert(k,model);
+        }
+    } else {
+        for k in [2,4,6]{
+            let model_path = model_path_for_k(&load_model_path, k);
+            match model_saver_loader::load_model::<FiniteContextModelImage>(&model_path) {
+                Ok(model) => {
+                    if let Ok(metadata) = model_saver_loader::load_metadata(&model_path) {
+                        warn_if_trained_on_database(&metadata, &database_path);
+                    }
+                    models.insert(k,model);
+                }
+                Err(e) => {
+                    eprintln!("Failed to load model {}: {}", model_path, e);
+                    return Ok(());
+                }
+            }
+        }
     }
-    
+
     println!("Processing database using weighted NRC...");
     let gamma = 0.99;
 
@@ -78,3 +122,34 @@ fn main() -> Result<()>{
 
     Ok(())
 }
+
+/*
+ * Path of the model with context size k,
+ * e.g. models/face.bin -> models/face_k2.bin
+*/
+fn model_path_for_k(path: &str, k: u8) -> String {
+    let path = std::path::Path::new(path);
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("model");
+    let file_name = match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => format!("{}_k{}.{}", stem, k, extension),
+        None => format!("{}_k{}", stem, k),
+    };
+    path.with_file_name(file_name).to_string_lossy().into_owned()
+}
+
+/*
+ * Warns if one of the database images is the
+ * image the model was trained on
+*/
+fn warn_if_trained_on_database(metadata: &model_saver_loader::ModelMetadata, database_path: &str) {
+    let entries = match std::fs::read_dir(database_path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path().to_string_lossy().to_string();
+        if metadata.was_trained_on(&path) {
+            println!("Warning: the model was trained on {}, which is also in the database", path);
+        }
+    }
+}
@@ -1,25 +1,61 @@
+use std::collections::{HashMap, HashSet};
+use rand::Rng;
 use tai_projects::
-    {chart_generator::ChartGenerator, data_base_processor::{ComparisionResult, DataBaseProcessor}, file_reader, finite_context_model::FiniteContextModel
+    {chart_generator::ChartGenerator, data_base_processor::{self, ComparisionResult, DataBaseProcessor}, distance_matrix::{DistanceMeasure, MatrixFormat, Symmetrisation}, file_reader::{self, InputText}, finite_context_model::FiniteContextModel, local_nrc::{self, LocalDirection}, model_index::ModelIndex, model_saver_loader, phylo_tree::{PhyloTree, TreeMethod}, read_classifier::ReadClassifier, sequence_reader::{self, RecordFilter, SequenceReader, SequenceType, TextRecords}, significance::{self, NullModel, NullTarget, Significance}, sketch::{self, Sketch, SketchCache, MAX_SKETCH_K}, task_tracker::TaskTracker, taxonomy::{GroupBy, GroupScore, TaxonomyMap}
 };
 extern crate argparse;
 
-use argparse::{ArgumentParser, Store};
+use argparse::{ArgumentParser, Collect, Store, StoreOption, StoreTrue};
 
 fn main(){
-    let mut meta_file_path: String = "".to_string();
+    let mut meta_file_paths: Vec<String> = Vec::new();
     let mut database_file_path: String= "".to_string();
     let mut k: usize = 3;
     let mut alpha = 0.01;
     let mut top_sequences = 20; 
     let mut treshold = 0.5;
+    let mut save_model_path = String::new();
+    let mut load_model_path = String::new();
+    let mut record_filter = RecordFilter::default();
+    let mut sequence_type = SequenceType::Dna;
+    let mut stream_database = false;
+    let mut threads: usize = 1;
+    let mut show_progress = false;
+    let mut matrix_path = String::new();
+    let mut distance = DistanceMeasure::Nrc;
+    let mut symmetrisation = Symmetrisation::None;
+    let mut tree_path = String::new();
+    let mut tree_method = TreeMethod::NeighborJoining;
+    let mut group_by_name = String::new();
+    let mut taxonomy_path = String::new();
+    let mut groups_output = String::new();
+    let mut local_window: usize = 0;
+    let mut local_step: usize = 0;
+    let mut local_direction = LocalDirection::Reference;
+    let mut local_output = String::new();
+    let mut local_chart = String::new();
+    let mut classify_reads = false;
+    let mut read_threshold = 0.9;
+    let mut use_em = false;
+    let mut read_output = String::new();
+    let mut abundance_output = String::new();
+    let mut null_model = NullModel::default();
+    let mut seed: Option<u64> = None;
+    let mut p_value_threshold: Option<f64> = None;
+    let mut significance_output = String::new();
+    let mut prefilter: usize = 0;
+    let mut sketch_k: usize = 21;
+    let mut sketch_scale: u64 = 100;
+    let mut sketch_cache = String::new();
+    let mut index_path = String::new();
 
     {
         let mut argument_parser: ArgumentParser<'_> = ArgumentParser::new();
         argument_parser.set_description("Algorithmic Theory of Information Second Project");
 
-        // Meta file path
-        argument_parser.refer(&mut meta_file_path)
-            .add_option(&["-s"], Store, "Path to the meta file (required)")
+        // Meta file paths
+        argument_parser.refer(&mut meta_file_paths)
+            .add_option(&["-s"], Collect, "Path to the meta file (required). The first one is compared with the database; when repeated, the NRC of every sample under the model is also printed")
             .required();
 
         // Database file path
@@ -29,7 +65,7 @@ fn main(){
 
         // Size of the sliding window - k
         argument_parser.refer(&mut k)
-            .add_option(&["-k"], Store, "Size of the sliding window (default: 3 must be 1 <= k <= 100)");
+            .add_option(&["-k"], Store, "Size of the sliding window (default: 3 must be 1 <= k <= 20)");
 
         // Smoothing parameter - alpha
         argument_parser.refer(&mut alpha)
@@ -43,109 +79,668 @@ fn main(){
         argument_parser.refer(&mut treshold)
             .add_option(&["-l"], Store, "Threshold for low scores (default: 0.5)");
 
+        // Model persistence
+        argument_parser.refer(&mut save_model_path)
+            .add_option(&["--save-model"], Store, "Save the trained model to this path (.json, .bson or binary)");
+
+        argument_parser.refer(&mut load_model_path)
+            .add_option(&["--load-model"], Store, "Load the model from this path instead of training it on the first meta file");
+
+        // Sequence type
+        argument_parser.refer(&mut sequence_type)
+            .add_option(&["--sequence-type"], Store, "Alphabet the NRC is normalised by: dna, rna, protein, alphabet:N or auto (observed by the model) (default: dna)");
+
+        // Model index
+        argument_parser.refer(&mut index_path)
+            .add_option(&["--index"], Store, "Load the models of the database records from this index (built with models index) instead of training them");
+
+        // Database filters
+        argument_parser.refer(&mut record_filter.min_length)
+            .add_option(&["--min-length"], Store, "Ignore database sequences shorter than this (default: 0)");
+
+        argument_parser.refer(&mut record_filter.min_mean_quality)
+            .add_option(&["--min-quality"], StoreOption, "Ignore FASTQ database records with a lower mean Phred quality");
+
+        // Streaming database
+        argument_parser.refer(&mut stream_database)
+            .add_option(&["--stream"], StoreTrue, "Score the database while reading it and keep only the top sequences in memory");
+
+        // Sketch prefilter
+        argument_parser.refer(&mut prefilter)
+            .add_option(&["--prefilter"], Store, "Score only the sequences whose k-mer sketches are the most contained in the sample, this many (default: 0, off)");
+
+        argument_parser.refer(&mut sketch_k)
+            .add_option(&["--sketch-k"], Store, "Length of the k-mers of the sketches (default: 21, must be 1 <= k <= 32)");
+
+        argument_parser.refer(&mut sketch_scale)
+            .add_option(&["--sketch-scale"], Store, "Keep about one k-mer in this many in the sketches (default: 100)");
+
+        argument_parser.refer(&mut sketch_cache)
+            .add_option(&["--sketch-cache"], Store, "Read the sketches of the database from this file, and save them to it");
+
+        // Parallel scoring
+        argument_parser.refer(&mut threads)
+            .add_option(&["--threads"], Store, "Threads used to score the database, 0 for one per core (default: 1, sequential)");
+
+        argument_parser.refer(&mut show_progress)
+            .add_option(&["--progress"], StoreTrue, "Show the progress of the parallel scoring");
+
+        // Distance matrix of the low score sequences
+        argument_parser.refer(&mut matrix_path)
+            .add_option(&["--matrix"], Store, "Save the distance matrix of the low score sequences (.csv, .tsv, .phy or .json)");
+
+        argument_parser.refer(&mut distance)
+            .add_option(&["--distance"], Store, "Distance of the matrix: nrc, fcm-ncd or ncd-<gz|bz2|xz|zstd|lzma> (default: nrc)");
+
+        argument_parser.refer(&mut symmetrisation)
+            .add_option(&["--symmetrise"], Store, "Combine both directions of each pair of the matrix: none, mean, min or max (default: none)");
+
+        // Tree of the low score sequences
+        argument_parser.refer(&mut tree_path)
+            .add_option(&["--tree"], Store, "Save the tree of the low score sequences, built from the distance matrix, in Newick format");
+
+        argument_parser.refer(&mut tree_method)
+            .add_option(&["--tree-method"], Store, "Method used to build the tree: nj (neighbor joining) or upgma (default: nj)");
+
+        // Grouped scores
+        argument_parser.refer(&mut group_by_name)
+            .add_option(&["--group-by"], Store, "Group the NRC scores by accession, organism or field:N (N-th field of the header)");
+
+        argument_parser.refer(&mut taxonomy_path)
+            .add_option(&["--taxonomy"], Store, "Tab separated file mapping accessions to taxa, to roll the NRC scores up its ranks");
+
+        argument_parser.refer(&mut groups_output)
+            .add_option(&["--groups-output"], Store, "Save the grouped and rolled up NRC scores to this JSON file");
+
+        // Local NRC of the top sequences
+        argument_parser.refer(&mut local_window)
+            .add_option(&["--local-window"], Store, "Score windows of this number of symbols of the top sequences (default: 0, off)");
+
+        argument_parser.refer(&mut local_step)
+            .add_option(&["--local-step"], Store, "Symbols between the starts of two windows (default: half the window)");
+
+        argument_parser.refer(&mut local_direction)
+            .add_option(&["--local-direction"], Store, "Windows of the references under the sample model (reference), of the sample under each reference model (sample) or both (default: reference)");
+
+        argument_parser.refer(&mut local_output)
+            .add_option(&["--local-output"], Store, "Save the windows to this file (.bed or .csv)");
+
+        argument_parser.refer(&mut local_chart)
+            .add_option(&["--local-chart"], Store, "Draw the NRC of the windows to this image");
+
+        // Read-level classification
+        argument_parser.refer(&mut classify_reads)
+            .add_option(&["--reads"], StoreTrue, "Assign each read of the first meta file (FASTA, FASTQ or one per line) to the top sequence that compresses it best");
+
+        argument_parser.refer(&mut read_threshold)
+            .add_option(&["--read-threshold"], Store, "Reads whose lowest NRC is not under this are unclassified (default: 0.9)");
+
+        argument_parser.refer(&mut use_em)
+            .add_option(&["--em"], StoreTrue, "Share ambiguous reads out between their references by expectation-maximisation");
+
+        argument_parser.refer(&mut read_output)
+            .add_option(&["--read-output"], Store, "Save the reference of every read to this TSV file");
+
+        argument_parser.refer(&mut abundance_output)
+            .add_option(&["--abundance-output"], Store, "Save the abundance of every reference to this TSV file");
+
+        // Significance of the NRC scores
+        argument_parser.refer(&mut null_model.replicates)
+            .add_option(&["--null-replicates"], Store, "Randomised replicates scored to estimate the significance of each NRC (default: 0, off)");
+
+        argument_parser.refer(&mut null_model.target)
+            .add_option(&["--null-target"], Store, "Randomise each reference (reference) or the sample the model is trained on (sample) (default: reference)");
+
+        argument_parser.refer(&mut null_model.randomisation)
+            .add_option(&["--null-model"], Store, "Randomisation: shuffle (keeps the composition) or blocks:N (shuffles blocks of N symbols) (default: shuffle)");
+
+        argument_parser.refer(&mut seed)
+            .add_option(&["--seed"], StoreOption, "Seed of the randomisations (default: random)");
+
+        argument_parser.refer(&mut p_value_threshold)
+            .add_option(&["--p-value"], StoreOption, "Keep the sequences with a p-value under this as low scores, instead of those with an NRC under -l");
+
+        argument_parser.refer(&mut significance_output)
+            .add_option(&["--significance-output"], Store, "Save the NRC, z-score and p-value of every sequence to this TSV file");
+
         argument_parser.parse_args_or_exit();
     }
     
     // Check if k has a valid value and if it is a number
-    if k < 1 || k > 20   {
-        println!("Error: k must be greater than 0");
+    if !(1..=20).contains(&k) {
+        println!("Error: k must be between 1 and 20");
         return;
     }
 
-    if alpha < 0.0 || alpha > 1.0 {
+    if !(0.0..=1.0).contains(&alpha) {
         println!("Error: alpha must be between 0 and 1");
         return;
     }
 
-    if top_sequences < 1 || top_sequences > 239 {
+    if !(1..=239).contains(&top_sequences) {
         println!("Error: top_sequences must be greater than 0 and less than 240");
         return;
     }
 
-    if treshold < 0.0 || treshold > 1.0 {
+    if !(0.0..=1.0).contains(&treshold) {
         println!("Error: treshold must be between 0 and 1");
         return;
     }
 
-    let mut file_reader_struct = file_reader::FileReader{
-        filename: String::from(meta_file_path.clone()),
-        reader: None,
-        buffer: Vec::new(),
-    };
+    // Also with one thread, so that the other rayon steps run on it alone
+    if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global() {
+        println!("Error: could not start {} threads: {}", threads, e);
+        return;
+    }
+
+    let matrix_format = MatrixFormat::from_path(&matrix_path);
+    if !matrix_path.is_empty() && matrix_format.is_none() {
+        println!("Error: the matrix file must end in .csv, .tsv, .phy or .json");
+        return;
+    }
+
+    if local_window > 0 && local_window <= k {
+        println!("Error: the local window must be longer than k");
+        return;
+    }
 
-    if !file_reader::open_file(&mut file_reader_struct).is_ok(){
-        println!("error Reading File");
+    if !local_output.is_empty() && !local_output.ends_with(".bed") && !local_output.ends_with(".csv") {
+        println!("Error: the local NRC output must end in .bed or .csv");
         return;
     }
 
+    if read_threshold <= 0.0 {
+        println!("Error: the read threshold must be greater than 0");
+        return;
+    }
+
+    if null_model.replicates == 0 && (p_value_threshold.is_some() || !significance_output.is_empty()) {
+        println!("Error: p-values need --null-replicates greater than 0");
+        return;
+    }
+
+    if p_value_threshold.is_some_and(|p_value| p_value <= 0.0 || p_value > 1.0) {
+        println!("Error: the p-value threshold must be greater than 0 and at most 1");
+        return;
+    }
+
+    if prefilter > 0 {
+        if stream_database {
+            println!("Error: the prefilter can not be used with --stream");
+            return;
+        }
+
+        if !(1..=MAX_SKETCH_K).contains(&sketch_k) {
+            println!("Error: the sketch k must be between 1 and {}", MAX_SKETCH_K);
+            return;
+        }
+
+        if sketch_scale < 1 {
+            println!("Error: the sketch scale must be at least 1");
+            return;
+        }
+
+        if matches!(sequence_type, SequenceType::Protein | SequenceType::Alphabet(_)) {
+            println!("Error: the sketches only read DNA and RNA");
+            return;
+        }
+    }
+
+    if local_step == 0 {
+        local_step = (local_window / 2).max(1);
+    }
+
+    // The streamed ranking only keeps the top sequences, the mean of a group would be over those
+    if stream_database && (!group_by_name.is_empty() || !taxonomy_path.is_empty()) {
+        println!("Error: --group-by and --taxonomy can not be used with --stream");
+        return;
+    }
+
+    let group_by = if group_by_name.is_empty() {
+        None
+    } else {
+        match group_by_name.parse::<GroupBy>() {
+            Ok(group_by) => Some(group_by),
+            Err(e) => {
+                println!("Error: {}", e);
+                return;
+            }
+        }
+    };
+
+    let model_index = if index_path.is_empty() {
+        None
+    } else {
+        match ModelIndex::open(&index_path) {
+            Ok(model_index) => Some(model_index),
+            Err(e) => {
+                println!("Error: could not read the model index {}: {}", index_path, e);
+                return;
+            }
+        }
+    };
+
+    // The reads are scored by the group models as they are, they can not be trained again here
+    if let Some(model_index) = model_index.as_ref().filter(|model_index| model_index.get_group_by().is_some()) {
+        if classify_reads && (model_index.get_k() != k || model_index.get_alpha() != alpha) {
+            println!(
+                "Error: the grouped models of the index have k = {} and alpha = {}, run with the same k and alpha or index again",
+                model_index.get_k(),
+                model_index.get_alpha(),
+            );
+            return;
+        }
+    }
+
+    let taxonomy_map = if taxonomy_path.is_empty() {
+        None
+    } else {
+        match TaxonomyMap::load(&taxonomy_path) {
+            Ok(taxonomy_map) => Some(taxonomy_map),
+            Err(e) => {
+                println!("Error: could not read the taxonomy {}: {}", taxonomy_path, e);
+                return;
+            }
+        }
+    };
+
     use std::time::Instant;
     let now = Instant::now();
+    let mut parallel_timings: Vec<String> = Vec::new();
 
-    println!("Reading file metagenomic sample in file {} and training model...", &meta_file_path);
+    let meta_file_path = &meta_file_paths[0];
+    let metagonic_input = match read_sample(meta_file_path) {
+        Some(input) => input,
+        None => return,
+    };
+    let metagonic_sample = metagonic_input.as_str();
 
-    let mut model = FiniteContextModel::new(k, alpha);
-    let mut metagonic_sample = String::new();
-    loop {
-        match file_reader::read_char(&mut file_reader_struct) {
-            Ok(Some(char)) => {
-                if char != '\n' {
-                    metagonic_sample.push(char);
-                    model.train_char(char);
-                }
-            }
-            Ok(None) => break,
+    let mut model = if load_model_path.is_empty() {
+        println!("Training model on metagenomic sample in file {}...", meta_file_path);
+        let mut model = FiniteContextModel::new(k, alpha);
+        for char in sample_symbols(metagonic_sample) {
+            model.train_char(char);
+        }
+        model
+    } else {
+        println!("Loading model from {}...", &load_model_path);
+        match model_saver_loader::load_model::<FiniteContextModel>(&load_model_path) {
+            Ok(model) => model,
             Err(e) => {
-                eprintln!("Error reading file: {}", e);
-                break;
+                eprintln!("Failed to load model: {}", e);
+                return;
+            }
+        }
+    };
+    let k = model.get_k();
+    let alpha = model.get_alpha();
+
+    // Scoring the data the model was trained on gives misleadingly low NRC values.
+    // The model is trained on a sample, so only the samples are compared with it
+    if !load_model_path.is_empty() {
+        if let Ok(metadata) = model_saver_loader::load_metadata(&load_model_path) {
+            for path in &meta_file_paths {
+                if metadata.was_trained_on(path) {
+                    println!("Warning: the model was trained on the same data as {}", path);
+                }
             }
         }
     }
 
     println!("Model trained with k = {} and alpha = {}", k, alpha);
 
+    let observed_symbols = model.get_symbols().len();
+    let alphabet_size = sequence_type.alphabet_size(observed_symbols);
+    println!("NRC normalised by an alphabet of {} symbols", alphabet_size);
+    if observed_symbols > alphabet_size {
+        println!("Warning: the model has seen {} different symbols, more than the alphabet of the sequence type", observed_symbols);
+    }
+    model.set_alphabet_size(alphabet_size);
+
+    if !save_model_path.is_empty() {
+        let saved = model_saver_loader::ModelMetadata::new(&model, Some(meta_file_path))
+            .and_then(|metadata| model_saver_loader::save_model_with_metadata(&model, &save_model_path, false, &metadata));
+        match saved {
+            Ok(()) => println!("Model saved as {}", save_model_path),
+            Err(e) => eprintln!("Failed to save model: {}", e),
+        }
+    }
+
     let elapsed = now.elapsed();
 
-    println!("Reading file database in file {} and computing NRC scores...", &database_file_path);
-    let data_processor = DataBaseProcessor::new(database_file_path.to_string());
-    let mut nrc_scores: Vec<_> = data_processor.compute_nrc(&model).into_iter().collect();
+    // NRC of every metagenomic sample under the model; only the first one is compared with the database
+    if meta_file_paths.len() > 1 || !load_model_path.is_empty() {
+        println!("\nNRC scores of the metagenomic samples:");
+        for (index, path) in meta_file_paths.iter().enumerate() {
+            let other_input = if index == 0 { None } else { read_sample(path) };
+            let sample = if index == 0 { Some(metagonic_sample) } else { other_input.as_ref().map(InputText::as_str) };
+            if let Some(sample) = sample {
+                let sample_length = sample_symbols(sample).count() as f64;
+                let nrc_score = if sample_length > 0.0 {
+                    model.information_content_of(sample_symbols(sample)) / (sequence_reader::bits_per_symbol(alphabet_size) * sample_length)
+                } else {
+                    0.0
+                };
+                println!("{}: {:.6}", path, nrc_score);
+            }
+        }
+        println!();
+    }
 
-    println!("NRC scores computed\nSorting NRC scores...");
+    println!("Reading file database in file {} and computing NRC scores...", &database_file_path);
+    let (mut data_processor, nrc_scores) = if stream_database {
+        let streamed = match DataBaseProcessor::stream_top_nrc(&database_file_path, &record_filter, &model, alphabet_size, top_sequences) {
+            Ok(streamed) => streamed,
+            Err(e) => {
+                eprintln!("Error reading database {}: {}", database_file_path, e);
+                return;
+            }
+        };
+        if streamed.get_filtered_count() > 0 {
+            println!("Sequences removed by the length and quality filters: {}", streamed.get_filtered_count());
+        }
+        println!("NRC scores computed for {} sequences, the top {} are kept", streamed.get_scored_count(), streamed.get_ranking().len());
+
+        // Already ranked, ties in the order of the database file
+        let nrc_scores: Vec<(String, f64)> = streamed.get_ranking().iter()
+            .map(|scored| (scored.get_record().get_id().to_string(), scored.get_nrc_score()))
+            .collect();
+        let mut data_processor = match DataBaseProcessor::from_records(streamed.into_records()) {
+            Ok(data_processor) => data_processor,
+            Err(e) => {
+                eprintln!("Error reading database {}: {}", database_file_path, e);
+                return;
+            }
+        };
+        data_processor.set_alphabet_size(alphabet_size);
+        (data_processor, nrc_scores)
+    } else {
+        let mut data_processor = match DataBaseProcessor::with_filter(database_file_path.to_string(), record_filter) {
+            Ok(data_processor) => data_processor,
+            Err(e) => {
+                eprintln!("Error reading database {}: {}", database_file_path, e);
+                return;
+            }
+        };
+        data_processor.set_alphabet_size(alphabet_size);
+        if data_processor.get_filtered_count() > 0 {
+            println!("Sequences removed by the length and quality filters: {}", data_processor.get_filtered_count());
+        }
+        if prefilter > 0 {
+            prefilter_database(&mut data_processor, metagonic_sample, prefilter, (sketch_k, sketch_scale), &sketch_cache);
+        }
+        let mut nrc_scores = if threads == 1 {
+            data_processor.compute_nrc(&model)
+        } else {
+            let tracker = TaskTracker::new("Scoring sequences", data_processor.get_database().len(), show_progress);
+            let nrc_scores = data_processor.compute_nrc_parallel(&model, &tracker);
+            tracker.finish();
+            parallel_timings.push(describe_parallel_work("NRC scores", &tracker));
+            nrc_scores
+        };
+
+        println!("NRC scores computed\nSorting NRC scores...");
+        data_base_processor::rank_nrc(&mut nrc_scores);
+        (data_processor, nrc_scores)
+    };
 
     let elapsed_nrc = now.elapsed();
 
-    nrc_scores.sort_unstable_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+    if let Some(model_index) = model_index {
+        if !model_index.is_up_to_date(&database_file_path) {
+            println!("Warning: the database has changed since the index was built, the models of changed records are trained");
+        }
+        if model_index.get_group_by().is_none() && (model_index.get_k() != k || model_index.get_alpha() != alpha) {
+            println!(
+                "Warning: the models of the index have k = {} and alpha = {}, they are not used",
+                model_index.get_k(),
+                model_index.get_alpha(),
+            );
+        }
+        data_processor.set_model_index(model_index);
+    }
+
+    let significances: Vec<Significance> = if null_model.replicates > 0 {
+        null_model.seed = seed.unwrap_or_else(|| rand::rng().random());
+        println!(
+            "Scoring {} randomised replicates of the {} with seed {}...",
+            null_model.replicates,
+            if null_model.target == NullTarget::Sample { "sample" } else { "sequences" },
+            null_model.seed,
+        );
+        data_processor.nrc_significance(&nrc_scores, &model, || sample_symbols(metagonic_sample), &null_model)
+    } else {
+        Vec::new()
+    };
+    let significance_by_name: HashMap<&str, &Significance> = significances.iter()
+        .map(|significance| (significance.get_name(), significance))
+        .collect();
 
     println!("Top {} sequences:", top_sequences);
     for (name, score) in nrc_scores.iter().take(top_sequences) {
-        println!("{}: {:.6}", name, score);
+        match significance_by_name.get(name.as_str()) {
+            Some(significance) => println!("{}: {:.6} (z = {:.2}, p = {:.4})", data_processor.display_name(name), score, significance.get_z_score(), significance.get_p_value()),
+            None => println!("{}: {:.6}", data_processor.display_name(name), score),
+        }
+    }
+
+    if !significance_output.is_empty() {
+        match significance::export_significance(&significances, &significance_output) {
+            Ok(()) => println!("Significance of the NRC scores saved to {}", significance_output),
+            Err(e) => eprintln!("Failed to save significance: {}", e),
+        }
+    }
+
+    let groups = group_by.as_ref().map(|group_by| data_processor.group_nrc(&nrc_scores, group_by));
+    if let Some(groups) = &groups {
+        println!("\nTop {} groups by {}:", top_sequences, group_by_name);
+        print_groups(groups, top_sequences);
+    }
+
+    let rollup = taxonomy_map.as_ref().map(|taxonomy_map| data_processor.taxonomy_rollup(&nrc_scores, taxonomy_map));
+    for rank_scores in rollup.iter().flatten() {
+        println!("\nTop {} taxa by {}:", top_sequences, rank_scores.get_rank());
+        print_groups(rank_scores.get_groups(), top_sequences);
+    }
+
+    let top_names: Vec<String> = nrc_scores.iter().take(top_sequences).map(|(name, _)| name.clone()).collect();
+
+    if local_window > 0 {
+        let sample_name = std::path::Path::new(meta_file_path).file_name()
+            .map_or(meta_file_path.to_string(), |name| name.to_string_lossy().to_string());
+
+        println!("\nScoring windows of {} symbols every {} symbols...", local_window, local_step);
+        let mut local_scores = Vec::new();
+        if local_direction.includes_references() {
+            local_scores.extend(data_processor.local_nrc_of_references(&top_names, &model, &sample_name, local_window, local_step));
+        }
+        if local_direction.includes_sample() {
+            local_scores.extend(data_processor.local_nrc_of_sample(
+                &top_names,
+                &sample_name,
+                metagonic_sample,
+                (k, alpha),
+                local_window,
+                local_step,
+            ));
+        }
+
+        println!("Lowest windows:");
+        for score in local_nrc::lowest_windows(&local_scores) {
+            println!("{}:{}-{} under the model of {}: {:.6}", score.get_sequence(), score.get_start(), score.get_end(), score.get_model(), score.get_nrc());
+        }
+
+        if !local_output.is_empty() {
+            match local_nrc::export_local_scores(&local_scores, &local_output) {
+                Ok(()) => println!("Local NRC scores saved to {}", local_output),
+                Err(e) => eprintln!("Failed to save local NRC scores: {}", e),
+            }
+        }
+
+        if !local_chart.is_empty() {
+            let generator: ChartGenerator = ChartGenerator::new(alpha as f32, 4.0);
+            match generator.draw_local_nrc(&local_scores, &local_chart) {
+                Ok(()) => println!("Local NRC chart saved to {}", local_chart),
+                Err(e) => eprintln!("Failed to draw local NRC chart: {}", e),
+            }
+        }
+    }
+
+    if classify_reads {
+        let reads = match SequenceReader::open_reads(meta_file_path) {
+            Ok(reads) => reads,
+            Err(e) => {
+                println!("Error reading the reads of {}: {}", meta_file_path, e);
+                return;
+            }
+        };
+
+        // A grouped index gives one model per group to assign the reads to
+        let grouped_index = data_processor.get_model_index().filter(|model_index| model_index.get_group_by().is_some());
+        let classifier = match grouped_index {
+            Some(model_index) => {
+                println!(
+                    "\nClassifying the reads against the {} groups by {} of the index...",
+                    model_index.get_entries().len(),
+                    model_index.get_group_by().unwrap_or_default(),
+                );
+                match model_index.load_all() {
+                    Ok((names, models)) => ReadClassifier::from_models(names, models, read_threshold, alphabet_size),
+                    Err(e) => {
+                        println!("Error reading the models of the index: {}", e);
+                        return;
+                    }
+                }
+            }
+            None => {
+                println!("\nClassifying the reads against the top {} sequences...", top_names.len());
+                data_processor.read_classifier(&top_names, k, alpha, read_threshold)
+            }
+        };
+        let assignments = if threads == 1 {
+            classifier.classify_batches(reads, None)
+        } else {
+            let tracker = TaskTracker::new("Classifying reads", 0, show_progress);
+            let assignments = classifier.classify_batches(reads, Some(&tracker));
+            tracker.finish();
+            parallel_timings.push(describe_parallel_work("Read classification", &tracker));
+            assignments
+        };
+        let assignments = match assignments {
+            Ok(assignments) => assignments,
+            Err(e) => {
+                println!("Error reading the reads of {}: {}", meta_file_path, e);
+                return;
+            }
+        };
+
+        let abundances = classifier.estimate_abundances(&assignments, use_em);
+        println!(
+            "Reads classified: {}, unclassified: {}",
+            abundances.get_total_reads() - abundances.get_unclassified_count(),
+            abundances.get_unclassified_count(),
+        );
+        if let Some(iterations) = abundances.get_em_iterations() {
+            println!("EM stopped after {} iterations", iterations);
+        }
+
+        println!("Abundances:");
+        for abundance in abundances.get_abundances() {
+            println!(
+                "{}: {:.2} reads ({:.2}%), best reference of {} reads",
+                abundance.get_reference(),
+                abundance.get_estimated_reads(),
+                100.0 * abundance.get_relative_abundance(),
+                abundance.get_reads(),
+            );
+        }
+
+        if !read_output.is_empty() {
+            match classifier.export_assignments(&assignments, &abundances, &read_output) {
+                Ok(()) => println!("Read assignments saved to {}", read_output),
+                Err(e) => eprintln!("Failed to save read assignments: {}", e),
+            }
+        }
+
+        if !abundance_output.is_empty() {
+            match abundances.export_tsv(&abundance_output) {
+                Ok(()) => println!("Abundances saved to {}", abundance_output),
+                Err(e) => eprintln!("Failed to save abundances: {}", e),
+            }
+        }
+    }
+
+    if !groups_output.is_empty() {
+        let grouped = serde_json::json!({ "groups": groups, "taxonomy": rollup });
+        match std::fs::write(&groups_output, serde_json::to_string_pretty(&grouped).unwrap_or_default()) {
+            Ok(()) => println!("Grouped NRC scores saved to {}", groups_output),
+            Err(e) => eprintln!("Failed to save grouped NRC scores: {}", e),
+        }
     }
 
     let elapsed_final = now.elapsed();
 
-    // Collect sequences with scores bellow the threshold
+    // Collect sequences with scores bellow the threshold, or significant ones
     let low_scores: Vec<_> = nrc_scores.iter()
-        .filter(|(_, score)| *score < treshold)
+        .filter(|(name, score)| match p_value_threshold {
+            Some(p_value) => significance_by_name.get(name.as_str()).is_some_and(|significance| significance.get_p_value() < p_value),
+            None => *score < treshold,
+        })
         .collect();
+    let low_score_rule = match p_value_threshold {
+        Some(p_value) => format!("p-values lower than {}", p_value),
+        None => format!("NRC scores lower than {}", treshold),
+    };
 
     let low_score_names: Vec<String> = low_scores.iter().map(|(name, _)| name.clone()).collect();
 
-    println!("\nObtained sequences with NRC scores lower than {}:", treshold);
+    println!("\nObtained sequences with {}:", low_score_rule);
 
     println!("\nComputing Similarity Matrix with {} sequences...", low_score_names.len());
-    let results: Vec<ComparisionResult> = data_processor.comparative_nrc_analysis(&low_score_names, k, alpha);
+    let results: Vec<ComparisionResult> = if threads == 1 {
+        data_processor.comparative_nrc_analysis(&low_score_names, k, alpha)
+    } else {
+        let tracker = TaskTracker::new("Comparing sequences", low_score_names.len(), show_progress);
+        let results = data_processor.comparative_nrc_analysis_parallel(&low_score_names, k, alpha, &tracker);
+        tracker.finish();
+        parallel_timings.push(describe_parallel_work("Similarity matrix", &tracker));
+        results
+    };
     let output_file = "comparative_nrc_results.json";
     let _ = data_processor.export_nrc_comparisons_to_json(&results, output_file);
     println!("Similarity matrix saved to {}", output_file);
 
-    println!("\nGenerating complexity profiles for metagenomic sample and sequences with NRC scores lower than {} ...", treshold);
+    if matrix_format.is_some() || !tree_path.is_empty() {
+        println!("\nComputing {} distance matrix with {} sequences...", distance, low_score_names.len());
+        let mut matrix = data_processor.distance_matrix(&low_score_names, &distance, k, alpha);
+        matrix.symmetrise(symmetrisation);
+
+        if let Some(matrix_format) = matrix_format {
+            match matrix.export(&matrix_path, matrix_format) {
+                Ok(()) => println!("Distance matrix saved to {}", matrix_path),
+                Err(e) => eprintln!("Failed to save distance matrix: {}", e),
+            }
+        }
+
+        if !tree_path.is_empty() {
+            let tree = PhyloTree::build(&matrix, tree_method);
+            match tree.export_newick(&tree_path) {
+                Ok(()) => println!("Tree saved to {}", tree_path),
+                Err(e) => eprintln!("Failed to save tree: {}", e),
+            }
+        }
+    }
+
+    println!("\nGenerating complexity profiles for metagenomic sample and sequences with {} ...", low_score_rule);
     let mut profiles: Vec<(&str, Vec<f64>)> = Vec::new();
-    let meta_profile = model.complexity_profile(&metagonic_sample);
+    let meta_profile = model.complexity_profile_of(sample_symbols(metagonic_sample));
     profiles.push(("meta", meta_profile));
 
-    for name in &low_score_names {
+    let low_score_display_names: Vec<String> = low_score_names.iter().map(|name| data_processor.display_name(name)).collect();
+    for (name, display_name) in low_score_names.iter().zip(&low_score_display_names) {
         if let Some(profile) = data_processor.get_sequence_by_name(name) {
-            let profile = model.complexity_profile(&profile);
-            profiles.push((name.as_str(), profile));
+            let profile = model.complexity_profile(profile);
+            profiles.push((display_name.as_str(), profile));
         }
     }
 
@@ -160,5 +755,121 @@ fn main(){
     println!("\nTime taken to train the model: {:?}", elapsed);
     println!("Time taken to compute NRC scores: {:?}", elapsed_nrc - elapsed);
     println!("Total time taken: {:?}", elapsed_final);
+    for timing in &parallel_timings {
+        println!("{}", timing);
+    }
+    if let Some(model_index) = data_processor.get_model_index() {
+        println!("Models loaded from the index: {}", model_index.get_loaded_count());
+    }
 
-}
\ No newline at end of file
+}
+
+/*
+ * Keeps the `top` sequences whose sketches are the most contained
+ * in the sample. The sketches of the database are read from the
+ * cache file, when one is given, and saved to it if any was added
+*/
+fn prefilter_database(
+    data_processor: &mut DataBaseProcessor,
+    sample: &str,
+    top: usize,
+    (k, scaled): (usize, u64),
+    cache_path: &str,
+) {
+    let mut cache = if cache_path.is_empty() {
+        SketchCache::new(k, scaled)
+    } else {
+        match SketchCache::open(cache_path, k, scaled) {
+            Ok(cache) => cache,
+            Err(e) => {
+                eprintln!("Failed to read sketch cache {}: {}", cache_path, e);
+                SketchCache::new(k, scaled)
+            }
+        }
+    };
+    if !cache.is_empty() {
+        println!("{} sketches found in {}", cache.len(), cache_path);
+    }
+
+    println!("Sketching the sample and the database with k = {} and scale {}...", k, scaled);
+    let sample_sketch = Sketch::from_symbols("sample", sample_symbols(sample), k, scaled);
+    let sketches = data_processor.sketch_database(&mut cache);
+    if !cache_path.is_empty() && cache.is_modified() {
+        match cache.save(cache_path) {
+            Ok(()) => println!("Sketches saved to {}", cache_path),
+            Err(e) => eprintln!("Failed to save sketches: {}", e),
+        }
+    }
+
+    let ranking = sketch::rank_by_containment(&sample_sketch, &sketches);
+    let kept: Vec<_> = ranking.iter().take(top).collect();
+    if let (Some(first), Some(last)) = (kept.first(), kept.last()) {
+        println!(
+            "Prefilter kept {} of {} sequences, containment in the sample from {:.4} ({}) to {:.4} ({})",
+            kept.len(),
+            ranking.len(),
+            first.get_containment(),
+            first.get_name(),
+            last.get_containment(),
+            last.get_name(),
+        );
+    }
+
+    let names: HashSet<String> = kept.iter().map(|score| score.get_name().to_string()).collect();
+    data_processor.retain(&names);
+}
+
+fn print_groups(groups: &[GroupScore], top: usize) {
+    for group in groups.iter().take(top) {
+        println!(
+            "{}: best {:.6} ({}), mean {:.6} over {} sequences",
+            group.get_group(),
+            group.get_best_nrc(),
+            group.get_best_sequence(),
+            group.get_mean_nrc(),
+            group.get_sequence_count(),
+        );
+    }
+}
+
+/*
+ * Wall-clock time of a parallel step, with the time spent
+ * in its tasks and the speedup it gives over running them
+ * one after the other
+*/
+fn describe_parallel_work(step: &str, tracker: &TaskTracker) -> String {
+    format!(
+        "{} on {} threads: {:?} wall-clock, {:?} of work, estimated speedup {:.2}x",
+        step,
+        rayon::current_num_threads(),
+        tracker.get_wall_time(),
+        tracker.get_busy_time(),
+        tracker.estimated_speedup(),
+    )
+}
+
+/*
+ * Reads a metagenomic sample, memory mapped when the file allows it
+*/
+fn read_sample(meta_file_path: &str) -> Option<InputText> {
+    let mut file_reader_struct = file_reader::FileReader::new(meta_file_path);
+
+    println!("Reading file metagenomic sample in file {}...", meta_file_path);
+
+    match file_reader::read_text(&mut file_reader_struct) {
+        Ok(sample) => Some(sample),
+        Err(e) => {
+            println!("error Reading File {}: {}", meta_file_path, e);
+            None
+        }
+    }
+}
+
+/*
+ * Symbols of a sample: the sequences of its records when it
+ * is a FASTA or FASTQ file, without headers and qualities,
+ * or else all its lines, whose breaks are not symbols
+*/
+fn sample_symbols(sample: &str) -> impl Iterator<Item = char> + '_ {
+    TextRecords::new(sample, "").flat_map(|record| record.symbols())
+}
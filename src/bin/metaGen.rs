@@ -0,0 +1,188 @@
+use rand::{seq::SliceRandom, Rng, SeedableRng, rngs::StdRng};
+use tai_projects::{
+    data_base_processor::DataBaseProcessor,
+    metagenome_generator::{MetagenomeGenerator, MutationRates, SampleSource}
+};
+extern crate argparse;
+
+use argparse::{ArgumentParser, Collect, Store, StoreOption};
+
+fn main(){
+    let mut database_file_path: String = "".to_string();
+    let mut output_file_path: String = "".to_string();
+    let mut ground_truth_file_path: String = "".to_string();
+    let mut references: Vec<String> = Vec::new();
+    let mut random_references: usize = 5;
+    let mut random_proportion: f64 = 0.0;
+    let mut read_count: usize = 155;
+    let mut read_length: usize = 151;
+    let mut substitution: f64 = 0.0;
+    let mut insertion: f64 = 0.0;
+    let mut deletion: f64 = 0.0;
+    let mut seed: Option<u64> = None;
+
+    {
+        let mut argument_parser: ArgumentParser<'_> = ArgumentParser::new();
+        argument_parser.set_description("Synthetic metagenomic sample generator");
+
+        // Database file path
+        argument_parser.refer(&mut database_file_path)
+            .add_option(&["-d"], Store, "Path to the database file with the reference sequences (required)")
+            .required();
+
+        // Output sample path
+        argument_parser.refer(&mut output_file_path)
+            .add_option(&["-o"], Store, "Path to the generated metagenomic sample (required)")
+            .required();
+
+        // Ground truth manifest path
+        argument_parser.refer(&mut ground_truth_file_path)
+            .add_option(&["-g"], Store, "Path to the ground truth manifest (default: <output>.truth.json)");
+
+        // References and their proportions
+        argument_parser.refer(&mut references)
+            .add_option(&["-r"], Collect, "Reference to include as ID:PROPORTION, where ID is the first word of its header (can be repeated)");
+
+        argument_parser.refer(&mut random_references)
+            .add_option(&["-n"], Store, "Number of random references with equal proportions, used when no -r is given (default: 5)");
+
+        argument_parser.refer(&mut random_proportion)
+            .add_option(&["--random-proportion"], Store, "Proportion of reads of random DNA not taken from any reference (default: 0)");
+
+        // Reads
+        argument_parser.refer(&mut read_count)
+            .add_option(&["--reads"], Store, "Number of reads in the sample (default: 155)");
+
+        argument_parser.refer(&mut read_length)
+            .add_option(&["--read-length"], Store, "Length of each read (default: 151)");
+
+        // Mutation rates
+        argument_parser.refer(&mut substitution)
+            .add_option(&["-S"], Store, "Substitution rate per base (default: 0, must be 0 <= rate <= 1)");
+
+        argument_parser.refer(&mut insertion)
+            .add_option(&["-I"], Store, "Insertion rate per base (default: 0, must be 0 <= rate <= 1)");
+
+        argument_parser.refer(&mut deletion)
+            .add_option(&["-D"], Store, "Deletion rate per base (default: 0, must be 0 <= rate <= 1)");
+
+        argument_parser.refer(&mut seed)
+            .add_option(&["--seed"], StoreOption, "Seed of the random generator (default: random)");
+
+        argument_parser.parse_args_or_exit();
+    }
+
+    for (name, rate) in [("substitution", substitution), ("insertion", insertion), ("deletion", deletion)] {
+        if !(0.0..=1.0).contains(&rate) {
+            println!("Error: {} rate must be between 0 and 1", name);
+            return;
+        }
+    }
+
+    if substitution + deletion > 1.0 {
+        println!("Error: substitution and deletion rates must not add up to more than 1");
+        return;
+    }
+
+    if read_length < 1 {
+        println!("Error: read length must be greater than 0");
+        return;
+    }
+
+    if random_proportion < 0.0 {
+        println!("Error: random proportion must not be negative");
+        return;
+    }
+
+    if ground_truth_file_path.is_empty() {
+        ground_truth_file_path = format!("{}.truth.json", output_file_path);
+    }
+
+    let seed = seed.unwrap_or_else(|| rand::rng().random());
+
+    println!("Reading file database in file {}...", &database_file_path);
+    let data_processor = match DataBaseProcessor::new(database_file_path.to_string()) {
+        Ok(data_processor) => data_processor,
+        Err(e) => {
+            eprintln!("Error reading database {}: {}", database_file_path, e);
+            return;
+        }
+    };
+
+    // IDs sorted so that the same seed always picks the same references
+    let mut ids: Vec<&String> = data_processor.get_database().keys().collect();
+    ids.sort();
+
+    let mut sources: Vec<SampleSource> = Vec::new();
+    if references.is_empty() {
+        let mut rng = StdRng::seed_from_u64(seed);
+        ids.shuffle(&mut rng);
+        for id in ids.iter().take(random_references) {
+            sources.push(SampleSource {
+                name: id.to_string(),
+                sequence: data_processor.get_sequence_by_name(id).cloned(),
+                proportion: 1.0,
+            });
+        }
+    } else {
+        for reference in &references {
+            let (id, proportion) = match reference.rsplit_once(':').map(|(id, p)| (id, p.parse::<f64>())) {
+                Some((id, Ok(proportion))) if proportion >= 0.0 => (id, proportion),
+                _ => {
+                    println!("Error: reference {} must be given as ID:PROPORTION", reference);
+                    return;
+                }
+            };
+
+            let sequence = match data_processor.get_sequence_by_name(id) {
+                Some(sequence) => sequence,
+                None => {
+                    println!("Error: reference {} not found in the database", id);
+                    return;
+                }
+            };
+
+            sources.push(SampleSource {
+                name: id.to_string(),
+                sequence: Some(sequence.clone()),
+                proportion,
+            });
+        }
+    }
+
+    if sources.iter().map(|source| source.proportion).sum::<f64>() <= 0.0 {
+        println!("Error: no references to build the sample from");
+        return;
+    }
+
+    if random_proportion > 0.0 {
+        // Reference proportions are relative to each other, the random reads take their share of the total
+        let reference_total: f64 = sources.iter().map(|source| source.proportion).sum();
+        for source in sources.iter_mut() {
+            source.proportion *= (1.0 - random_proportion.min(1.0)) / reference_total;
+        }
+        sources.push(SampleSource {
+            name: "random".to_string(),
+            sequence: None,
+            proportion: random_proportion.min(1.0),
+        });
+    }
+
+    let rates = MutationRates { substitution, insertion, deletion };
+    let mut generator = MetagenomeGenerator::new(seed, rates, read_length);
+
+    println!("Generating {} reads from {} sources with seed {}...", read_count, sources.len(), seed);
+    let sample = generator.generate(&sources, read_count);
+
+    if let Err(e) = sample.write_sample(&output_file_path) {
+        eprintln!("Failed to write sample: {}", e);
+        return;
+    }
+    println!("Metagenomic sample saved to {}", output_file_path);
+
+    if let Err(e) = sample.write_ground_truth(&ground_truth_file_path) {
+        eprintln!("Failed to write ground truth: {}", e);
+        return;
+    }
+    println!("Ground truth saved to {}", ground_truth_file_path);
+}
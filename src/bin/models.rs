@@ -0,0 +1,195 @@
+use tai_projects::{data_base_processor::DataBaseProcessor, model_index::ModelIndex, model_saver_loader::{self, ModelMetadata}, taxonomy::GroupBy};
+extern crate argparse;
+
+use argparse::{ArgumentParser, Store};
+
+fn main(){
+    let mut command: String = "".to_string();
+    let mut path: String = "".to_string();
+    let mut database_file_path: String = "".to_string();
+    let mut k: usize = 3;
+    let mut alpha = 0.01;
+    let mut group_by_name = String::new();
+
+    {
+        let mut argument_parser: ArgumentParser<'_> = ArgumentParser::new();
+        argument_parser.set_description("Catalog of saved models");
+
+        // Command
+        argument_parser.refer(&mut command)
+            .add_argument("command", Store, "\"list\": models of a directory, \"inspect\": metadata of one model, \"index\": build or update the model index of a database")
+            .required();
+
+        // Directory or model path
+        argument_parser.refer(&mut path)
+            .add_argument("path", Store, "Directory to list (default: models), model to inspect or index directory");
+
+        // Index options
+        argument_parser.refer(&mut database_file_path)
+            .add_option(&["-d"], Store, "Path to the database file to index");
+
+        argument_parser.refer(&mut k)
+            .add_option(&["-k"], Store, "Size of the sliding window of the indexed models (default: 3, must be 1 <= k <= 20)");
+
+        argument_parser.refer(&mut alpha)
+            .add_option(&["-a"], Store, "Smoothing parameter of the indexed models (default: 0.01)");
+
+        argument_parser.refer(&mut group_by_name)
+            .add_option(&["--group-by"], Store, "One model per group instead of per record: accession, organism or field:N");
+
+        argument_parser.parse_args_or_exit();
+    }
+
+    match command.as_str() {
+        "list" => {
+            if path.is_empty() {
+                path = "models".to_string();
+            }
+            list(&path);
+        }
+        "inspect" => {
+            if path.is_empty() {
+                println!("Error: inspect needs the path of a model");
+                return;
+            }
+            inspect(&path);
+        }
+        "index" => {
+            if path.is_empty() || database_file_path.is_empty() {
+                println!("Error: index needs the index directory and a database file (-d)");
+                return;
+            }
+            if !(1..=20).contains(&k) {
+                println!("Error: k must be between 1 and 20");
+                return;
+            }
+            if !(0.0..=1.0).contains(&alpha) {
+                println!("Error: alpha must be between 0 and 1");
+                return;
+            }
+            let group_by = if group_by_name.is_empty() {
+                None
+            } else {
+                match group_by_name.parse::<GroupBy>() {
+                    Ok(group_by) => Some(group_by),
+                    Err(e) => {
+                        println!("Error: {}", e);
+                        return;
+                    }
+                }
+            };
+            index(&path, &database_file_path, (k, alpha), group_by.as_ref());
+        }
+        _ => println!("Error: command must be \"list\", \"inspect\" or \"index\""),
+    }
+}
+
+fn list(directory: &str) {
+    let models = match model_saver_loader::list_models(directory) {
+        Ok(models) => models,
+        Err(e) => {
+            eprintln!("Failed to read directory {}: {}", directory, e);
+            return;
+        }
+    };
+
+    if models.is_empty() {
+        println!("No models found in {}", directory);
+        return;
+    }
+
+    println!("{:<40} {:<8} {:>3} {:>8} {:>8}  {:<20} Training file", "Model", "Type", "k", "Alpha", "Symbols", "Created");
+    for (path, metadata) in models {
+        match metadata {
+            Some(metadata) => println!(
+                "{:<40} {:<8} {:>3} {:>8} {:>8}  {:<20} {}",
+                path,
+                metadata.model_type,
+                metadata.k,
+                metadata.alpha,
+                metadata.symbol_count,
+                format_timestamp(metadata.created_at),
+                metadata.training_file.as_deref().unwrap_or("-"),
+            ),
+            None => println!("{:<40} (no metadata)", path),
+        }
+    }
+}
+
+fn inspect(model_path: &str) {
+    let metadata: ModelMetadata = match model_saver_loader::load_metadata(model_path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            eprintln!("Failed to read metadata of {}: {}", model_path, e);
+            return;
+        }
+    };
+
+    println!("Model:          {}", model_path);
+    println!("Metadata file:  {}", model_saver_loader::metadata_path(model_path));
+    println!("Type:           {}", metadata.model_type);
+    println!("k:              {}", metadata.k);
+    println!("Alpha:          {}", metadata.alpha);
+    println!("Smoothing:      {}", metadata.smoothing);
+    println!("Symbols:        {}", metadata.symbol_count);
+    println!("Training file:  {}", metadata.training_file.as_deref().unwrap_or("-"));
+    println!("Training hash:  {}", metadata.training_hash.as_deref().unwrap_or("-"));
+    println!("Created:        {}", format_timestamp(metadata.created_at));
+    println!("Crate version:  {}", metadata.crate_version);
+
+    if let Some(training_file) = &metadata.training_file {
+        if std::path::Path::new(training_file).exists() && !metadata.was_trained_on(training_file) {
+            println!("Warning: {} has changed since the model was trained", training_file);
+        }
+    }
+}
+
+/*
+ * Trains the models of the database that are not in the
+ * index yet, or whose records have changed, and saves them
+*/
+fn index(directory: &str, database_path: &str, (k, alpha): (usize, f64), group_by: Option<&GroupBy>) {
+    println!("Reading database {}...", database_path);
+    let data_processor = match DataBaseProcessor::new(database_path.to_string()) {
+        Ok(data_processor) => data_processor,
+        Err(e) => {
+            eprintln!("Error reading database {}: {}", database_path, e);
+            return;
+        }
+    };
+    let sources = data_processor.index_sources(group_by);
+
+    println!("Indexing {} models with k = {} and alpha = {} in {}...", sources.len(), k, alpha, directory);
+    match ModelIndex::update(directory, database_path, group_by, (k, alpha), &sources) {
+        Ok((index, update)) => println!(
+            "Index of {} models: {} trained, {} unchanged, {} removed",
+            index.get_entries().len(),
+            update.get_trained(),
+            update.get_reused(),
+            update.get_removed(),
+        ),
+        Err(e) => eprintln!("Failed to update index {}: {}", directory, e),
+    }
+}
+
+/*
+ * Formats seconds since the Unix epoch as a UTC
+ * date and time, e.g. 2025-03-14 09:26:53
+*/
+fn format_timestamp(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64;
+    let time = seconds % 86400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
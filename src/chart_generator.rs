@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 use plotters::{prelude::*, style::full_palette::{ORANGE, PURPLE}};
 
+use crate::{local_nrc::LocalScore, taxonomy};
+
 pub struct ChartGenerator {
     data: HashMap<char, HashMap<char, f32>>,
     alpha: f32,
@@ -26,7 +28,7 @@ impl ChartGenerator {
         let symbol_count: f32 = *symbol_counts.get(&next_symbol).unwrap_or(&0.0);
         let total_count: f32 = symbol_counts.values().sum::<f32>();
 
-        let res = (symbol_count + self.alpha) / (total_count + self.alpha * self.total_symbols as f32);
+        let res = (symbol_count + self.alpha) / (total_count + self.alpha * self.total_symbols);
 
         -res.log2()
     }
@@ -40,7 +42,7 @@ impl ChartGenerator {
         let prob = self.compute_probability(symbol, next_symbol);
         self.probabilities.push(prob);
 
-        let entry = self.data.entry(symbol).or_insert_with(HashMap::new); 
+        let entry = self.data.entry(symbol).or_default(); 
         entry.entry(next_symbol).or_insert(0.0);
 
         *entry.get_mut(&next_symbol).unwrap() += 1.0;
@@ -51,7 +53,7 @@ impl ChartGenerator {
         let root = BitMapBackend::new(output_file, (800,600)).into_drawing_area();
         root.fill(&WHITE).unwrap();
 
-        let max_prob = self.probabilities.iter().cloned().fold(0.0/0.0, f32::max);
+        let max_prob = self.probabilities.iter().cloned().fold(f32::NAN, f32::max);
 
         let mut chart = ChartBuilder::on(&root)
             .caption("Probability Distribution", ("sans-serif", 30))
@@ -132,15 +134,59 @@ impl ChartGenerator {
                 .configure_series_labels()
                 .position(SeriesLabelPosition::UpperRight)
                 .background_style(WHITE)
-                .border_style(&BLACK)
+                .border_style(BLACK)
                 .draw()?;
         }
 
         Ok(())
     }
     
+    /*
+     * One chart per sequence and model with the NRC of its
+     * windows, drawn at the middle of each window
+    */
+    pub fn draw_local_nrc(
+        &self,
+        scores: &[LocalScore],
+        output_path: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tracks: Vec<(String, Vec<(usize, f64)>)> = Vec::new();
+        for score in scores {
+            let title = format!("{} under the model of {}", score.get_sequence(), score.get_model());
+            let point = ((score.get_start() + score.get_end()) / 2, score.get_nrc());
+            match tracks.iter_mut().find(|(track, _)| *track == title) {
+                Some((_, points)) => points.push(point),
+                None => tracks.push((title, vec![point])),
+            }
+        }
+
+        let rows = tracks.len().max(1);
+        let root = BitMapBackend::new(output_path, (1860, 300 * rows as u32)).into_drawing_area();
+        root.fill(&WHITE)?;
+
+        let areas = root.split_evenly((rows, 1));
+
+        for ((title, points), area) in tracks.iter().zip(areas) {
+            let max_x = points.iter().map(|&(x, _)| x).max().unwrap_or(0) + 1;
+            let max_val = points.iter().map(|&(_, y)| y).fold(1.0, f64::max) * 1.05;
+
+            let mut chart = ChartBuilder::on(&area)
+                .margin(10)
+                .caption(title, ("sans-serif", 20))
+                .x_label_area_size(30)
+                .y_label_area_size(50)
+                .build_cartesian_2d(0..max_x, 0f64..max_val)?;
+
+            chart.configure_mesh().disable_mesh().y_desc("NRC").draw()?;
+            chart.draw_series(LineSeries::new(points.iter().copied(), &BLUE))?;
+        }
+
+        root.present()?;
+        Ok(())
+    }
+
     /// Group by matching identifiers
-    fn group_by_identifier<'a>(profiles: Vec<(&'a str, Vec<f64>)>) -> HashMap<String, Vec<(&'a str, Vec<f64>)>> {
+    fn group_by_identifier(profiles: Vec<(&str, Vec<f64>)>) -> HashMap<String, Vec<(&str, Vec<f64>)>> {
         let mut groups: HashMap<String, Vec<(&str, Vec<f64>)>> = HashMap::new();
 
         for (name, profile) in profiles {
@@ -152,26 +198,7 @@ impl ChartGenerator {
     }
 
     fn extract_identifier(name: &str) -> String {
-        let re = regex::Regex::new(r"(NC|OR|Super)[_\d]+(?:\.\d+)?").unwrap();
-
-        if let Some(captures) = re.find(name) {
-            return captures.as_str().to_string();
-        }
-
-        // Fallback: look for ref|ID| format
-        if let Some(start) = name.find("ref|") {
-            if let Some(end) = name[start + 4..].find('|') {
-                return name[start + 4..start + 4+ end].to_string();
-            }
-        }
-
-        // Otherwise, fallback to first word
-        name.trim_start_matches('@')
-            .split_whitespace()
-            .next()
-            .unwrap_or("Unknown")
-            .to_string()
-
+        taxonomy::extract_accession(name)
     }
 
     fn smooth_profile(profile: &[f64], window_size: usize) -> Vec<f64> {
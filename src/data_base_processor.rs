@@ -1,11 +1,37 @@
 
-use std::{collections::HashMap, fs::File, io::Write};
+use std::{cmp::Ordering, collections::{BinaryHeap, HashMap, HashSet}, fs::File, io::{self, Write}};
+use rayon::prelude::*;
 use serde::Serialize;
 
-use crate::{file_reader::{self, FileReader}, finite_context_model::FiniteContextModel};
+use crate::{distance_matrix::{DistanceMatrix, DistanceMeasure}, finite_context_model::FiniteContextModel, local_nrc::LocalScore, model_index::{IndexSource, ModelIndex}, read_classifier::ReadClassifier, sequence_reader::{self, RecordFilter, SequenceReader, SequenceRecord, SequenceType, TextRecords}, significance::{NullModel, NullTarget, Significance}, sketch::{Sketch, SketchCache}, task_tracker::TaskTracker, taxonomy::{self, GroupBy, GroupScore, RankScores, TaxonomyMap}};
 
 pub struct DataBaseProcessor {
-    database: HashMap<String,String>,
+    ids: Vec<String>,                               // record IDs in file order, each only once
+    database: HashMap<String,String>,               // sequences by record ID
+    descriptions: HashMap<String,String>,
+    filtered_out: usize,
+    alphabet_size: usize,                           // symbols of the alphabet every NRC is normalised by
+    model_index: Option<ModelIndex>,                // models of the records saved by an earlier run
+}
+
+/*
+ * A database record with its NRC under the model. Records
+ * are ranked by NRC, ties by their position in the file
+*/
+pub struct ScoredRecord {
+    position: usize,
+    nrc_score: f64,
+    record: SequenceRecord,
+}
+
+/*
+ * Result of scoring a database while it is read: only
+ * the best records are kept, ranked from the lowest NRC
+*/
+pub struct StreamedNrc {
+    ranking: Vec<ScoredRecord>,
+    scored: usize,
+    filtered_out: usize,
 }
 
 #[derive(Serialize)]
@@ -21,116 +47,482 @@ pub struct MatchScore {
 }
 
 impl DataBaseProcessor{
-    pub fn new(filename:String) -> Self{
-        let mut file_reader_struct = FileReader {
-            filename: String::from(filename),
-            reader: None,
-            buffer: Vec::new(),
+    pub fn new(filename:String) -> io::Result<Self>{
+        Self::with_filter(filename, RecordFilter::default())
+    }
+
+    /*
+     * Reads a FASTA or FASTQ database, keeping only the
+     * records accepted by the filter. Records are named by
+     * their ID, so two records with the same ID are an error
+    */
+    pub fn with_filter(filename: String, filter: RecordFilter) -> io::Result<Self> {
+        let mut processor = Self {
+            ids: Vec::new(),
+            database: HashMap::new(),
+            descriptions: HashMap::new(),
+            filtered_out: 0,
+            alphabet_size: SequenceType::default().alphabet_size(0),
+            model_index: None,
+        };
+
+        processor.read_samples(&filename, &filter)?;
+        Ok(processor)
+    }
+
+    /*
+     * Database of the given records, e.g. the ranking of a streamed database
+    */
+    pub fn from_records<I: IntoIterator<Item = SequenceRecord>>(records: I) -> io::Result<Self> {
+        let mut processor = Self {
+            ids: Vec::new(),
+            database: HashMap::new(),
+            descriptions: HashMap::new(),
+            filtered_out: 0,
+            alphabet_size: SequenceType::default().alphabet_size(0),
+            model_index: None,
         };
-        let database = Self::read_samples(&mut file_reader_struct).unwrap_or_default();
-        Self { database }
+
+        for record in records {
+            processor.insert(record)?;
+        }
+        Ok(processor)
+    }
+
+    /*
+     * Scores every record of the database as it is read, so the
+     * database never has to fit in memory. Only the top_n records
+     * with the lowest NRC are kept, with their sequences. Only
+     * the IDs of the other records are kept, so that a repeated
+     * ID is an error here too
+    */
+    pub fn stream_top_nrc(
+        filename: &str,
+        filter: &RecordFilter,
+        model: &FiniteContextModel,
+        alphabet_size: usize,
+        top_n: usize,
+    ) -> io::Result<StreamedNrc> {
+        // Max-heap of the best records, the worst of them is on top and dropped first
+        let mut best: BinaryHeap<ScoredRecord> = BinaryHeap::with_capacity(top_n + 1);
+        let mut scored = 0;
+        let mut filtered_out = 0;
+        let mut ids: HashSet<String> = HashSet::new();
+
+        for (position, record) in SequenceReader::open(filename)?.enumerate() {
+            let record = record?;
+            if !filter.accepts(&record) {
+                filtered_out += 1;
+                continue;
+            }
+            if !ids.insert(record.get_id().to_string()) {
+                return Err(duplicate_id(record.get_id()));
+            }
+
+            let nrc_score = nrc(model, record.get_sequence(), alphabet_size);
+            scored += 1;
+
+            best.push(ScoredRecord { position, nrc_score, record });
+            if best.len() > top_n {
+                best.pop();
+            }
+        }
+
+        Ok(StreamedNrc {
+            ranking: best.into_sorted_vec(),
+            scored,
+            filtered_out,
+        })
     }
 
     pub fn get_database(&self) -> &HashMap<String,String> {
         &self.database
     }    
 
-    fn read_samples(mut file_reader_struct: &mut FileReader) -> Option<HashMap<String,String>> {
-        let _ = file_reader::open_file(&mut file_reader_struct);
-        let mut database= HashMap::new();
-        let mut sample: String = String::new();
-        let mut sample_name = None;
-
-        while let Ok(Some(line)) = file_reader::read_line(file_reader_struct) {
-            if line.starts_with('@') {
-                // If a previous sample exists, store it
-                if let Some(name) = sample_name.take() {
-                    database.insert(name, sample.clone());
-                }
-                sample_name = Some(line);
-                sample.clear();
-            } else {
-                sample.push_str(&line);
+    pub fn get_description(&self, id: &str) -> Option<&String> {
+        self.descriptions.get(id)
+    }
+
+    /*
+     * Number of records removed by the filter
+    */
+    pub fn get_filtered_count(&self) -> usize {
+        self.filtered_out
+    }
+
+    pub fn get_alphabet_size(&self) -> usize {
+        self.alphabet_size
+    }
+
+    /*
+     * Sets the size of the alphabet every NRC is normalised
+     * by (4, DNA, by default), resolved once for the run
+    */
+    pub fn set_alphabet_size(&mut self, alphabet_size: usize) {
+        self.alphabet_size = alphabet_size;
+    }
+
+    pub fn get_model_index(&self) -> Option<&ModelIndex> {
+        self.model_index.as_ref()
+    }
+
+    /*
+     * Index the models of the records are loaded from instead
+     * of being trained, when it has an up to date model of the
+     * record with the k and alpha asked for
+    */
+    pub fn set_model_index(&mut self, model_index: ModelIndex) {
+        self.model_index = Some(model_index);
+    }
+
+    /*
+     * Records of each model of an index: every record on its own,
+     * or the records of each group, in the order of the database file
+    */
+    pub fn index_sources(&self, group_by: Option<&GroupBy>) -> Vec<IndexSource<'_>> {
+        let mut sources: Vec<IndexSource> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+
+        for id in &self.ids {
+            let name = match group_by {
+                Some(group_by) => group_by.key(id, self.description_of(id)),
+                None => id.clone(),
+            };
+            let position = *positions.entry(name.clone()).or_insert_with(|| {
+                sources.push(IndexSource { name, records: Vec::new(), sequences: Vec::new() });
+                sources.len() - 1
+            });
+            sources[position].records.push(id.clone());
+            sources[position].sequences.push(&self.database[id]);
+        }
+        sources
+    }
+
+    /*
+     * Model of one record, from the index when it has it
+    */
+    fn reference_model(&self, name: &str, sequence: &str, k: usize, alpha: f64) -> FiniteContextModel {
+        let indexed = self.model_index.as_ref()
+            .filter(|index| index.get_k() == k && index.get_alpha() == alpha)
+            .and_then(|index| index.model_for(name, sequence));
+
+        let mut model = indexed.unwrap_or_else(|| {
+            let mut model = FiniteContextModel::new(k, alpha);
+            sequence.chars().for_each(|symbol| model.train_char(symbol));
+            model
+        });
+        model.set_alphabet_size(self.alphabet_size);
+        model
+    }
+
+    fn read_samples(&mut self, filename: &str, filter: &RecordFilter) -> io::Result<()> {
+        for record in SequenceReader::open(filename)? {
+            let record = record?;
+            if !filter.accepts(&record) {
+                self.filtered_out += 1;
+                continue;
             }
+            self.insert(record)?;
         }
 
-        // Insert the last sample if it exists
-        if let Some(name) = sample_name {
-            database.insert(name, sample);
+        Ok(())
+    }
+
+    fn insert(&mut self, record: SequenceRecord) -> io::Result<()> {
+        if self.database.contains_key(record.get_id()) {
+            return Err(duplicate_id(record.get_id()));
         }
+        let id = record.get_id().to_string();
+        self.descriptions.insert(id.clone(), record.get_description().to_string());
+        self.database.insert(id.clone(), record.into_sequence());
+        self.ids.push(id);
+        Ok(())
+    }
 
-        Some(database)
+    /*
+     * NRC of every sequence, in the order of the database file
+    */
+    pub fn compute_nrc(&self, model: &FiniteContextModel) -> Vec<(String,f64)> {
+        self.ids.iter()
+            .map(|id| (id.clone(), nrc(model, &self.database[id], self.alphabet_size)))
+            .collect()
     }
 
-    pub fn compute_nrc(&self, model: &FiniteContextModel) -> HashMap<String,f64> {
+    /*
+     * Same as compute_nrc, with the sequences scored on
+     * the threads of the rayon pool. Each task is run
+     * through the tracker to report progress and timing
+    */
+    pub fn compute_nrc_parallel(&self, model: &FiniteContextModel, tracker: &TaskTracker) -> Vec<(String,f64)> {
+        self.ids.par_iter()
+            .map(|id| tracker.run(|| (id.clone(), nrc(model, &self.database[id], self.alphabet_size))))
+            .collect()
+    }
 
-        let mut nrc_scores: HashMap<String, f64> = HashMap::new();
+    /*
+     * Significance of NRC scores against the NRC of randomised
+     * replicates of each sequence, or of the sample, whose
+     * models are trained with the k and alpha of the model.
+     * The symbols of the sample are read again by each
+     * replicate, and randomised in parts, so the sample is
+     * never copied whole. Sequences that are not in the
+     * database are skipped
+    */
+    pub fn nrc_significance<I, F>(
+        &self,
+        nrc_scores: &[(String,f64)],
+        model: &FiniteContextModel,
+        sample_symbols: F,
+        null_model: &NullModel,
+    ) -> Vec<Significance>
+    where
+        I: Iterator<Item = char>,
+        F: Fn() -> I + Sync,
+    {
+        let scored: Vec<(&str, f64, &str)> = nrc_scores.iter()
+            .filter_map(|(name, nrc_score)| self.database.get(name).map(|sequence| (name.as_str(), *nrc_score, sequence.as_str())))
+            .collect();
 
-        for (name, sequence) in &self.database {
-            let compress_size = model.calculate_information_content( sequence);
-            let sequence_length = sequence.len() as f64;
-            let nrc_score = if sequence_length > 0.0 {
-                compress_size / (2.0 * sequence_length)
-            } else {
-                0.0
-            };
+        let null_scores: Vec<Vec<f64>> = match null_model.target {
+            NullTarget::Reference => scored.par_iter()
+                .enumerate()
+                .map(|(position, &(_, _, sequence))| {
+                    let mut rng = null_model.rng(position);
+                    (0..null_model.replicates)
+                        .map(|_| nrc(model, &null_model.randomisation.randomise(sequence, &mut rng), self.alphabet_size))
+                        .collect()
+                })
+                .collect(),
+            NullTarget::Sample => {
+                let replicates: Vec<Vec<f64>> = (0..null_model.replicates).into_par_iter()
+                    .map(|replicate| {
+                        let mut rng = null_model.rng(replicate);
+                        let mut null = FiniteContextModel::new(model.get_k(), model.get_alpha());
+                        null.set_alphabet_size(self.alphabet_size);
+                        null_model.randomisation.randomise_in_parts(sample_symbols(), &mut rng, |symbol| null.train_char(symbol));
+                        scored.iter().map(|&(_, _, sequence)| nrc(&null, sequence, self.alphabet_size)).collect()
+                    })
+                    .collect();
+                (0..scored.len()).map(|i| replicates.iter().map(|scores| scores[i]).collect()).collect()
+            }
+        };
+
+        scored.iter()
+            .zip(null_scores)
+            .map(|(&(name, nrc_score, _), null_scores)| Significance::from_null(name, nrc_score, &null_scores))
+            .collect()
+    }
 
-            nrc_scores.insert(name.clone(), nrc_score);
+    /*
+     * Sketches of every sequence, in the order of the database
+     * file. Sketches found in the cache for the same sequence
+     * are reused, the others are computed on the threads of
+     * the rayon pool and added. Sketches of IDs that are not
+     * in the database any more are dropped from the cache
+    */
+    pub fn sketch_database(&self, cache: &mut SketchCache) -> Vec<Sketch> {
+        let (k, scaled) = (cache.get_k(), cache.get_scaled());
+        let hashes: Vec<String> = self.ids.par_iter()
+            .map(|id| SketchCache::sequence_hash(&self.database[id]))
+            .collect();
+        let missing: Vec<(Sketch, String)> = self.ids.par_iter().zip(&hashes)
+            .filter(|(id, hash)| cache.get(id, hash).is_none())
+            .map(|(id, hash)| (Sketch::from_symbols(id, self.database[id].chars(), k, scaled), hash.clone()))
+            .collect();
+        for (sketch, hash) in missing {
+            cache.insert(sketch, hash);
         }
+        cache.retain(&self.ids.iter().map(String::as_str).collect());
+
+        self.ids.iter().zip(&hashes)
+            .filter_map(|(id, hash)| cache.get(id, hash).cloned())
+            .collect()
+    }
 
-        nrc_scores
+    /*
+     * Keeps only the named sequences, e.g. the candidates
+     * left by a prefilter, in the order of the database file
+    */
+    pub fn retain(&mut self, names: &HashSet<String>) {
+        self.ids.retain(|id| names.contains(id));
+        self.database.retain(|id, _| names.contains(id));
+        self.descriptions.retain(|id, _| names.contains(id));
     }
 
     pub fn get_sequence_by_name(&self, name: &str) -> Option<&String> {
         self.database.get(name)
     }
 
+    /*
+     * Best and mean NRC of every group of sequences, from the
+     * best group. Sequences that are not in the database are
+     * grouped by their name only
+    */
+    pub fn group_nrc(&self, nrc_scores: &[(String,f64)], group_by: &GroupBy) -> Vec<GroupScore> {
+        taxonomy::group_scores(nrc_scores, |id| group_by.key(id, self.description_of(id)))
+    }
+
+    /*
+     * NRC scores rolled up every rank of the taxonomy,
+     * with the sequences found by their accession
+    */
+    pub fn taxonomy_rollup(&self, nrc_scores: &[(String,f64)], taxonomy_map: &TaxonomyMap) -> Vec<RankScores> {
+        taxonomy::rollup(nrc_scores, taxonomy_map, |id| GroupBy::Accession.key(id, self.description_of(id)))
+    }
+
+    fn description_of(&self, id: &str) -> &str {
+        self.descriptions.get(id).map_or("", |description| description.as_str())
+    }
+
+    /*
+     * Name of a sequence in printed results: its whole
+     * header, the ID followed by the description
+    */
+    pub fn display_name(&self, id: &str) -> String {
+        match self.description_of(id) {
+            "" => id.to_string(),
+            description => format!("{} {}", id, description),
+        }
+    }
+
+    /*
+     * Windows of the named references scored by the model of
+     * the sample: low windows are where the reference matches
+    */
+    pub fn local_nrc_of_references(
+        &self,
+        names: &[String],
+        model: &FiniteContextModel,
+        model_name: &str,
+        window: usize,
+        step: usize,
+    ) -> Vec<LocalScore> {
+        let bits_per_symbol = sequence_reader::bits_per_symbol(self.alphabet_size);
+        let windows: Vec<Vec<LocalScore>> = names.par_iter()
+            .filter_map(|name| self.database.get(name).map(|sequence| (name, sequence)))
+            .map(|(name, sequence)| {
+                model.window_nrc_of(sequence.chars(), window, step, bits_per_symbol).into_iter()
+                    .map(|window_score| LocalScore::new(name, window_score, model_name))
+                    .collect()
+            })
+            .collect();
+        windows.into_iter().flatten().collect()
+    }
+
+    /*
+     * Windows of the sample scored by a model trained on each
+     * named reference: low windows are the regions of the
+     * sample best explained by the reference. The windows of
+     * a FASTA or FASTQ sample are cut in each of its records
+     * and named by the record ID; a sample without headers is
+     * one sequence named sample_name
+    */
+    pub fn local_nrc_of_sample(
+        &self,
+        names: &[String],
+        sample_name: &str,
+        sample: &str,
+        (k, alpha): (usize, f64),
+        window: usize,
+        step: usize,
+    ) -> Vec<LocalScore> {
+        let bits_per_symbol = sequence_reader::bits_per_symbol(self.alphabet_size);
+        let windows: Vec<Vec<LocalScore>> = names.par_iter()
+            .filter_map(|name| self.database.get(name).map(|sequence| (name, sequence)))
+            .map(|(name, sequence)| {
+                let model = self.reference_model(name, sequence, k, alpha);
+                TextRecords::new(sample, sample_name)
+                    .flat_map(|record| {
+                        model.window_nrc_of(record.symbols(), window, step, bits_per_symbol).into_iter()
+                            .map(move |window_score| LocalScore::new(record.get_id(), window_score, name))
+                    })
+                    .collect()
+            })
+            .collect();
+        windows.into_iter().flatten().collect()
+    }
+
+    /*
+     * Distances between the named sequences of the
+     * database, names that are not in it are skipped.
+     * The models of the rows come from the index when
+     * it has them
+    */
+    pub fn distance_matrix(&self, names: &[String], measure: &DistanceMeasure, k: usize, alpha: f64) -> DistanceMatrix {
+        let (names, sequences): (Vec<String>, Vec<&str>) = names.iter()
+            .filter_map(|name| self.database.get(name).map(|sequence| (name.clone(), sequence.as_str())))
+            .unzip();
+        DistanceMatrix::compute_with_models(
+            &names,
+            &sequences,
+            measure,
+            |row| self.reference_model(&names[row], sequences[row], k, alpha),
+            self.alphabet_size,
+        )
+    }
+
+    /*
+     * Classifier of reads with a model of each named
+     * sequence, names that are not in the database are skipped
+    */
+    pub fn read_classifier(&self, names: &[String], k: usize, alpha: f64, threshold: f64) -> ReadClassifier {
+        let (names, models): (Vec<String>, Vec<FiniteContextModel>) = names.par_iter()
+            .filter_map(|name| self.database.get(name).map(|sequence| (name.clone(), self.reference_model(name, sequence, k, alpha))))
+            .unzip();
+        ReadClassifier::from_models(names, models, threshold, self.alphabet_size)
+    }
+
     pub fn comparative_nrc_analysis(
         &self, 
         low_score_names: &[String], 
         k: usize, 
         alpha: f64
     ) -> Vec<ComparisionResult> {
-        let mut comparison_results: Vec<ComparisionResult> = Vec::new();
-
-        for low_name in low_score_names {
-            if let Some(low_sequence) = self.database.get(low_name) {
-                // Train a model on the low NRC sequence
-                let mut model = FiniteContextModel::new(k, alpha);
-                for char in low_sequence.chars() {
-                    model.train_char(char);
-                }
-
-                // Calculate NRC for all other sequences
-                let mut comparisons: Vec<_> = Vec::new();
-                for target_name in low_score_names {
-                    if let Some(target_sequence) = self.database.get(target_name) {
-
-                        let compressed_size = model.calculate_information_content(target_sequence);
-                        let nrc_score = if !target_sequence.is_empty() {
-                            compressed_size / (2.0 * target_sequence.len() as f64)
-                        } else {
-                            0.0
-                        };
-
-                        comparisons.push(MatchScore {
-                            target_name: target_name.clone(),
-                            nrc_score,
-                        });
-                    }
-                }
-
-                // Sort comparisons by NRC score
-                comparisons.sort_by(|a, b| a.nrc_score.partial_cmp(&b.nrc_score).unwrap());
-
-                comparison_results.push(ComparisionResult {
-                    base_sequence: low_name.clone(),
-                    matches: comparisons,
+        low_score_names.iter()
+            .filter_map(|low_name| self.compare_with(low_name, low_score_names, k, alpha))
+            .collect()
+    }
+
+    /*
+     * Same as comparative_nrc_analysis, with one task per
+     * base sequence on the threads of the rayon pool
+    */
+    pub fn comparative_nrc_analysis_parallel(
+        &self,
+        low_score_names: &[String],
+        k: usize,
+        alpha: f64,
+        tracker: &TaskTracker,
+    ) -> Vec<ComparisionResult> {
+        low_score_names.par_iter()
+            .filter_map(|low_name| tracker.run(|| self.compare_with(low_name, low_score_names, k, alpha)))
+            .collect()
+    }
+
+    fn compare_with(&self, low_name: &str, low_score_names: &[String], k: usize, alpha: f64) -> Option<ComparisionResult> {
+        let low_sequence = self.database.get(low_name)?;
+
+        // Train a model on the low NRC sequence, or load it from the index
+        let model = self.reference_model(low_name, low_sequence, k, alpha);
+
+        // Calculate NRC for all other sequences
+        let mut comparisons: Vec<_> = Vec::new();
+        for target_name in low_score_names {
+            if let Some(target_sequence) = self.database.get(target_name) {
+                comparisons.push(MatchScore {
+                    target_name: self.display_name(target_name),
+                    nrc_score: nrc(&model, target_sequence, self.alphabet_size),
                 });
             }
         }
 
-        comparison_results
+        // Sort comparisons by NRC score
+        comparisons.sort_by(|a, b| a.nrc_score.total_cmp(&b.nrc_score));
+
+        Some(ComparisionResult {
+            base_sequence: self.display_name(low_name),
+            matches: comparisons,
+        })
     }
 
     pub fn export_nrc_comparisons_to_json(
@@ -143,4 +535,120 @@ impl DataBaseProcessor{
         file.write_all(json_data.as_bytes())?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl ScoredRecord {
+
+    pub fn get_position(&self) -> usize {
+        self.position
+    }
+
+    pub fn get_nrc_score(&self) -> f64 {
+        self.nrc_score
+    }
+
+    pub fn get_record(&self) -> &SequenceRecord {
+        &self.record
+    }
+}
+
+impl Ord for ScoredRecord {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.nrc_score.total_cmp(&other.nrc_score)
+            .then(self.position.cmp(&other.position))
+    }
+}
+
+impl PartialOrd for ScoredRecord {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ScoredRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ScoredRecord {}
+
+impl StreamedNrc {
+
+    /*
+     * Kept records, from the lowest NRC
+    */
+    pub fn get_ranking(&self) -> &[ScoredRecord] {
+        &self.ranking
+    }
+
+    /*
+     * Number of records scored, including those not kept
+    */
+    pub fn get_scored_count(&self) -> usize {
+        self.scored
+    }
+
+    pub fn get_filtered_count(&self) -> usize {
+        self.filtered_out
+    }
+
+    pub fn into_records(self) -> impl Iterator<Item = SequenceRecord> {
+        self.ranking.into_iter().map(|scored| scored.record)
+    }
+}
+
+fn duplicate_id(id: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Duplicate record ID \"{}\" in the database", id))
+}
+
+/*
+ * Sorts NRC scores from the lowest. The sort is stable, so
+ * equal scores keep the order of the database file
+*/
+pub fn rank_nrc(nrc_scores: &mut [(String,f64)]) {
+    nrc_scores.sort_by(|a, b| a.1.total_cmp(&b.1));
+}
+
+/*
+ * Bits of the sequence under the model over the bits of its
+ * symbols when all symbols of the alphabet are equally likely
+*/
+pub(crate) fn nrc(model: &FiniteContextModel, sequence: &str, alphabet_size: usize) -> f64 {
+    let symbol_count = sequence.chars().count() as f64;
+    if symbol_count > 0.0 {
+        model.calculate_information_content(sequence) / (sequence_reader::bits_per_symbol(alphabet_size) * symbol_count)
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parallel_scores_are_the_sequential_ones() {
+        let path = std::env::temp_dir().join(format!("tai_database_{}.fa", std::process::id()));
+        std::fs::write(&path, ">a\nACGTACGTAA\n>b\nTTGACCATGA\n>c\nACGTTTGACA\n>d\nGGGCCCAAAT\n>e\nACGTACGTAC\n").unwrap();
+        let data_processor = DataBaseProcessor::new(path.to_string_lossy().to_string()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut model = FiniteContextModel::new(2, 0.1);
+        "ACGTACGTTTGACA".chars().for_each(|symbol| model.train_char(symbol));
+        let names: Vec<String> = ["a", "b", "c", "d", "e"].iter().map(|name| name.to_string()).collect();
+
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let tracker = TaskTracker::new("Scoring", names.len(), false);
+        let (nrc_scores, comparisons) = pool.install(|| (
+            data_processor.compute_nrc_parallel(&model, &tracker),
+            data_processor.comparative_nrc_analysis_parallel(&names, 2, 0.1, &tracker),
+        ));
+
+        assert_eq!(nrc_scores, data_processor.compute_nrc(&model));
+        assert_eq!(
+            serde_json::to_string(&comparisons).unwrap(),
+            serde_json::to_string(&data_processor.comparative_nrc_analysis(&names, 2, 0.1)).unwrap(),
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use std::{fs::File, io::{self, BufWriter, Write}, str::FromStr};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{data_base_processor, finite_context_model::FiniteContextModel, ncd};
+
+const COMPRESSORS: [&str; 5] = ["gz", "bz2", "xz", "zstd", "lzma"];
+
+/*
+ * How the distance from one sequence to another is measured.
+ * NRC and FCM-NCD use a model trained on the sequence of the
+ * row, so they are not symmetric
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub enum DistanceMeasure {
+    Nrc,
+    FcmNcd,
+    Ncd(String),                                    // compressor: gz, bz2, xz, zstd or lzma
+}
+
+impl FromStr for DistanceMeasure {
+    type Err = String;
+
+    fn from_str(measure: &str) -> Result<Self, Self::Err> {
+        match measure {
+            "nrc" => Ok(DistanceMeasure::Nrc),
+            "fcm-ncd" => Ok(DistanceMeasure::FcmNcd),
+            _ => match measure.strip_prefix("ncd-") {
+                Some(compressor) if COMPRESSORS.contains(&compressor) => Ok(DistanceMeasure::Ncd(compressor.to_string())),
+                _ => Err(format!(
+                    "invalid distance \"{}\", expected nrc, fcm-ncd or ncd-<compressor> with one of {}",
+                    measure,
+                    COMPRESSORS.join(", ")
+                )),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for DistanceMeasure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DistanceMeasure::Nrc => write!(f, "nrc"),
+            DistanceMeasure::FcmNcd => write!(f, "fcm-ncd"),
+            DistanceMeasure::Ncd(compressor) => write!(f, "ncd-{}", compressor),
+        }
+    }
+}
+
+/*
+ * How the two directions of a pair are combined
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Symmetrisation {
+    #[default]
+    None,
+    Mean,
+    Min,
+    Max,
+}
+
+impl FromStr for Symmetrisation {
+    type Err = String;
+
+    fn from_str(symmetrisation: &str) -> Result<Self, Self::Err> {
+        match symmetrisation {
+            "none" => Ok(Symmetrisation::None),
+            "mean" => Ok(Symmetrisation::Mean),
+            "min" => Ok(Symmetrisation::Min),
+            "max" => Ok(Symmetrisation::Max),
+            _ => Err(format!("invalid symmetrisation \"{}\", expected none, mean, min or max", symmetrisation)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatrixFormat {
+    Csv,
+    Tsv,
+    Phylip,
+    Json,
+}
+
+impl MatrixFormat {
+
+    /*
+     * Format given by the extension of the path, e.g. matrix.phy
+    */
+    pub fn from_path(path: &str) -> Option<Self> {
+        let extension = path.rsplit_once('.')?.1.to_lowercase();
+        match extension.as_str() {
+            "csv" => Some(MatrixFormat::Csv),
+            "tsv" | "txt" => Some(MatrixFormat::Tsv),
+            "phy" | "phylip" | "dist" => Some(MatrixFormat::Phylip),
+            "json" => Some(MatrixFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/*
+ * Distances between every pair of a set of sequences.
+ * values[i][j] is the distance from sequence i to
+ * sequence j, e.g. the NRC of j under the model of i
+*/
+#[derive(Serialize)]
+pub struct DistanceMatrix {
+    measure: String,
+    symmetrisation: Symmetrisation,
+    names: Vec<String>,
+    values: Vec<Vec<f64>>,
+}
+
+impl DistanceMatrix {
+
+    /*
+     * Computes the matrix with one row per task on the
+     * threads of the rayon pool. k and alpha are used
+     * by the models of NRC and FCM-NCD, which are smoothed
+     * over an alphabet of alphabet_size symbols, and NRC is
+     * normalised by the same alphabet.
+     * There must be one name per sequence
+    */
+    pub fn compute(
+        names: &[String],
+        sequences: &[&str],
+        measure: &DistanceMeasure,
+        (k, alpha): (usize, f64),
+        alphabet_size: usize,
+    ) -> Self {
+        Self::compute_with_models(names, sequences, measure, |row| train_model(sequences[row], k, alpha), alphabet_size)
+    }
+
+    /*
+     * Same as compute, with the model of each row given by
+     * model_of, e.g. loaded from an index instead of trained.
+     * It is only called for NRC and FCM-NCD
+    */
+    pub fn compute_with_models<F>(
+        names: &[String],
+        sequences: &[&str],
+        measure: &DistanceMeasure,
+        model_of: F,
+        alphabet_size: usize,
+    ) -> Self
+    where
+        F: Fn(usize) -> FiniteContextModel + Sync,
+    {
+        assert!(names.len() == sequences.len(), "distance matrix needs one name per sequence");
+        let values: Vec<Vec<f64>> = sequences.par_iter()
+            .enumerate()
+            .map(|(row, base)| match measure {
+                DistanceMeasure::Nrc => {
+                    let mut model = model_of(row);
+                    model.set_alphabet_size(alphabet_size);
+                    sequences.iter().map(|target| data_base_processor::nrc(&model, target, alphabet_size)).collect()
+                }
+                DistanceMeasure::FcmNcd => {
+                    let mut model = model_of(row);
+                    model.set_alphabet_size(alphabet_size);
+                    sequences.iter().map(|target| ncd::compute_ncd_fcm(base, target, &model)).collect()
+                }
+                DistanceMeasure::Ncd(compressor) => {
+                    sequences.iter().map(|target| ncd::compute_ncd(base, target, compressor)).collect()
+                }
+            })
+            .collect();
+
+        Self {
+            measure: measure.to_string(),
+            symmetrisation: Symmetrisation::None,
+            names: names.to_vec(),
+            values,
+        }
+    }
+
+    /*
+     * Matrix of distances computed elsewhere, e.g. by
+     * another tool. values must be a names.len() square
+    */
+    pub fn new(measure: &str, names: Vec<String>, values: Vec<Vec<f64>>) -> Self {
+        assert!(values.len() == names.len() && values.iter().all(|row| row.len() == names.len()), "distance matrix must be square");
+        Self {
+            measure: measure.to_string(),
+            symmetrisation: Symmetrisation::None,
+            names,
+            values,
+        }
+    }
+
+    pub fn get_names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn get_values(&self) -> &[Vec<f64>] {
+        &self.values
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> f64 {
+        self.values[i][j]
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /*
+     * Replaces both directions of every pair by their mean,
+     * minimum or maximum. The diagonal is left as it is
+    */
+    pub fn symmetrise(&mut self, symmetrisation: Symmetrisation) {
+        if symmetrisation == Symmetrisation::None {
+            return;
+        }
+
+        for i in 0..self.len() {
+            for j in (i + 1)..self.len() {
+                let (a, b) = (self.values[i][j], self.values[j][i]);
+                let value = match symmetrisation {
+                    Symmetrisation::Mean => (a + b) / 2.0,
+                    Symmetrisation::Min => a.min(b),
+                    Symmetrisation::Max => a.max(b),
+                    Symmetrisation::None => unreachable!(),
+                };
+                self.values[i][j] = value;
+                self.values[j][i] = value;
+            }
+        }
+
+        self.symmetrisation = symmetrisation;
+    }
+
+    pub fn export(&self, path: &str, format: MatrixFormat) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        match format {
+            MatrixFormat::Csv => self.write_delimited(&mut writer, ','),
+            MatrixFormat::Tsv => self.write_delimited(&mut writer, '\t'),
+            MatrixFormat::Phylip => self.write_phylip(&mut writer),
+            MatrixFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, self)?;
+                writeln!(writer)
+            }
+        }?;
+        writer.flush()
+    }
+
+    /*
+     * Square matrix with the names in the first row and column
+    */
+    pub fn write_delimited<W: Write>(&self, writer: &mut W, delimiter: char) -> io::Result<()> {
+        let field = |name: &str| -> String {
+            if name.contains(delimiter) || name.contains('"') {
+                format!("\"{}\"", name.replace('"', "\"\""))
+            } else {
+                name.to_string()
+            }
+        };
+
+        let header: Vec<String> = self.names.iter().map(|name| field(name)).collect();
+        writeln!(writer, "{}{}", delimiter, header.join(&delimiter.to_string()))?;
+
+        for (name, row) in self.names.iter().zip(&self.values) {
+            let values: Vec<String> = row.iter().map(|value| format!("{:.6}", value)).collect();
+            writeln!(writer, "{}{}{}", field(name), delimiter, values.join(&delimiter.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Square PHYLIP distance matrix as read by the PHYLIP
+     * neighbor program and most tree tools: the number of
+     * sequences, then one row per sequence. Names are cut or
+     * padded to 10 characters, as strict PHYLIP requires, and
+     * their whitespace is replaced by '_'
+    */
+    pub fn write_phylip<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "{:>5}", self.len())?;
+
+        for (name, row) in self.names.iter().zip(&self.values) {
+            let name: String = name.chars().take(10).map(|c| if c.is_whitespace() { '_' } else { c }).collect();
+            let values: Vec<String> = row.iter().map(|value| format!("{:.6}", value)).collect();
+            writeln!(writer, "{:<10} {}", name, values.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+fn train_model(sequence: &str, k: usize, alpha: f64) -> FiniteContextModel {
+    let mut model = FiniteContextModel::new(k, alpha);
+    sequence.chars().for_each(|symbol| model.train_char(symbol));
+    model
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phylip_names_are_cut_to_ten_characters() {
+        let names = vec!["NC_000913.3 Escherichia".to_string(), "short".to_string()];
+        let matrix = DistanceMatrix::new("nrc", names, vec![vec![0.0, 0.5], vec![0.5, 0.0]]);
+
+        let mut output = Vec::new();
+        matrix.write_phylip(&mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "    2\nNC_000913. 0.000000 0.500000\nshort      0.500000 0.000000\n"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "one name per sequence")]
+    fn compute_needs_one_name_per_sequence() {
+        DistanceMatrix::compute(&["a".to_string()], &["ACGT", "TTGA"], &DistanceMeasure::Nrc, (2, 0.1), 4);
+    }
+
+    #[test]
+    fn given_models_score_like_trained_ones() {
+        let names = ["a".to_string(), "b".to_string()];
+        let sequences = ["ACGTACGA", "TTGACCAT"];
+        let trained = DistanceMatrix::compute(&names, &sequences, &DistanceMeasure::Nrc, (2, 0.1), 4);
+
+        let calls = std::sync::atomic::AtomicUsize::new(0);
+        let given = DistanceMatrix::compute_with_models(&names, &sequences, &DistanceMeasure::Nrc, |row| {
+            calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            train_model(sequences[row], 2, 0.1)
+        }, 4);
+        assert_eq!(calls.into_inner(), 2);
+        assert_eq!(trained.get_values(), given.get_values());
+    }
+}
@@ -1,75 +1,394 @@
-use std::{fs::File, io::{self, BufRead, BufReader, Read}};
+use std::{fs::File, io::{self, BufRead, BufReader, Read}, str::FromStr};
+use flate2::read::MultiGzDecoder;
+use bzip2::read::MultiBzDecoder;
+use xz2::read::XzDecoder;
+use memmap2::Mmap;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const XZ_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+// "BZh" is followed by the block size, '1' to '9', then the magic of the first block or of the end of the stream
+const BZIP2_BLOCK_MAGIC: &[u8] = &[0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+const BZIP2_END_MAGIC: &[u8] = &[0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+
+// Bytes peeked at the start of the input to recognise compressed data
+const HEADER_LENGTH: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Compression {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+
+    /*
+     * Recognises compressed data from its first HEADER_LENGTH
+     * bytes, or fewer if the input is shorter
+    */
+    fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if is_bzip2(header) {
+            Some(Compression::Bzip2)
+        } else if header.starts_with(XZ_MAGIC) {
+            Some(Compression::Xz)
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+fn is_bzip2(header: &[u8]) -> bool {
+    header.len() >= HEADER_LENGTH
+        && header.starts_with(BZIP2_MAGIC)
+        && (b'1'..=b'9').contains(&header[3])
+        && (header[4..10] == *BZIP2_BLOCK_MAGIC || header[4..10] == *BZIP2_END_MAGIC)
+}
+
+/*
+ * What to do with bytes that are not valid UTF-8,
+ * including a sequence cut short by the end of input
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InvalidUtf8Policy {
+    #[default]
+    Error,                                          // fail with the byte offset
+    Skip,                                           // drop the invalid bytes
+    Replace,                                        // decode them as U+FFFD
+}
+
+impl FromStr for InvalidUtf8Policy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "error" => Ok(InvalidUtf8Policy::Error),
+            "skip" => Ok(InvalidUtf8Policy::Skip),
+            "replace" => Ok(InvalidUtf8Policy::Replace),
+            _ => Err(format!("invalid UTF-8 policy \"{}\", expected error, skip or replace", policy)),
+        }
+    }
+}
+
+/*
+ * The whole text of an input. Uncompressed files of valid
+ * UTF-8 are memory-mapped, so even multi-gigabyte inputs
+ * are iterated without copying them into a String. Only
+ * read_text makes one, after checking the UTF-8 once
+*/
+pub struct InputText {
+    text: Text,
+}
+
+enum Text {
+    Mapped(Mmap),                                   // checked to be UTF-8 when it was mapped
+    Owned(String),
+}
+
+impl InputText {
+
+    pub fn as_str(&self) -> &str {
+        match &self.text {
+            // SAFETY: read_text checked that the mapped bytes are UTF-8. They stay
+            // so as long as no other process writes the file, which the map itself
+            // already assumes (see read_text)
+            Text::Mapped(mmap) => unsafe { std::str::from_utf8_unchecked(mmap) },
+            Text::Owned(text) => text,
+        }
+    }
+}
 
 pub struct FileReader {
-    pub filename: String,
-    pub reader: Option<BufReader<File>>,
+    pub filename: String,                           // "-" reads from stdin
+    pub reader: Option<BufReader<Box<dyn Read>>>,
     pub buffer: Vec<String>,
+    pub invalid_utf8: InvalidUtf8Policy,
+    pending: Vec<u8>,                               // bytes read but not decoded yet, e.g. half a character
+    offset: u64,                                    // byte offset of the first pending byte
+    invalid_sequences: usize,
+    first_invalid_offset: Option<u64>,
 }
 
+impl FileReader {
+
+    pub fn new(filename: &str) -> Self {
+        Self {
+            filename: filename.to_string(),
+            reader: None,
+            buffer: Vec::new(),
+            invalid_utf8: InvalidUtf8Policy::Error,
+            pending: Vec::new(),
+            offset: 0,
+            invalid_sequences: 0,
+            first_invalid_offset: None,
+        }
+    }
+
+    /*
+     * Number of invalid sequences skipped or replaced so far
+    */
+    pub fn get_invalid_sequences(&self) -> usize {
+        self.invalid_sequences
+    }
+
+    /*
+     * Byte offset of the first invalid sequence, counted
+     * in the decompressed input
+    */
+    pub fn get_first_invalid_offset(&self) -> Option<u64> {
+        self.first_invalid_offset
+    }
+
+    /*
+     * Applies the policy to `length` invalid bytes at the
+     * start of the pending bytes, returning the character
+     * that replaces them, if any
+    */
+    fn handle_invalid(&mut self, length: usize) -> io::Result<Option<char>> {
+        if self.invalid_utf8 == InvalidUtf8Policy::Error {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid UTF-8 in {} at byte offset {}", self.filename, self.offset),
+            ));
+        }
+
+        self.invalid_sequences += 1;
+        self.first_invalid_offset.get_or_insert(self.offset);
+        self.consume(length);
+
+        if self.invalid_utf8 == InvalidUtf8Policy::Replace {
+            Ok(Some(char::REPLACEMENT_CHARACTER))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn consume(&mut self, length: usize) {
+        self.pending.drain(..length);
+        self.offset += length as u64;
+    }
+
+    /*
+     * Appends up to `size` bytes from the reader to the
+     * pending bytes, returning how many were read
+    */
+    fn fill(&mut self, size: usize) -> io::Result<usize> {
+        let reader = match self.reader.as_mut() {
+            Some(r) => r,
+            None => return Ok(0),
+        };
+
+        let start = self.pending.len();
+        self.pending.resize(start + size, 0);
+        match reader.read(&mut self.pending[start..]) {
+            Ok(bytes_read) => {
+                self.pending.truncate(start + bytes_read);
+                Ok(bytes_read)
+            }
+            Err(e) => {
+                self.pending.truncate(start);
+                Err(e)
+            }
+        }
+    }
+
+    /*
+     * Decodes the pending bytes into `output`. A character
+     * split at the end is kept for the next chunk, unless
+     * there is no more input
+    */
+    fn decode_pending(&mut self, output: &mut String, end_of_input: bool) -> io::Result<()> {
+        loop {
+            let (valid, error_length) = match std::str::from_utf8(&self.pending) {
+                Ok(s) => {
+                    output.push_str(s);
+                    let length = self.pending.len();
+                    self.consume(length);
+                    return Ok(());
+                }
+                Err(e) => {
+                    output.push_str(std::str::from_utf8(&self.pending[..e.valid_up_to()]).unwrap());
+                    (e.valid_up_to(), e.error_len())
+                }
+            };
+            self.consume(valid);
+
+            let length = match error_length {
+                Some(length) => length,
+                None if end_of_input => self.pending.len(),
+                None => return Ok(()),
+            };
+            if let Some(replacement) = self.handle_invalid(length)? {
+                output.push(replacement);
+            }
+        }
+    }
+}
+
+/*
+ * Opens the file, or stdin if the filename is "-".
+ * gzip, bzip2, xz and zstd input is decompressed
+ * transparently
+*/
 pub fn open_file(file_reader: &mut FileReader) -> io::Result<()> {
+    if file_reader.filename == "-" {
+        return open_reader(file_reader, io::stdin());
+    }
     let file = File::open(&file_reader.filename)?;
-    file_reader.reader = Some(BufReader::new(file));
-    Ok(())
+    open_reader(file_reader, file)
 }
 
-pub fn read_char(file_reader: &mut FileReader) -> io::Result<Option<char>> {
-    let mut byte_buff = [0u8; 4]; // UTF-8 chars can be up to 4 bytes
-    let reader = match file_reader.reader.as_mut() {
-        Some(r) => r,
-        None => return Ok(None), // No reader available
+/*
+ * Reads from any source, detecting compressed
+ * data by its magic bytes rather than by the
+ * extension so piped input is handled too
+*/
+pub fn open_reader<R: Read + 'static>(file_reader: &mut FileReader, mut source: R) -> io::Result<()> {
+    // A single read, e.g. from a pipe, may return fewer bytes than the magic
+    let mut header = vec![0; HEADER_LENGTH];
+    let mut header_length = 0;
+    while header_length < HEADER_LENGTH {
+        match source.read(&mut header[header_length..]) {
+            Ok(0) => break,
+            Ok(bytes_read) => header_length += bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    header.truncate(header_length);
+
+    let compression = Compression::detect(&header);
+    let source = BufReader::new(io::Cursor::new(header).chain(source));
+
+    let decoded: Box<dyn Read> = match compression {
+        Some(Compression::Gzip) => Box::new(MultiGzDecoder::new(source)),
+        Some(Compression::Bzip2) => Box::new(MultiBzDecoder::new(source)),
+        Some(Compression::Xz) => Box::new(XzDecoder::new_multi_decoder(source)),
+        Some(Compression::Zstd) => Box::new(zstd::stream::read::Decoder::with_buffer(source)?),
+        None => Box::new(source),
     };
 
-    let mut first_byte = [0u8; 1];
-    if reader.read(&mut first_byte)? == 0 {
-        return Ok(None); // End of file
-    }
+    file_reader.reader = Some(BufReader::new(decoded));
+    file_reader.buffer.clear();
+    file_reader.pending.clear();
+    file_reader.offset = 0;
+    file_reader.invalid_sequences = 0;
+    file_reader.first_invalid_offset = None;
+    Ok(())
+}
 
-    byte_buff[0] = first_byte[0];
-    let char_len = utf8_char_length(byte_buff[0]);
+/*
+ * Reads the whole input, memory-mapping it when it is an
+ * uncompressed file of valid UTF-8. stdin, compressed files
+ * and text that needs the invalid UTF-8 policy are read
+ * through the reader instead. The UTF-8 of a mapped file is
+ * checked once, here. A mapping shows later writes to the
+ * file, so the file must not be written or truncated while
+ * the text is in use; copy it first if another process may
+ * change it
+*/
+pub fn read_text(file_reader: &mut FileReader) -> io::Result<InputText> {
+    if file_reader.filename != "-" {
+        let file = File::open(&file_reader.filename)?;
+        if file.metadata()?.len() > 0 {
+            // SAFETY: the map is read only and owned by the returned text, so it
+            // is never unmapped while borrowed. Like any file mapping, it assumes
+            // no other process truncates or writes the file while it is read,
+            // which would also break the UTF-8 checked below
+            let mmap = unsafe { Mmap::map(&file)? };
+            let compressed = Compression::detect(&mmap[..mmap.len().min(HEADER_LENGTH)]).is_some();
 
-    for i in 1..char_len {
-        if reader.read(&mut byte_buff[i..i + 1])? == 0 {
-            return Ok(None);
+            if !compressed {
+                match std::str::from_utf8(&mmap) {
+                    Ok(_) => return Ok(InputText { text: Text::Mapped(mmap) }),
+                    Err(e) if file_reader.invalid_utf8 == InvalidUtf8Policy::Error => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Invalid UTF-8 in {} at byte offset {}", file_reader.filename, e.valid_up_to()),
+                        ));
+                    }
+                    Err(_) => {}
+                }
+            }
         }
     }
 
-    match std::str::from_utf8(&byte_buff[..char_len]) {
-        Ok(s) => Ok(s.chars().next()),
-        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8")),
+    open_file(file_reader)?;
+    let mut text = String::new();
+    let mut text_buff = String::new();
+    while read_buff(file_reader, &mut text_buff, 1 << 16)? > 0 {
+        text.push_str(&text_buff);
     }
+    Ok(InputText { text: Text::Owned(text) })
 }
 
-pub fn read_buff(file_reader: &mut FileReader, buff: &mut String, size: usize) -> io::Result<usize> {
-    buff.clear();
-    let mut byte_buff = vec![0u8; size];
-    let reader = match file_reader.reader.as_mut() {
-        Some(r) => r,
-        None => return Ok(0),
-    };
+/*
+ * Reads one character. Invalid or truncated
+ * sequences are handled by the reader's policy
+*/
+pub fn read_char(file_reader: &mut FileReader) -> io::Result<Option<char>> {
+    loop {
+        if file_reader.pending.is_empty() && file_reader.fill(1)? == 0 {
+            return Ok(None); // End of file
+        }
+
+        let char_len = utf8_char_length(file_reader.pending[0]);
+        while file_reader.pending.len() < char_len {
+            if file_reader.fill(char_len - file_reader.pending.len())? == 0 {
+                break; // Truncated by the end of file
+            }
+        }
+
+        let end = char_len.min(file_reader.pending.len());
+        let invalid_length = match std::str::from_utf8(&file_reader.pending[..end]) {
+            Ok(s) => {
+                let symbol = s.chars().next();
+                file_reader.consume(end);
+                return Ok(symbol);
+            }
+            Err(e) => e.error_len().unwrap_or(end),
+        };
 
-    let bytes_read = reader.read(&mut byte_buff)?;
-    if bytes_read == 0 {
-        return Ok(0);
+        if let Some(replacement) = file_reader.handle_invalid(invalid_length)? {
+            return Ok(Some(replacement));
+        }
     }
+}
+
+/*
+ * Reads a chunk of about `size` bytes into `buff`, returning
+ * the number of characters read, 0 at the end of the file.
+ * A character split by the chunk boundary is carried over
+ * to the next chunk instead of failing
+*/
+pub fn read_buff(file_reader: &mut FileReader, buff: &mut String, size: usize) -> io::Result<usize> {
+    buff.clear();
 
-    match String::from_utf8(byte_buff[..bytes_read].to_vec()) {
-        Ok(s) => {
-            buff.push_str(&s);
-            Ok(s.chars().count())
+    while buff.is_empty() {
+        let bytes_read = file_reader.fill(size.max(1))?;
+        if bytes_read == 0 && file_reader.pending.is_empty() {
+            return Ok(0);
         }
-        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8")),
+        file_reader.decode_pending(buff, bytes_read == 0)?;
     }
+
+    Ok(buff.chars().count())
 }
 
 pub fn read_word(file_reader: &mut FileReader) -> io::Result<Option<String>> {
-    let reader = match file_reader.reader.as_mut() {
-        Some(r) => r,
-        None => return Ok(None),
-    };
+    if file_reader.reader.is_none() {
+        return Ok(None);
+    }
 
     if file_reader.buffer.is_empty(){
-        let mut buffer = String::new();
-        reader.read_line(&mut buffer)?;
+        let buffer = read_line_raw(file_reader)?.unwrap_or_default();
     
         for word in buffer.rsplit(' ').filter(|w| !w.is_empty()){
             file_reader.buffer.push(String::from(word));
@@ -80,23 +399,35 @@ pub fn read_word(file_reader: &mut FileReader) -> io::Result<Option<String>> {
         return Ok(None);
     }
 
-    return Ok(Some(file_reader.buffer.remove(0)))
+    Ok(Some(file_reader.buffer.remove(0)))
 
 }
 
 pub fn read_line(file_reader: &mut FileReader) -> io::Result<Option<String>> {
-    if let Some(reader) = file_reader.reader.as_mut() {
-        let mut buffer = String::new();
-        let bytes_read = reader.read_line(&mut buffer)?;
+    match read_line_raw(file_reader)? {
+        Some(buffer) => Ok(Some(buffer.trim_end().to_string())),
+        None => Ok(None), // End of file
+    }
+}
 
-        if bytes_read == 0 {
-            return Ok(None); // End of file
-        }
+/*
+ * Reads up to and including the next newline,
+ * decoding it with the reader's policy
+*/
+fn read_line_raw(file_reader: &mut FileReader) -> io::Result<Option<String>> {
+    let reader = match file_reader.reader.as_mut() {
+        Some(r) => r,
+        None => return Ok(None),
+    };
 
-        Ok(Some(buffer.trim_end().to_string()))
-    } else {
-        Ok(None)
+    let bytes_read = reader.read_until(b'\n', &mut file_reader.pending)?;
+    if bytes_read == 0 && file_reader.pending.is_empty() {
+        return Ok(None);
     }
+
+    let mut buffer = String::new();
+    file_reader.decode_pending(&mut buffer, true)?;
+    Ok(Some(buffer))
 }
 
 fn utf8_char_length(byte: u8) -> usize {
@@ -112,3 +443,156 @@ fn utf8_char_length(byte: u8) -> usize {
         1 // Invalid char
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /*
+     * Source that hands out one byte per read, like a slow pipe
+    */
+    struct TrickleReader {
+        data: Vec<u8>,
+        position: usize,
+    }
+
+    impl Read for TrickleReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.position >= self.data.len() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.data[self.position];
+            self.position += 1;
+            Ok(1)
+        }
+    }
+
+    fn trickle(data: Vec<u8>) -> TrickleReader {
+        TrickleReader { data, position: 0 }
+    }
+
+    fn read_all(file_reader: &mut FileReader, size: usize) -> io::Result<String> {
+        let mut text = String::new();
+        let mut text_buff = String::new();
+        while read_buff(file_reader, &mut text_buff, size)? > 0 {
+            text.push_str(&text_buff);
+        }
+        Ok(text)
+    }
+
+    fn read_source<R: Read + 'static>(source: R) -> String {
+        let mut file_reader = FileReader::new("test");
+        open_reader(&mut file_reader, source).unwrap();
+        read_all(&mut file_reader, 7).unwrap()
+    }
+
+    #[test]
+    fn detects_compression_from_a_trickling_source() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"ACGT\nTTGA\n").unwrap();
+        assert_eq!(read_source(trickle(encoder.finish().unwrap())), "ACGT\nTTGA\n");
+
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        encoder.write_all(b"ACGT\nTTGA\n").unwrap();
+        assert_eq!(read_source(trickle(encoder.finish().unwrap())), "ACGT\nTTGA\n");
+
+        let compressed = zstd::stream::encode_all(&b"ACGT\nTTGA\n"[..], 3).unwrap();
+        assert_eq!(read_source(trickle(compressed)), "ACGT\nTTGA\n");
+    }
+
+    #[test]
+    fn text_starting_like_bzip2_is_plain_text() {
+        assert_eq!(read_source(io::Cursor::new(b"BZh9 is not bzip2".to_vec())), "BZh9 is not bzip2");
+        assert_eq!(read_source(io::Cursor::new(b"BZh".to_vec())), "BZh");
+    }
+
+    #[test]
+    fn short_and_empty_inputs_are_plain_text() {
+        assert_eq!(read_source(trickle(b"ab".to_vec())), "ab");
+        assert_eq!(read_source(trickle(Vec::new())), "");
+    }
+
+    fn reader_with(data: &[u8], policy: InvalidUtf8Policy) -> FileReader {
+        let mut file_reader = FileReader::new("test");
+        file_reader.invalid_utf8 = policy;
+        open_reader(&mut file_reader, trickle(data.to_vec())).unwrap();
+        file_reader
+    }
+
+    fn read_chars(file_reader: &mut FileReader) -> io::Result<String> {
+        let mut text = String::new();
+        while let Some(symbol) = read_char(file_reader)? {
+            text.push(symbol);
+        }
+        Ok(text)
+    }
+
+    fn read_lines(file_reader: &mut FileReader) -> io::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        while let Some(line) = read_line(file_reader)? {
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+
+    const POLICIES: [InvalidUtf8Policy; 3] = [InvalidUtf8Policy::Error, InvalidUtf8Policy::Skip, InvalidUtf8Policy::Replace];
+
+    #[test]
+    fn characters_split_across_reads_are_carried_over() {
+        let text = "h\u{e9}llo \u{2713} \u{1f600}\nna\u{ef}ve";
+        for policy in POLICIES {
+            for size in [1, 2, 3, 5] {
+                let mut file_reader = reader_with(text.as_bytes(), policy);
+                assert_eq!(read_all(&mut file_reader, size).unwrap(), text);
+                assert_eq!(file_reader.get_invalid_sequences(), 0);
+            }
+            assert_eq!(read_chars(&mut reader_with(text.as_bytes(), policy)).unwrap(), text);
+            assert_eq!(read_lines(&mut reader_with(text.as_bytes(), policy)).unwrap(), vec!["h\u{e9}llo \u{2713} \u{1f600}", "na\u{ef}ve"]);
+        }
+    }
+
+    #[test]
+    fn invalid_bytes_follow_the_policy() {
+        let data = b"a\xe2\x9c\x93\xffb\n";
+
+        let mut file_reader = reader_with(data, InvalidUtf8Policy::Error);
+        let error = read_all(&mut file_reader, 2).unwrap_err();
+        assert!(error.to_string().contains("byte offset 4"));
+        assert!(read_chars(&mut reader_with(data, InvalidUtf8Policy::Error)).is_err());
+        assert!(read_lines(&mut reader_with(data, InvalidUtf8Policy::Error)).is_err());
+
+        let mut file_reader = reader_with(data, InvalidUtf8Policy::Skip);
+        assert_eq!(read_all(&mut file_reader, 2).unwrap(), "a\u{2713}b\n");
+        assert_eq!(file_reader.get_invalid_sequences(), 1);
+        assert_eq!(file_reader.get_first_invalid_offset(), Some(4));
+        assert_eq!(read_chars(&mut reader_with(data, InvalidUtf8Policy::Skip)).unwrap(), "a\u{2713}b\n");
+        assert_eq!(read_lines(&mut reader_with(data, InvalidUtf8Policy::Skip)).unwrap(), vec!["a\u{2713}b"]);
+
+        let mut file_reader = reader_with(data, InvalidUtf8Policy::Replace);
+        assert_eq!(read_all(&mut file_reader, 2).unwrap(), "a\u{2713}\u{fffd}b\n");
+        assert_eq!(read_chars(&mut reader_with(data, InvalidUtf8Policy::Replace)).unwrap(), "a\u{2713}\u{fffd}b\n");
+        assert_eq!(read_lines(&mut reader_with(data, InvalidUtf8Policy::Replace)).unwrap(), vec!["a\u{2713}\u{fffd}b"]);
+    }
+
+    #[test]
+    fn a_character_cut_by_the_end_of_input_is_one_invalid_sequence() {
+        let data = b"ab\xe2\x9c";
+
+        for size in [1, 3, 64] {
+            assert!(read_all(&mut reader_with(data, InvalidUtf8Policy::Error), size).is_err());
+            assert_eq!(read_all(&mut reader_with(data, InvalidUtf8Policy::Skip), size).unwrap(), "ab");
+
+            let mut file_reader = reader_with(data, InvalidUtf8Policy::Replace);
+            assert_eq!(read_all(&mut file_reader, size).unwrap(), "ab\u{fffd}");
+            assert_eq!(file_reader.get_invalid_sequences(), 1);
+            assert_eq!(file_reader.get_first_invalid_offset(), Some(2));
+        }
+
+        assert!(read_chars(&mut reader_with(data, InvalidUtf8Policy::Error)).is_err());
+        assert_eq!(read_chars(&mut reader_with(data, InvalidUtf8Policy::Skip)).unwrap(), "ab");
+        assert_eq!(read_chars(&mut reader_with(data, InvalidUtf8Policy::Replace)).unwrap(), "ab\u{fffd}");
+        assert!(read_lines(&mut reader_with(data, InvalidUtf8Policy::Error)).is_err());
+        assert_eq!(read_lines(&mut reader_with(data, InvalidUtf8Policy::Replace)).unwrap(), vec!["ab\u{fffd}"]);
+    }
+}
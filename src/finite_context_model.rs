@@ -1,8 +1,10 @@
-use std::collections::{HashMap, HashSet};
+use std::{cell::Cell, collections::{HashMap, HashSet, VecDeque}, io};
 use plotters::prelude::LogScalable;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
+use crate::model_saver_loader::{self, BinaryHeader, ByteReader, ModelDescription, PersistentModel, MODEL_TYPE_CHARS};
+
 /*
  * Defines the finite-context model structure,
  * storing model parameters and frequency counts
@@ -11,11 +13,14 @@ use serde::{Deserialize, Serialize};
 pub struct FiniteContextModel {
     k: usize,                                       // context length (Order of the Markov model)
     alpha: f64,                                     // smoothing factor to avoid zero probabilities
-    current_context: String,                     
+    #[serde(with = "context_string")]               // saved as a string, as it always was
+    current_context: VecDeque<char>,
     symbols: HashSet<char>,                           
     counts: HashMap<String, HashMap<char, usize>>,
         // The outer hashmap maps a context or a substring of length k to the inner hashmap
         // The inner hashmap counts the occurences of characters appearing after the context
+    #[serde(skip)]
+    alphabet_size: Option<usize>,                   // symbols the counts are smoothed over, the seen ones when None
 }
 
 impl FiniteContextModel {
@@ -24,9 +29,10 @@ impl FiniteContextModel {
         Self {
             k,
             alpha,
-            current_context: String::new(),
+            current_context: VecDeque::new(),
             symbols: HashSet::new(),
             counts: HashMap::new(),
+            alphabet_size: None,
         }
     }
 
@@ -38,19 +44,23 @@ impl FiniteContextModel {
     pub fn train_char(&mut self, current_char: char) {
         self.symbols.insert(current_char);
 
+        // k counts Unicode scalar values, not the bytes of the context
         if self.current_context.len() >= self.k {
             
-            let context = &self.current_context;
+            let context: String = self.current_context.iter().collect();
             
             // Insert the count into the HashMap
-            let entry = self.counts.entry(context.clone()).or_insert_with(HashMap::new);
+            let entry = self.counts.entry(context).or_default();
             *entry.entry(current_char).or_insert(0) += 1;
             
             // Slide the context window (remove the first char)
-            self.current_context.drain(..1);
+            if self.k == 0 {
+                return; // an order-0 model never keeps a context
+            }
+            self.current_context.pop_front();
         }
 
-        self.current_context.push(current_char);
+        self.current_context.push_back(current_char);
     }
 
     /*
@@ -64,7 +74,22 @@ impl FiniteContextModel {
         let symbol_count: f64 = symbol_counts.get(&symbol).copied().unwrap_or(0) as f64;
         let total_count: f64 = symbol_counts.values().sum::<usize>() as f64;
 
-        (symbol_count + self.alpha) / (total_count + self.alpha * self.symbols.len().as_f64())
+        (symbol_count + self.alpha) / (total_count + self.alpha * self.smoothing_alphabet_size().as_f64())
+    }
+
+    /*
+     * Smooths the probabilities over an alphabet of this size
+     * instead of the symbols seen in training, so that models
+     * which saw fewer symbols do not give them more probability.
+     * It is never taken as less than the symbols seen, and it
+     * is not saved with the model
+    */
+    pub fn set_alphabet_size(&mut self, alphabet_size: usize) {
+        self.alphabet_size = Some(alphabet_size);
+    }
+
+    fn smoothing_alphabet_size(&self) -> usize {
+        self.alphabet_size.map_or(self.symbols.len(), |alphabet_size| alphabet_size.max(self.symbols.len()))
     }
 
     /*
@@ -73,65 +98,382 @@ impl FiniteContextModel {
      * finite-context model
     */
     pub fn calculate_information_content(&self, text: &str) -> f64 {
+        self.information_content_of(text.chars())
+    }
+
+    /*
+     * Same as calculate_information_content, for any
+     * sequence of symbols. Only the last k symbols are
+     * kept, so the sequence is never collected
+    */
+    pub fn information_content_of<I: IntoIterator<Item = char>>(&self, symbols: I) -> f64 {
         let mut total_info = 0.0;
-        
-        for window in text.chars().collect::<Vec<_>>().windows(self.k + 1) {
-            if let Some((&next_char, context_chars)) = window.split_last() {
-                let context: String = context_chars.iter().collect();
-                let probability = self.compute_probability(&context, next_char);
-                total_info += -probability.log2();
-            }
-        }
-    
+        self.for_each_symbol_cost(symbols, |bit_cost| total_info += bit_cost);
         total_info
     }
-    
 
     /*
      * Samples a character based on stored probabilities
      * with frozen counts
     */
     pub fn sample_next_char(&self, context: &str) -> char {
+        self.sample_next_char_where(context, |_| true).unwrap_or(' ') // default fallback
+    }
+
+    /*
+     * Samples a character based on stored probabilities,
+     * restricted to the symbols accepted by `allowed`.
+     * Returns None if no accepted symbol follows the context
+    */
+    pub fn sample_next_char_where<F: Fn(char) -> bool>(&self, context: &str, allowed: F) -> Option<char> {
         let mut rng = rand::rng(); // Random number generator
-        let binding: HashMap<char, usize> = HashMap::new();
-        let symbol_counts = self.counts.get(context).unwrap_or(&binding);
+        let symbol_counts: Vec<(char, usize)> = match self.counts.get(context) {
+            Some(counts) => counts.iter()
+                .filter(|(&symbol, _)| allowed(symbol))
+                .map(|(&symbol, &count)| (symbol, count))
+                .collect(),
+            None => return None,
+        };
 
-        let total_count: f64 = symbol_counts.values().sum::<usize>() as f64;
+        let total_count: f64 = symbol_counts.iter().map(|(_, count)| count).sum::<usize>() as f64;
 
         if total_count == 0.0 {
-            return ' '; // default fallback
+            return None;
         }
 
         let mut cumulative_probability = 0.0;
         let threshold = rng.random::<f64>();
 
-        for (&symbol, &count) in symbol_counts {
+        for &(symbol, count) in &symbol_counts {
             cumulative_probability += (count as f64) / total_count;
             if threshold <= cumulative_probability {
-                return symbol;
+                return Some(symbol);
             }
         }
 
-        ' ' // Fallback
+        symbol_counts.last().map(|&(symbol, _)| symbol) // Rounding fallback
+    }
+
+    /*
+     * Computes the distribution of the character following
+     * the context from the frozen counts. The distribution
+     * is empty if the context was never seen
+    */
+    pub fn next_char_distribution(&self, context: &str) -> HashMap<char, f64> {
+        let binding: HashMap<char, usize> = HashMap::new();
+        let symbol_counts = self.counts.get(context).unwrap_or(&binding);
+        let total_count: f64 = symbol_counts.values().sum::<usize>() as f64;
+
+        symbol_counts.iter()
+            .map(|(&symbol, &count)| (symbol, count as f64 / total_count))
+            .collect()
+    }
+
+    /*
+     * Checks whether the given context was
+     * seen at least once during training
+    */
+    pub fn has_context(&self, context: &str) -> bool {
+        self.counts.get(context).is_some_and(|symbol_counts| !symbol_counts.is_empty())
+    }
+
+    pub fn get_symbols(&self) -> &HashSet<char> {
+        &self.symbols
     }
 
     pub fn get_k(&self) -> usize {
         self.k
     }
 
+    pub fn get_alpha(&self) -> f64 {
+        self.alpha
+    }
+
     pub fn complexity_profile(&self, text: &str) -> Vec<f64> {
+        self.complexity_profile_of(text.chars())
+    }
+
+    pub fn complexity_profile_of<I: IntoIterator<Item = char>>(&self, symbols: I) -> Vec<f64> {
         let mut profile: Vec<f64> = Vec::new();
-        let chars: Vec<char> = text.chars().collect();
-
-        for window in chars.windows(self.k + 1) {
-            if let Some((&next_char, context_chars)) = window.split_last() {
-                let context: String = context_chars.iter().collect();
-                let probability = self.compute_probability(&context, next_char);
-                let bit_cost = -probability.log2(); 
-                profile.push(bit_cost);
+        self.for_each_symbol_cost(symbols, |bit_cost| profile.push(bit_cost));
+        profile
+    }
+
+    /*
+     * NRC of windows of `window` symbols starting every `step`
+     * symbols, as (start, end, nrc) with end excluded, with the
+     * bits of each window divided by `bits_per_symbol` times
+     * its symbols. The first k symbols have no context, so they
+     * are left out of the NRC of the windows that cover them.
+     * A sequence shorter than the window gives a single window,
+     * and one of k symbols or fewer, with no symbol scored, none
+    */
+    pub fn window_nrc_of<I: IntoIterator<Item = char>>(
+        &self,
+        symbols: I,
+        window: usize,
+        step: usize,
+        bits_per_symbol: f64,
+    ) -> Vec<(usize, usize, f64)> {
+        let mut windows: Vec<(usize, usize, f64)> = Vec::new();
+        let mut costs: VecDeque<(usize, f64)> = VecDeque::with_capacity(window);
+        let mut position = self.k;                  // position of the next scored symbol
+        let symbol_count = Cell::new(0);
+
+        let counted = symbols.into_iter().inspect(|_| symbol_count.set(symbol_count.get() + 1));
+        self.for_each_symbol_cost(counted, |bit_cost| {
+            costs.push_back((position, bit_cost));
+            position += 1;
+
+            if position >= window && (position - window).is_multiple_of(step.max(1)) {
+                let start = position - window;
+                while costs.front().is_some_and(|&(scored, _)| scored < start) {
+                    costs.pop_front();
+                }
+                windows.push((start, position, window_nrc(&costs, bits_per_symbol)));
             }
+        });
+
+        if windows.is_empty() && !costs.is_empty() {
+            windows.push((0, symbol_count.get(), window_nrc(&costs, bits_per_symbol)));
         }
+        windows
+    }
 
-        profile
+    /*
+     * Calls `cost` with the bits of every symbol that
+     * follows a full context of k symbols
+    */
+    fn for_each_symbol_cost<I: IntoIterator<Item = char>, F: FnMut(f64)>(&self, symbols: I, mut cost: F) {
+        let mut context = String::new();
+        let mut context_length = 0;
+
+        for symbol in symbols {
+            if context_length == self.k {
+                let probability = self.compute_probability(&context, symbol);
+                cost(-probability.log2());
+            }
+
+            context.push(symbol);
+            context_length += 1;
+            if context_length > self.k {
+                context.remove(0);
+                context_length -= 1;
+            }
+        }
     }
-}
\ No newline at end of file
+}
+
+/*
+ * Mean bits of the scored symbols of a window over the most
+ * bits of a symbol. Windows always have a scored symbol
+*/
+fn window_nrc(costs: &VecDeque<(usize, f64)>, bits_per_symbol: f64) -> f64 {
+    costs.iter().map(|&(_, bit_cost)| bit_cost).sum::<f64>() / (bits_per_symbol * costs.len() as f64)
+}
+
+/*
+ * Binary layout after the header:
+ *   alphabet size | code points (sorted)
+ *   body: current context | number of contexts, then for each context:
+ *     context length | alphabet indices | number of symbols | (alphabet index, count) pairs
+*/
+impl PersistentModel for FiniteContextModel {
+    fn to_binary(&self, compress: bool) -> io::Result<Vec<u8>> {
+        let mut alphabet: Vec<char> = self.symbols.iter().copied().collect();
+        alphabet.sort();
+        let index: HashMap<char, u64> = alphabet.iter().enumerate().map(|(i, &c)| (c, i as u64)).collect();
+
+        let mut data = Vec::new();
+        model_saver_loader::write_header(&mut data, MODEL_TYPE_CHARS, &BinaryHeader { k: self.k, alpha: self.alpha, compressed: compress });
+        model_saver_loader::write_varint(&mut data, alphabet.len() as u64);
+        for &symbol in &alphabet {
+            model_saver_loader::write_varint(&mut data, symbol as u64);
+        }
+
+        let mut body = Vec::new();
+        write_symbols(&mut body, &self.current_context.iter().collect::<String>(), &index)?;
+
+        // Contexts are sorted so the same model always gives the same file
+        let mut contexts: Vec<(&String, &HashMap<char, usize>)> = self.counts.iter().collect();
+        contexts.sort_by(|a, b| a.0.cmp(b.0));
+        model_saver_loader::write_varint(&mut body, contexts.len() as u64);
+
+        for (context, symbol_counts) in contexts {
+            write_symbols(&mut body, context, &index)?;
+            model_saver_loader::write_varint(&mut body, symbol_counts.len() as u64);
+            for (symbol, &count) in symbol_counts {
+                model_saver_loader::write_varint(&mut body, symbol_index(&index, *symbol)?);
+                model_saver_loader::write_varint(&mut body, count as u64);
+            }
+        }
+
+        model_saver_loader::append_body(&mut data, body, compress)?;
+        Ok(data)
+    }
+
+    fn describe(&self) -> ModelDescription {
+        ModelDescription {
+            model_type: "chars",
+            k: self.k,
+            alpha: self.alpha,
+            smoothing: "additive",
+            symbol_count: self.symbols.len(),
+        }
+    }
+
+    fn from_binary(data: &[u8]) -> io::Result<Self> {
+        let (header, mut reader) = model_saver_loader::read_header(data, MODEL_TYPE_CHARS)?;
+
+        let alphabet_size = reader.read_count()?;
+        let mut alphabet = Vec::with_capacity(alphabet_size);
+        for _ in 0..alphabet_size {
+            let code_point = u32::try_from(reader.read_varint()?).ok().and_then(char::from_u32);
+            alphabet.push(code_point.ok_or_else(|| model_saver_loader::invalid_data("Invalid symbol"))?);
+        }
+
+        let body_data = model_saver_loader::read_body(&reader, header.compressed)?;
+        let mut body = ByteReader::new(&body_data);
+
+        let current_context = read_symbols(&mut body, &alphabet)?.chars().collect();
+
+        let context_count = body.read_count()?;
+        let mut counts: HashMap<String, HashMap<char, usize>> = HashMap::with_capacity(context_count);
+        for _ in 0..context_count {
+            let context = read_symbols(&mut body, &alphabet)?;
+            let symbol_count = body.read_count()?;
+            let mut symbol_counts = HashMap::with_capacity(symbol_count);
+            for _ in 0..symbol_count {
+                let symbol = alphabet[body.read_index(alphabet.len())?];
+                symbol_counts.insert(symbol, body.read_varint()? as usize);
+            }
+            counts.insert(context, symbol_counts);
+        }
+
+        Ok(Self {
+            k: header.k,
+            alpha: header.alpha,
+            current_context,
+            symbols: alphabet.into_iter().collect(),
+            counts,
+            alphabet_size: None,
+        })
+    }
+}
+
+fn write_symbols(data: &mut Vec<u8>, text: &str, index: &HashMap<char, u64>) -> io::Result<()> {
+    model_saver_loader::write_varint(data, text.chars().count() as u64);
+    for symbol in text.chars() {
+        model_saver_loader::write_varint(data, symbol_index(index, symbol)?);
+    }
+    Ok(())
+}
+
+fn symbol_index(index: &HashMap<char, u64>, symbol: char) -> io::Result<u64> {
+    index.get(&symbol).copied().ok_or_else(|| model_saver_loader::invalid_data("Symbol missing from the alphabet"))
+}
+
+fn read_symbols(reader: &mut ByteReader, alphabet: &[char]) -> io::Result<String> {
+    let length = reader.read_count()?;
+    let mut text = String::with_capacity(length);
+    for _ in 0..length {
+        text.push(alphabet[reader.read_index(alphabet.len())?]);
+    }
+    Ok(text)
+}
+
+/*
+ * Serializes the context as a string, so models saved
+ * as JSON or BSON keep the same layout
+*/
+mod context_string {
+    use std::collections::VecDeque;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(context: &VecDeque<char>, serializer: S) -> Result<S::Ok, S::Error> {
+        context.iter().collect::<String>().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<VecDeque<char>, D::Error> {
+        Ok(String::deserialize(deserializer)?.chars().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bits of each symbol of the text after the first k, from its k preceding symbols
+    fn symbol_costs(model: &FiniteContextModel, text: &str) -> Vec<f64> {
+        let symbols: Vec<char> = text.chars().collect();
+        (model.get_k()..symbols.len())
+            .map(|position| {
+                let context: String = symbols[position - model.get_k()..position].iter().collect();
+                -model.compute_probability(&context, symbols[position]).log2()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn windows_slide_by_the_step_over_the_scored_symbols() {
+        let mut model = FiniteContextModel::new(1, 0.1);
+        "ACGTACGA".chars().for_each(|symbol| model.train_char(symbol));
+        let text = "ACGTTCGA";
+        let costs = symbol_costs(&model, text);
+
+        let windows = model.window_nrc_of(text.chars(), 4, 2, 2.0);
+        let bounds: Vec<(usize, usize)> = windows.iter().map(|&(start, end, _)| (start, end)).collect();
+        assert_eq!(bounds, [(0, 4), (2, 6), (4, 8)]);
+
+        // The first symbol has no context, so the first window only scores 3 symbols
+        assert!((windows[0].2 - costs[0..3].iter().sum::<f64>() / (2.0 * 3.0)).abs() < 1e-12);
+        assert!((windows[1].2 - costs[1..5].iter().sum::<f64>() / (2.0 * 4.0)).abs() < 1e-12);
+        assert!((windows[2].2 - costs[3..7].iter().sum::<f64>() / (2.0 * 4.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn short_sequences_give_one_window_or_none() {
+        let mut model = FiniteContextModel::new(2, 0.1);
+        "ACGTACGT".chars().for_each(|symbol| model.train_char(symbol));
+
+        let windows = model.window_nrc_of("ACGT".chars(), 10, 5, 2.0);
+        assert_eq!(windows.len(), 1);
+        assert_eq!((windows[0].0, windows[0].1), (0, 4));
+        assert!((windows[0].2 - symbol_costs(&model, "ACGT").iter().sum::<f64>() / (2.0 * 2.0)).abs() < 1e-12);
+
+        // No symbol follows a full context, so there is nothing to score
+        assert!(model.window_nrc_of("AC".chars(), 10, 5, 2.0).is_empty());
+        assert!(model.window_nrc_of("".chars(), 10, 5, 2.0).is_empty());
+    }
+
+    #[test]
+    fn probabilities_are_smoothed_over_the_given_alphabet() {
+        let mut model = FiniteContextModel::new(1, 0.5);
+        "ACACAC".chars().for_each(|symbol| model.train_char(symbol));
+        model.set_alphabet_size(4);
+
+        let total: f64 = "ACGT".chars().map(|symbol| model.compute_probability("A", symbol)).sum();
+        assert!((total - 1.0).abs() < 1e-12);
+        assert!((model.compute_probability("A", 'G') - 0.5 / 5.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn contexts_count_scalar_values() {
+        let mut model = FiniteContextModel::new(2, 0.0);
+        "ñaña".chars().for_each(|symbol| model.train_char(symbol));
+        assert!(model.has_context("ña"));
+        assert!(model.has_context("añ"));
+        assert_eq!(model.compute_probability("ña", 'ñ'), 1.0);
+    }
+
+    #[test]
+    fn json_keeps_the_context_as_a_string() {
+        let mut model = FiniteContextModel::new(3, 0.1);
+        "abcd".chars().for_each(|symbol| model.train_char(symbol));
+        let json = serde_json::to_value(&model).unwrap();
+        assert_eq!(json["current_context"], "bcd");
+
+        let loaded: FiniteContextModel = serde_json::from_value(json).unwrap();
+        assert_eq!(loaded.current_context, VecDeque::from(['b', 'c', 'd']));
+    }
+}
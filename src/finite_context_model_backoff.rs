@@ -0,0 +1,215 @@
+use std::{collections::HashMap, io};
+use serde::{Deserialize, Serialize};
+
+use crate::{finite_context_model::FiniteContextModel, model_saver_loader::{self, BinaryHeader, ModelDescription, PersistentModel, MODEL_TYPE_BACKOFF}};
+
+/*
+ * Defines a chain of finite-context models of
+ * orders 0..=k trained from the same text, used
+ * to back off to shorter contexts when the
+ * current context was never seen
+*/
+#[derive(Serialize,Deserialize)]
+pub struct FiniteContextModelBackoff {
+    k: usize,                                       // highest context length of the chain
+    models: Vec<FiniteContextModel>,                // models[i] is the model of order i
+}
+
+impl FiniteContextModelBackoff {
+
+    pub fn new (k: usize, alpha: f64) -> Self {
+        Self {
+            k,
+            models: (0..=k).map(|order| FiniteContextModel::new(order, alpha)).collect(),
+        }
+    }
+
+    /*
+     * Trains every model of the chain with
+     * the same symbol, so the whole chain is
+     * built in a single pass over the text
+    */
+    pub fn train_char(&mut self, current_char: char) {
+        for model in self.models.iter_mut() {
+            model.train_char(current_char);
+        }
+    }
+
+    /*
+     * Finds the highest order model that has
+     * counts for the suffix of the given context,
+     * returning it along with that suffix
+    */
+    pub fn longest_known_context(&self, context: &str) -> (&FiniteContextModel, String) {
+        let chars: Vec<char> = context.chars().collect();
+        let max_order = self.k.min(chars.len());
+
+        for order in (1..=max_order).rev() {
+            let suffix: String = chars[chars.len() - order..].iter().collect();
+            if self.models[order].has_context(&suffix) {
+                return (&self.models[order], suffix);
+            }
+        }
+
+        (&self.models[0], String::new())
+    }
+
+    /*
+     * Samples a character from the longest
+     * context that has counts
+    */
+    pub fn sample_next_char(&self, context: &str) -> char {
+        let (model, suffix) = self.longest_known_context(context);
+        model.sample_next_char(&suffix)
+    }
+
+    /*
+     * Samples a character accepted by `allowed` from the
+     * longest context that has counts for such a character.
+     * Returns None if not even the order-0 model has one
+    */
+    pub fn sample_next_char_where<F: Fn(char) -> bool>(&self, context: &str, allowed: F) -> Option<char> {
+        let chars: Vec<char> = context.chars().collect();
+        let max_order = self.k.min(chars.len());
+
+        for order in (0..=max_order).rev() {
+            let suffix: String = chars[chars.len() - order..].iter().collect();
+            if let Some(symbol) = self.models[order].sample_next_char_where(&suffix, &allowed) {
+                return Some(symbol);
+            }
+        }
+
+        None
+    }
+
+    /*
+     * Distribution of the next character given
+     * by the longest context that has counts
+    */
+    pub fn next_char_distribution(&self, context: &str) -> HashMap<char, f64> {
+        let (model, suffix) = self.longest_known_context(context);
+        model.next_char_distribution(&suffix)
+    }
+
+    pub fn get_model(&self, order: usize) -> Option<&FiniteContextModel> {
+        self.models.get(order)
+    }
+
+    pub fn get_k(&self) -> usize {
+        self.k
+    }
+}
+
+/*
+ * Binary layout after the header (no alphabet):
+ *   body: for each order 0..=k, length | binary model of that order
+*/
+impl PersistentModel for FiniteContextModelBackoff {
+    fn to_binary(&self, compress: bool) -> io::Result<Vec<u8>> {
+        let alpha = self.models[0].get_alpha();
+
+        let mut data = Vec::new();
+        model_saver_loader::write_header(&mut data, MODEL_TYPE_BACKOFF, &BinaryHeader { k: self.k, alpha, compressed: compress });
+
+        let mut body = Vec::new();
+        for model in &self.models {
+            let model_data = model.to_binary(false)?;
+            model_saver_loader::write_varint(&mut body, model_data.len() as u64);
+            body.extend_from_slice(&model_data);
+        }
+
+        model_saver_loader::append_body(&mut data, body, compress)?;
+        Ok(data)
+    }
+
+    fn describe(&self) -> ModelDescription {
+        ModelDescription {
+            model_type: "backoff",
+            k: self.k,
+            alpha: self.models[0].get_alpha(),
+            smoothing: "additive with backoff",
+            symbol_count: self.models[0].get_symbols().len(),
+        }
+    }
+
+    fn from_binary(data: &[u8]) -> io::Result<Self> {
+        let (header, reader) = model_saver_loader::read_header(data, MODEL_TYPE_BACKOFF)?;
+
+        let body_data = model_saver_loader::read_body(&reader, header.compressed)?;
+        let mut body = model_saver_loader::ByteReader::new(&body_data);
+
+        // Each order takes at least one byte of the body
+        let order_count = header.k.checked_add(1)
+            .filter(|&order_count| order_count <= body.remaining().len())
+            .ok_or_else(|| model_saver_loader::invalid_data("Invalid k for the backoff models"))?;
+        let mut models = Vec::with_capacity(order_count);
+        for order in 0..order_count {
+            let length = body.read_varint()? as usize;
+            let model = FiniteContextModel::from_binary(body.read_bytes(length)?)?;
+            if model.get_k() != order {
+                return Err(model_saver_loader::invalid_data("Backoff models are out of order"));
+            }
+            models.push(model);
+        }
+
+        Ok(Self { k: header.k, models })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trained(text: &str, k: usize) -> FiniteContextModelBackoff {
+        let mut model = FiniteContextModelBackoff::new(k, 0.01);
+        text.chars().for_each(|symbol| model.train_char(symbol));
+        model
+    }
+
+    #[test]
+    fn uses_the_full_context_when_it_was_seen() {
+        let model = trained("abcabd", 2);
+        let (order_model, suffix) = model.longest_known_context("xab");
+        assert_eq!(order_model.get_k(), 2);
+        assert_eq!(suffix, "ab");
+    }
+
+    #[test]
+    fn backs_off_to_the_longest_seen_suffix() {
+        let model = trained("abcabd", 2);
+        let (order_model, suffix) = model.longest_known_context("db");
+        assert_eq!(order_model.get_k(), 1);
+        assert_eq!(suffix, "b");
+
+        let (order_model, suffix) = model.longest_known_context("zz");
+        assert_eq!(order_model.get_k(), 0);
+        assert_eq!(suffix, "");
+    }
+
+    #[test]
+    fn short_contexts_start_at_their_own_length() {
+        let model = trained("abcabd", 3);
+        let (order_model, suffix) = model.longest_known_context("a");
+        assert_eq!(order_model.get_k(), 1);
+        assert_eq!(suffix, "a");
+    }
+
+    #[test]
+    fn distribution_follows_the_backed_off_context() {
+        let model = trained("ababababx", 2);
+        let distribution = model.next_char_distribution("zb");
+        assert_eq!(distribution.len(), 2);
+        assert!((distribution[&'a'] - 0.75).abs() < 1e-9);
+        assert!((distribution[&'x'] - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn constrained_sampling_backs_off_past_disallowed_symbols() {
+        // After "a" only 'b' was seen, so 'c' can only come from order 0
+        let model = trained("ababc", 1);
+        for _ in 0..20 {
+            assert_eq!(model.sample_next_char_where("a", |symbol| symbol == 'c'), Some('c'));
+        }
+        assert_eq!(model.sample_next_char_where("a", |symbol| symbol == 'z'), None);
+    }
+}
@@ -1,8 +1,10 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::{collections::{HashMap, HashSet, VecDeque}, io};
 use plotters::prelude::LogScalable;
 use serde::{Deserialize, Serialize};
 use opencv::core::{Mat, MatTraitConst};
 
+use crate::model_saver_loader::{self, BinaryHeader, ByteReader, ModelDescription, PersistentModel, MODEL_TYPE_IMAGE};
+
 /*
  * Defines the finite-context model structure,
  * storing model parameters and frequency counts
@@ -12,6 +14,7 @@ pub struct FiniteContextModelImage {                                   // contex
     alpha: f64,                                     // smoothing factor to avoid zero probabilities
     current_context: VecDeque<u8>,                     
     symbols: HashSet<u8>,                           
+    #[serde(with = "string_keys")]                  // JSON and BSON only allow string keys
     counts: HashMap<Vec<u8>, HashMap<u8, i32>>,
         // The outer hashmap maps a context or a substring of length k to the inner hashmap
         // The inner hashmap counts the occurences of characters appearing after the context
@@ -112,4 +115,131 @@ impl FiniteContextModelImage {
         }
         total_info
     }
-}
\ No newline at end of file
+}
+
+/*
+ * Binary layout after the header (k is 0, as it is
+ * given when training and contexts store their own length):
+ *   alphabet size | pixel values
+ *   body: current context | number of contexts, then for each context:
+ *     context length | pixel values | number of pixels | (pixel value, count) pairs
+*/
+impl PersistentModel for FiniteContextModelImage {
+    fn to_binary(&self, compress: bool) -> io::Result<Vec<u8>> {
+        let mut alphabet: Vec<u8> = self.symbols.iter().copied().collect();
+        alphabet.sort();
+
+        let mut data = Vec::new();
+        model_saver_loader::write_header(&mut data, MODEL_TYPE_IMAGE, &BinaryHeader { k: 0, alpha: self.alpha, compressed: compress });
+        model_saver_loader::write_varint(&mut data, alphabet.len() as u64);
+        data.extend_from_slice(&alphabet);
+
+        let mut body = Vec::new();
+        model_saver_loader::write_varint(&mut body, self.current_context.len() as u64);
+        body.extend(self.current_context.iter());
+
+        // Contexts are sorted so the same model always gives the same file
+        let mut contexts: Vec<(&Vec<u8>, &HashMap<u8, i32>)> = self.counts.iter().collect();
+        contexts.sort_by(|a, b| a.0.cmp(b.0));
+        model_saver_loader::write_varint(&mut body, contexts.len() as u64);
+
+        for (context, pixel_counts) in contexts {
+            model_saver_loader::write_varint(&mut body, context.len() as u64);
+            body.extend_from_slice(context);
+            model_saver_loader::write_varint(&mut body, pixel_counts.len() as u64);
+            for (&pixel, &count) in pixel_counts {
+                body.push(pixel);
+                model_saver_loader::write_varint(&mut body, count.max(0) as u64);
+            }
+        }
+
+        model_saver_loader::append_body(&mut data, body, compress)?;
+        Ok(data)
+    }
+
+    fn describe(&self) -> ModelDescription {
+        ModelDescription {
+            model_type: "image",
+            k: 0,
+            alpha: self.alpha,
+            smoothing: "additive",
+            symbol_count: self.symbols.len(),
+        }
+    }
+
+    fn from_binary(data: &[u8]) -> io::Result<Self> {
+        let (header, mut reader) = model_saver_loader::read_header(data, MODEL_TYPE_IMAGE)?;
+
+        let alphabet_size = reader.read_varint()? as usize;
+        let symbols: HashSet<u8> = reader.read_bytes(alphabet_size)?.iter().copied().collect();
+
+        let body_data = model_saver_loader::read_body(&reader, header.compressed)?;
+        let mut body = ByteReader::new(&body_data);
+
+        let context_length = body.read_varint()? as usize;
+        let current_context: VecDeque<u8> = body.read_bytes(context_length)?.iter().copied().collect();
+
+        let context_count = body.read_count()?;
+        let mut counts: HashMap<Vec<u8>, HashMap<u8, i32>> = HashMap::with_capacity(context_count);
+        for _ in 0..context_count {
+            let context_length = body.read_varint()? as usize;
+            let context = body.read_bytes(context_length)?.to_vec();
+            let pixel_count = body.read_count()?;
+            let mut pixel_counts = HashMap::with_capacity(pixel_count);
+            for _ in 0..pixel_count {
+                let pixel = body.read_u8()?;
+                let count = i32::try_from(body.read_varint()?).map_err(model_saver_loader::invalid_data)?;
+                pixel_counts.insert(pixel, count);
+            }
+            counts.insert(context, pixel_counts);
+        }
+
+        Ok(Self {
+            alpha: header.alpha,
+            current_context,
+            symbols,
+            counts,
+        })
+    }
+}
+
+/*
+ * Serializes the counts with contexts as comma separated
+ * pixel values and pixels as decimal strings
+*/
+mod string_keys {
+    use std::collections::HashMap;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    // Pixel counts by context
+    type Counts = HashMap<Vec<u8>, HashMap<u8, i32>>;
+
+    pub fn serialize<S: Serializer>(counts: &Counts, serializer: S) -> Result<S::Ok, S::Error> {
+        let string_counts: HashMap<String, HashMap<String, i32>> = counts.iter()
+            .map(|(context, pixel_counts)| {
+                let context = context.iter().map(|pixel| pixel.to_string()).collect::<Vec<_>>().join(",");
+                let pixel_counts = pixel_counts.iter().map(|(pixel, &count)| (pixel.to_string(), count)).collect();
+                (context, pixel_counts)
+            })
+            .collect();
+        string_counts.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Counts, D::Error> {
+        let string_counts = HashMap::<String, HashMap<String, i32>>::deserialize(deserializer)?;
+        string_counts.into_iter()
+            .map(|(context, pixel_counts)| {
+                let context = if context.is_empty() {
+                    Vec::new()
+                } else {
+                    context.split(',').map(|pixel| pixel.parse::<u8>()).collect::<Result<Vec<u8>, _>>().map_err(D::Error::custom)?
+                };
+                let pixel_counts = pixel_counts.into_iter()
+                    .map(|(pixel, count)| pixel.parse::<u8>().map(|pixel| (pixel, count)))
+                    .collect::<Result<HashMap<u8, i32>, _>>()
+                    .map_err(D::Error::custom)?;
+                Ok((context, pixel_counts))
+            })
+            .collect()
+    }
+}
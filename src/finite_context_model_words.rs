@@ -1,7 +1,9 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
+use crate::model_saver_loader::{self, BinaryHeader, ByteReader, ModelDescription, PersistentModel, MODEL_TYPE_WORDS};
+
 /*
  * Defines the finite-context model structure,
  * storing model parameters and frequency counts
@@ -43,7 +45,7 @@ impl FiniteContextModelWords {
             let context = self.current_context.join(" ");
             
             // Insert the count into the HashMap
-            let entry = self.counts.entry(context).or_insert_with(HashMap::new);
+            let entry = self.counts.entry(context).or_default();
             *entry.entry(current_word.clone()).or_insert(0) += 1;
             
             // Slide the context window (remove the first char)
@@ -66,7 +68,7 @@ impl FiniteContextModelWords {
         let symbol_count: f64 = *symbol_counts.get(word).unwrap_or(&0) as f64;
         let total_count: f64 = symbol_counts.values().sum::<usize>() as f64;
 
-        (symbol_count + self.alpha) / (total_count + self.alpha * 256 as f64)
+        (symbol_count + self.alpha) / (total_count + self.alpha * 256.0)
     }
 
     /*
@@ -117,10 +119,137 @@ impl FiniteContextModelWords {
         }
 
         let index = rng.random_range(0..self.symbols.len());
-        return self.symbols[index].clone();
+        self.symbols[index].clone()
+    }
+
+    /*
+     * Computes the distribution of the word following
+     * the context from the frozen counts. The distribution
+     * is empty if the context was never seen
+    */
+    pub fn next_word_distribution(&self, context: &str) -> HashMap<String, f64> {
+        let binding: HashMap<String, usize> = HashMap::new();
+        let symbol_counts = self.counts.get(context).unwrap_or(&binding);
+        let total_count: f64 = symbol_counts.values().sum::<usize>() as f64;
+
+        symbol_counts.iter()
+            .map(|(symbol, &count)| (symbol.clone(), count as f64 / total_count))
+            .collect()
+    }
+
+    pub fn get_symbols(&self) -> &Vec<String> {
+        &self.symbols
     }
 
     pub fn get_k(&self) -> usize {
         self.k
     }
-}
\ No newline at end of file
+}
+
+/*
+ * Binary layout after the header:
+ *   vocabulary size | words (in training order)
+ *   body: current context | number of contexts, then for each context:
+ *     number of words | vocabulary indices | number of words | (vocabulary index, count) pairs
+*/
+impl PersistentModel for FiniteContextModelWords {
+    fn to_binary(&self, compress: bool) -> io::Result<Vec<u8>> {
+        let index: HashMap<&str, u64> = self.symbols.iter().enumerate().map(|(i, word)| (word.as_str(), i as u64)).collect();
+
+        let mut data = Vec::new();
+        model_saver_loader::write_header(&mut data, MODEL_TYPE_WORDS, &BinaryHeader { k: self.k, alpha: self.alpha, compressed: compress });
+        model_saver_loader::write_varint(&mut data, self.symbols.len() as u64);
+        for word in &self.symbols {
+            model_saver_loader::write_string(&mut data, word);
+        }
+
+        let mut body = Vec::new();
+        write_words(&mut body, self.current_context.iter().map(String::as_str), &index)?;
+
+        // Contexts are sorted so the same model always gives the same file
+        let mut contexts: Vec<(&String, &HashMap<String, usize>)> = self.counts.iter().collect();
+        contexts.sort_by(|a, b| a.0.cmp(b.0));
+        model_saver_loader::write_varint(&mut body, contexts.len() as u64);
+
+        for (context, word_counts) in contexts {
+            // Contexts are the k previous words joined by spaces
+            write_words(&mut body, context.split(' ').filter(|word| !word.is_empty()), &index)?;
+            model_saver_loader::write_varint(&mut body, word_counts.len() as u64);
+            for (word, &count) in word_counts {
+                model_saver_loader::write_varint(&mut body, word_index(&index, word)?);
+                model_saver_loader::write_varint(&mut body, count as u64);
+            }
+        }
+
+        model_saver_loader::append_body(&mut data, body, compress)?;
+        Ok(data)
+    }
+
+    fn describe(&self) -> ModelDescription {
+        ModelDescription {
+            model_type: "words",
+            k: self.k,
+            alpha: self.alpha,
+            smoothing: "additive",
+            symbol_count: self.symbols.len(),
+        }
+    }
+
+    fn from_binary(data: &[u8]) -> io::Result<Self> {
+        let (header, mut reader) = model_saver_loader::read_header(data, MODEL_TYPE_WORDS)?;
+
+        let vocabulary_size = reader.read_count()?;
+        let mut symbols = Vec::with_capacity(vocabulary_size);
+        for _ in 0..vocabulary_size {
+            symbols.push(reader.read_string()?);
+        }
+
+        let body_data = model_saver_loader::read_body(&reader, header.compressed)?;
+        let mut body = ByteReader::new(&body_data);
+
+        let current_context = read_words(&mut body, &symbols)?;
+
+        let context_count = body.read_count()?;
+        let mut counts: HashMap<String, HashMap<String, usize>> = HashMap::with_capacity(context_count);
+        for _ in 0..context_count {
+            let context = read_words(&mut body, &symbols)?.join(" ");
+            let word_count = body.read_count()?;
+            let mut word_counts = HashMap::with_capacity(word_count);
+            for _ in 0..word_count {
+                let word = symbols[body.read_index(symbols.len())?].clone();
+                word_counts.insert(word, body.read_varint()? as usize);
+            }
+            counts.insert(context, word_counts);
+        }
+
+        Ok(Self {
+            k: header.k,
+            alpha: header.alpha,
+            current_context,
+            symbols,
+            counts,
+        })
+    }
+}
+
+fn write_words<'a, I: Iterator<Item = &'a str>>(data: &mut Vec<u8>, words: I, index: &HashMap<&str, u64>) -> io::Result<()> {
+    let indices = words.map(|word| word_index(index, word)).collect::<io::Result<Vec<u64>>>()?;
+    model_saver_loader::write_varint(data, indices.len() as u64);
+    for word in indices {
+        model_saver_loader::write_varint(data, word);
+    }
+    Ok(())
+}
+
+fn word_index(index: &HashMap<&str, u64>, word: &str) -> io::Result<u64> {
+    index.get(word).copied().ok_or_else(|| model_saver_loader::invalid_data("Word missing from the vocabulary"))
+}
+
+fn read_words(reader: &mut ByteReader, symbols: &[String]) -> io::Result<Vec<String>> {
+    let length = reader.read_count()?;
+    let mut words = Vec::with_capacity(length);
+    for _ in 0..length {
+        words.push(symbols[reader.read_index(symbols.len())?].clone());
+    }
+    Ok(words)
+}
@@ -1,13 +1,26 @@
 pub mod file_reader;
+pub mod symbol_encoder;
+pub mod sequence_reader;
 pub mod text_generator;
 pub mod chart_generator;
 pub mod model_saver_loader;
+pub mod model_index;
 pub mod data_base_processor;
+pub mod task_tracker;
+pub mod metagenome_generator;
 pub mod image_processor;
 pub mod audio_reader;
 pub mod ncd;
+pub mod distance_matrix;
+pub mod phylo_tree;
+pub mod taxonomy;
+pub mod local_nrc;
+pub mod read_classifier;
+pub mod significance;
+pub mod sketch;
 pub mod compressors;
 // finites_contexts_models
 pub mod finite_context_model;
+pub mod finite_context_model_backoff;
 pub mod finite_context_model_words;
 pub mod finite_context_model_image;
\ No newline at end of file
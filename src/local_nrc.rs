@@ -0,0 +1,200 @@
+use std::{fs::File, io::{self, BufWriter, Write}, str::FromStr};
+use serde::Serialize;
+
+/*
+ * Which sequence is cut into windows: the references, scored
+ * by the model of the sample, or the sample, scored by the
+ * model of each reference
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LocalDirection {
+    #[default]
+    Reference,
+    Sample,
+    Both,
+}
+
+impl FromStr for LocalDirection {
+    type Err = String;
+
+    fn from_str(direction: &str) -> Result<Self, Self::Err> {
+        match direction {
+            "reference" => Ok(LocalDirection::Reference),
+            "sample" => Ok(LocalDirection::Sample),
+            "both" => Ok(LocalDirection::Both),
+            _ => Err(format!("invalid direction \"{}\", expected reference, sample or both", direction)),
+        }
+    }
+}
+
+impl LocalDirection {
+
+    pub fn includes_references(&self) -> bool {
+        *self != LocalDirection::Sample
+    }
+
+    pub fn includes_sample(&self) -> bool {
+        *self != LocalDirection::Reference
+    }
+}
+
+/*
+ * NRC of one window of a sequence under the model trained
+ * on another one. Positions are in symbols, from 0, with
+ * the end excluded as in BED
+*/
+#[derive(Clone, Debug, Serialize)]
+pub struct LocalScore {
+    sequence: String,
+    start: usize,
+    end: usize,
+    model: String,
+    nrc: f64,
+}
+
+impl LocalScore {
+
+    pub fn new(sequence: &str, (start, end, nrc): (usize, usize, f64), model: &str) -> Self {
+        Self {
+            sequence: sequence.to_string(),
+            start,
+            end,
+            model: model.to_string(),
+            nrc,
+        }
+    }
+
+    pub fn get_sequence(&self) -> &str {
+        &self.sequence
+    }
+
+    pub fn get_start(&self) -> usize {
+        self.start
+    }
+
+    pub fn get_end(&self) -> usize {
+        self.end
+    }
+
+    pub fn get_model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn get_nrc(&self) -> f64 {
+        self.nrc
+    }
+
+    /*
+     * BED score, from 0 to 1000, higher for lower
+     * NRC so that genome browsers shade matches darker
+    */
+    pub fn bed_score(&self) -> u32 {
+        (1000.0 * (1.0 - self.nrc.clamp(0.0, 1.0))).round() as u32
+    }
+}
+
+/*
+ * Lowest scoring window of each pair of sequence and
+ * model, in the order the pairs first appear
+*/
+pub fn lowest_windows(scores: &[LocalScore]) -> Vec<&LocalScore> {
+    let mut lowest: Vec<&LocalScore> = Vec::new();
+    for score in scores {
+        match lowest.iter_mut().find(|best| best.sequence == score.sequence && best.model == score.model) {
+            Some(best) if score.nrc < best.nrc => *best = score,
+            Some(_) => {}
+            None => lowest.push(score),
+        }
+    }
+    lowest
+}
+
+/*
+ * Saves the windows as BED (.bed: sequence, start, end, model
+ * and BED score) or CSV (.csv, with the NRC of each window)
+*/
+pub fn export_local_scores(scores: &[LocalScore], path: &str) -> io::Result<()> {
+    let bed = if path.ends_with(".bed") {
+        true
+    } else if path.ends_with(".csv") {
+        false
+    } else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{}: local NRC output must end in .bed or .csv", path)));
+    };
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    if bed {
+        for score in scores {
+            writeln!(writer, "{}\t{}\t{}\t{}\t{}", score.sequence, score.start, score.end, score.model, score.bed_score())?;
+        }
+    } else {
+        writeln!(writer, "sequence,start,end,model,nrc")?;
+        for score in scores {
+            writeln!(writer, "{},{},{},{},{:.6}", csv_field(&score.sequence), score.start, score.end, csv_field(&score.model), score.nrc)?;
+        }
+    }
+    writer.flush()
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(sequence: &str, start: usize, model: &str, nrc: f64) -> LocalScore {
+        LocalScore::new(sequence, (start, start + 10, nrc), model)
+    }
+
+    #[test]
+    fn lower_nrc_gives_a_higher_bed_score() {
+        assert_eq!(score("r", 0, "m", 0.25).bed_score(), 750);
+        assert_eq!(score("r", 0, "m", 1.4).bed_score(), 0);
+        assert_eq!(score("r", 0, "m", 0.0).bed_score(), 1000);
+    }
+
+    #[test]
+    fn lowest_window_of_each_sequence_and_model() {
+        let scores = [
+            score("r1", 0, "m1", 0.8),
+            score("r2", 0, "m1", 0.7),
+            score("r1", 5, "m1", 0.6),
+            score("r1", 0, "m2", 0.9),
+            score("r1", 10, "m1", 0.6),
+        ];
+        let lowest: Vec<(&str, &str, usize)> = lowest_windows(&scores).iter()
+            .map(|score| (score.get_sequence(), score.get_model(), score.get_start()))
+            .collect();
+        assert_eq!(lowest, [("r1", "m1", 5), ("r2", "m1", 0), ("r1", "m2", 0)]);
+    }
+
+    #[test]
+    fn scores_are_saved_as_bed_or_csv() {
+        let scores = [score("r1", 0, "a, b", 0.5)];
+        let path = std::env::temp_dir().join(format!("tai_local_{}", std::process::id()));
+        let (bed, csv) = (format!("{}.bed", path.display()), format!("{}.csv", path.display()));
+
+        export_local_scores(&scores, &bed).unwrap();
+        assert_eq!(std::fs::read_to_string(&bed).unwrap(), "r1\t0\t10\ta, b\t500\n");
+        export_local_scores(&scores, &csv).unwrap();
+        assert_eq!(std::fs::read_to_string(&csv).unwrap(), "sequence,start,end,model,nrc\nr1,0,10,\"a, b\",0.500000\n");
+        std::fs::remove_file(&bed).unwrap();
+        std::fs::remove_file(&csv).unwrap();
+
+        assert!(export_local_scores(&scores, "scores.txt").is_err());
+    }
+
+    #[test]
+    fn directions_parse() {
+        assert_eq!("both".parse::<LocalDirection>(), Ok(LocalDirection::Both));
+        assert!("sample".parse::<LocalDirection>().unwrap().includes_sample());
+        assert!(!"sample".parse::<LocalDirection>().unwrap().includes_references());
+        assert!("left".parse::<LocalDirection>().is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use std::{fs::File, io::{self, BufWriter, Write}};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::Serialize;
+
+const BASES: [char; 4] = ['A', 'C', 'G', 'T'];
+
+/*
+ * Per-base probabilities of each kind of mutation
+*/
+#[derive(Serialize, Clone, Copy)]
+pub struct MutationRates {
+    pub substitution: f64,
+    pub insertion: f64,
+    pub deletion: f64,
+}
+
+/*
+ * A sequence that contributes reads to the sample.
+ * Sources without a sequence produce random DNA,
+ * like the background reads of generate_meta.sh
+*/
+pub struct SampleSource {
+    pub name: String,
+    pub sequence: Option<String>,
+    pub proportion: f64,
+}
+
+#[derive(Serialize)]
+pub struct SourceSummary {
+    name: String,
+    proportion: f64,
+    reads: usize,
+    bases: usize,
+}
+
+#[derive(Serialize)]
+pub struct ReadRecord {
+    read_id: usize,
+    source: String,
+    start: usize,
+    length: usize,
+    substitutions: usize,
+    insertions: usize,
+    deletions: usize,
+}
+
+/*
+ * Ground truth of a synthetic sample: where every read
+ * came from and which mutations were applied to it
+*/
+#[derive(Serialize)]
+pub struct GroundTruth {
+    seed: u64,
+    read_length: usize,
+    rates: MutationRates,
+    sources: Vec<SourceSummary>,
+    reads: Vec<ReadRecord>,
+}
+
+pub struct SyntheticSample {
+    pub reads: Vec<String>,
+    pub ground_truth: GroundTruth,
+}
+
+pub struct MetagenomeGenerator {
+    rng: StdRng,
+    seed: u64,
+    rates: MutationRates,
+    read_length: usize,
+}
+
+impl MetagenomeGenerator {
+
+    pub fn new(seed: u64, rates: MutationRates, read_length: usize) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            seed,
+            rates,
+            read_length,
+        }
+    }
+
+    /*
+     * Builds a sample of `read_count` reads drawn from the
+     * sources in the given proportions. Each read is a random
+     * window of its source, mutated with the configured rates,
+     * and the reads of all sources are shuffled together
+    */
+    pub fn generate(&mut self, sources: &[SampleSource], read_count: usize) -> SyntheticSample {
+        let reads_per_source = Self::allocate_reads(sources, read_count);
+
+        let mut origins: Vec<usize> = reads_per_source.iter()
+            .enumerate()
+            .flat_map(|(source_index, &count)| std::iter::repeat_n(source_index, count))
+            .collect();
+        origins.shuffle(&mut self.rng);
+
+        let total_proportion: f64 = sources.iter().map(|source| source.proportion).sum();
+        let mut summaries: Vec<SourceSummary> = sources.iter()
+            .zip(&reads_per_source)
+            .map(|(source, &reads)| SourceSummary {
+                name: source.name.clone(),
+                proportion: source.proportion / total_proportion,
+                reads,
+                bases: 0,
+            })
+            .collect();
+
+        let mut reads = Vec::with_capacity(read_count);
+        let mut records = Vec::with_capacity(read_count);
+
+        for (read_id, source_index) in origins.into_iter().enumerate() {
+            let source = &sources[source_index];
+            let (window, start) = match &source.sequence {
+                Some(sequence) => self.sample_window(sequence),
+                None => (self.random_dna(self.read_length), 0),
+            };
+
+            let (read, substitutions, insertions, deletions) = self.mutate(&window);
+            summaries[source_index].bases += read.len();
+
+            records.push(ReadRecord {
+                read_id,
+                source: source.name.clone(),
+                start,
+                length: window.chars().count(),
+                substitutions,
+                insertions,
+                deletions,
+            });
+            reads.push(read);
+        }
+
+        SyntheticSample {
+            reads,
+            ground_truth: GroundTruth {
+                seed: self.seed,
+                read_length: self.read_length,
+                rates: self.rates,
+                sources: summaries,
+                reads: records,
+            },
+        }
+    }
+
+    /*
+     * Applies deletions, substitutions and insertions base by
+     * base, returning the mutated sequence and how many of each
+     * mutation were applied
+    */
+    pub fn mutate(&mut self, sequence: &str) -> (String, usize, usize, usize) {
+        let mut mutated = String::with_capacity(sequence.len());
+        let (mut substitutions, mut insertions, mut deletions) = (0, 0, 0);
+
+        for base in sequence.chars() {
+            let r = self.rng.random::<f64>();
+            if r < self.rates.deletion {
+                deletions += 1;
+            } else if r < self.rates.deletion + self.rates.substitution {
+                // Soft-masked (lowercase) bases stay lowercase
+                let alternatives: Vec<char> = BASES.iter().copied().filter(|&b| b != base.to_ascii_uppercase()).collect();
+                let substitute = alternatives[self.rng.random_range(0..alternatives.len())];
+                mutated.push(if base.is_ascii_lowercase() { substitute.to_ascii_lowercase() } else { substitute });
+                substitutions += 1;
+            } else {
+                mutated.push(base);
+            }
+
+            if self.rng.random::<f64>() < self.rates.insertion {
+                mutated.push(BASES[self.rng.random_range(0..BASES.len())]);
+                insertions += 1;
+            }
+        }
+
+        (mutated, substitutions, insertions, deletions)
+    }
+
+    pub fn random_dna(&mut self, length: usize) -> String {
+        (0..length).map(|_| BASES[self.rng.random_range(0..BASES.len())]).collect()
+    }
+
+    /*
+     * Picks a random window of `read_length` bases from the
+     * sequence, or the whole sequence if it is shorter
+    */
+    fn sample_window(&mut self, sequence: &str) -> (String, usize) {
+        let bases: Vec<char> = sequence.chars().collect();
+        if bases.len() <= self.read_length {
+            return (sequence.to_string(), 0);
+        }
+
+        let start = self.rng.random_range(0..=bases.len() - self.read_length);
+        (bases[start..start + self.read_length].iter().collect(), start)
+    }
+
+    /*
+     * Splits the reads among the sources according to their
+     * proportions with the largest remainder method: each source
+     * gets the whole part of its share, and the reads left over
+     * go to the largest fractional parts, so exactly read_count
+     * reads are handed out. Proportions that are negative or not
+     * finite count as 0
+    */
+    fn allocate_reads(sources: &[SampleSource], read_count: usize) -> Vec<usize> {
+        let proportions: Vec<f64> = sources.iter()
+            .map(|source| if source.proportion.is_finite() && source.proportion > 0.0 { source.proportion } else { 0.0 })
+            .collect();
+        let total_proportion: f64 = proportions.iter().sum();
+        if sources.is_empty() || !total_proportion.is_finite() || total_proportion <= 0.0 {
+            return vec![0; sources.len()];
+        }
+
+        let shares: Vec<f64> = proportions.iter().map(|proportion| proportion / total_proportion * read_count as f64).collect();
+        let mut counts: Vec<usize> = shares.iter().map(|share| share.floor() as usize).collect();
+
+        // Ties go to the source given first
+        let mut by_remainder: Vec<usize> = (0..sources.len()).collect();
+        by_remainder.sort_by(|&a, &b| (shares[b] - shares[b].floor()).total_cmp(&(shares[a] - shares[a].floor())).then(a.cmp(&b)));
+
+        let leftover = read_count.saturating_sub(counts.iter().sum());
+        for &source_index in by_remainder.iter().take(leftover) {
+            counts[source_index] += 1;
+        }
+
+        counts
+    }
+}
+
+impl SyntheticSample {
+
+    /*
+     * Writes one read per line, which metaClass reads
+     * as a single sample since it ignores line breaks
+    */
+    pub fn write_sample(&self, output_file: &str) -> io::Result<()> {
+        let file = File::create(output_file)?;
+        let mut writer = BufWriter::new(file);
+        for read in &self.reads {
+            writeln!(writer, "{}", read)?;
+        }
+        writer.flush()
+    }
+
+    pub fn write_ground_truth(&self, output_file: &str) -> io::Result<()> {
+        let json_data = serde_json::to_string_pretty(&self.ground_truth)?;
+        let mut file = File::create(output_file)?;
+        file.write_all(json_data.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(proportions: &[f64]) -> Vec<SampleSource> {
+        proportions.iter()
+            .enumerate()
+            .map(|(i, &proportion)| SampleSource { name: format!("source_{}", i), sequence: None, proportion })
+            .collect()
+    }
+
+    #[test]
+    fn allocates_exactly_the_requested_reads() {
+        assert_eq!(MetagenomeGenerator::allocate_reads(&sources(&[1.0, 1.0, 1.0, 1.0]), 2), vec![1, 1, 0, 0]);
+        assert_eq!(MetagenomeGenerator::allocate_reads(&sources(&[1.0, 1.0, 1.0]), 10), vec![4, 3, 3]);
+        assert_eq!(MetagenomeGenerator::allocate_reads(&sources(&[0.7, 0.2, 0.1]), 100), vec![70, 20, 10]);
+        assert_eq!(MetagenomeGenerator::allocate_reads(&sources(&[0.5, 0.25, 0.25]), 3), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn ignores_invalid_proportions() {
+        assert_eq!(MetagenomeGenerator::allocate_reads(&sources(&[f64::NAN, 1.0, -1.0]), 5), vec![0, 5, 0]);
+        assert_eq!(MetagenomeGenerator::allocate_reads(&sources(&[0.0, f64::NAN]), 5), vec![0, 0]);
+    }
+
+    #[test]
+    fn substitutions_keep_the_case_of_the_base() {
+        let rates = MutationRates { substitution: 1.0, insertion: 0.0, deletion: 0.0 };
+        let mut generator = MetagenomeGenerator::new(7, rates, 100);
+        let (mutated, substitutions, _, _) = generator.mutate("acgtACGT");
+        assert_eq!(substitutions, 8);
+        assert!(mutated[..4].chars().all(|base| "acgt".contains(base)));
+        assert!(mutated[4..].chars().all(|base| "ACGT".contains(base)));
+        assert!(mutated.chars().zip("acgtACGT".chars()).all(|(new, old)| new != old));
+    }
+}
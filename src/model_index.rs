@@ -0,0 +1,368 @@
+use std::{collections::{HashMap, HashSet}, fs, io, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{finite_context_model::FiniteContextModel, model_saver_loader, taxonomy::GroupBy};
+
+const MANIFEST: &str = "manifest.json";
+const MODEL_DIRECTORY: &str = "models";
+
+/*
+ * Records one model of the index is trained on: a single
+ * record, or every record of a group, in file order
+*/
+pub struct IndexSource<'a> {
+    pub name: String,
+    pub records: Vec<String>,
+    pub sequences: Vec<&'a str>,
+}
+
+impl IndexSource<'_> {
+
+    /*
+     * Hash of the sequences, to tell when the model is out of date
+    */
+    pub fn sequence_hash(&self) -> String {
+        model_saver_loader::hash_bytes(self.sequences.join("\n").as_bytes())
+    }
+}
+
+/*
+ * One model of the index, saved in the binary format
+*/
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    name: String,
+    file: String,                                   // relative to the index directory
+    records: Vec<String>,
+    sequence_hash: String,
+    symbol_count: usize,
+}
+
+impl IndexEntry {
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_file(&self) -> &str {
+        &self.file
+    }
+
+    pub fn get_records(&self) -> &[String] {
+        &self.records
+    }
+
+    pub fn get_sequence_hash(&self) -> &str {
+        &self.sequence_hash
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    k: usize,
+    alpha: f64,
+    group_by: Option<String>,                       // None for one model per record
+    database_file: String,
+    database_hash: String,
+    crate_version: String,
+    entries: Vec<IndexEntry>,
+}
+
+/*
+ * Models of the database saved once, in a directory with a
+ * manifest.json describing them and a models directory, so
+ * later runs load them instead of training them again
+*/
+pub struct ModelIndex {
+    directory: PathBuf,
+    manifest: Manifest,
+    positions: HashMap<String, usize>,              // entries by name
+    loaded: AtomicUsize,                            // models loaded so far
+}
+
+/*
+ * What an update of the index did
+*/
+pub struct IndexUpdate {
+    trained: usize,
+    reused: usize,
+    removed: usize,
+}
+
+impl IndexUpdate {
+
+    pub fn get_trained(&self) -> usize {
+        self.trained
+    }
+
+    pub fn get_reused(&self) -> usize {
+        self.reused
+    }
+
+    pub fn get_removed(&self) -> usize {
+        self.removed
+    }
+}
+
+impl ModelIndex {
+
+    pub fn open(directory: &str) -> io::Result<Self> {
+        let data = fs::read(Path::new(directory).join(MANIFEST))?;
+        Ok(Self::from_manifest(directory, serde_json::from_slice(&data)?))
+    }
+
+    fn from_manifest(directory: &str, manifest: Manifest) -> Self {
+        let positions = manifest.entries.iter()
+            .enumerate()
+            .map(|(position, entry)| (entry.name.clone(), position))
+            .collect();
+        Self {
+            directory: PathBuf::from(directory),
+            manifest,
+            positions,
+            loaded: AtomicUsize::new(0),
+        }
+    }
+
+    /*
+     * Builds the index, or brings it up to date with the
+     * database. Models whose records and sequences have not
+     * changed since the last update are kept, the others are
+     * trained on the threads of the rayon pool, and the models
+     * of records no longer in the database are deleted once
+     * the new manifest has replaced the old one, so a failed
+     * update never leaves a manifest listing deleted models. A
+     * different k, alpha or grouping rebuilds every model
+    */
+    pub fn update(
+        directory: &str,
+        database_path: &str,
+        group_by: Option<&GroupBy>,
+        (k, alpha): (usize, f64),
+        sources: &[IndexSource],
+    ) -> io::Result<(Self, IndexUpdate)> {
+        let group_by = group_by.map(|group_by| group_by.to_string());
+        let previous = Self::open(directory).ok();
+        let reusable: HashMap<&str, &IndexEntry> = previous.iter()
+            .filter(|index| index.manifest.k == k && index.manifest.alpha == alpha && index.manifest.group_by == group_by)
+            .flat_map(|index| index.manifest.entries.iter())
+            .map(|entry| (entry.name.as_str(), entry))
+            .collect();
+
+        // Files are named after the entries, kept files keep their name
+        let mut used_files: HashSet<String> = HashSet::new();
+        let mut files: Vec<String> = Vec::with_capacity(sources.len());
+        for source in sources {
+            let file = match reusable.get(source.name.as_str()) {
+                Some(entry) => entry.file.clone(),
+                None => unique_file_name(&source.name, &used_files, &reusable),
+            };
+            used_files.insert(file.clone());
+            files.push(file);
+        }
+
+        fs::create_dir_all(Path::new(directory).join(MODEL_DIRECTORY))?;
+        let updated: Vec<(IndexEntry, bool)> = sources.par_iter()
+            .zip(files.par_iter())
+            .map(|(source, file)| {
+                let sequence_hash = source.sequence_hash();
+                let path = Path::new(directory).join(file);
+                if let Some(entry) = reusable.get(source.name.as_str()) {
+                    if entry.sequence_hash == sequence_hash && entry.records == source.records && path.exists() {
+                        return Ok(((*entry).clone(), false));
+                    }
+                }
+
+                let mut model = FiniteContextModel::new(k, alpha);
+                for sequence in &source.sequences {
+                    sequence.chars().for_each(|symbol| model.train_char(symbol));
+                }
+                model_saver_loader::save_model(&model, &path.to_string_lossy(), false)?;
+
+                let entry = IndexEntry {
+                    name: source.name.clone(),
+                    file: file.clone(),
+                    records: source.records.clone(),
+                    sequence_hash,
+                    symbol_count: model.get_symbols().len(),
+                };
+                Ok((entry, true))
+            })
+            .collect::<io::Result<_>>()?;
+
+        let trained = updated.iter().filter(|(_, trained)| *trained).count();
+        let index = Self::from_manifest(directory, Manifest {
+            k,
+            alpha,
+            group_by,
+            database_file: database_path.to_string(),
+            database_hash: model_saver_loader::hash_file(database_path)?,
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            entries: updated.into_iter().map(|(entry, _)| entry).collect(),
+        });
+
+        // The new manifest replaces the old one at once, before any file it no longer lists is deleted
+        let manifest_path = index.directory.join(MANIFEST);
+        let new_manifest_path = manifest_path.with_extension("json.new");
+        fs::write(&new_manifest_path, serde_json::to_string_pretty(&index.manifest)?)?;
+        fs::rename(&new_manifest_path, &manifest_path)?;
+
+        // Models of the previous index that are not part of this one
+        let mut removed = 0;
+        for entry in previous.iter().flat_map(|index| index.manifest.entries.iter()) {
+            if !used_files.contains(&entry.file) && fs::remove_file(Path::new(directory).join(&entry.file)).is_ok() {
+                removed += 1;
+            }
+        }
+
+        Ok((index, IndexUpdate { trained, reused: sources.len() - trained, removed }))
+    }
+
+    pub fn get_k(&self) -> usize {
+        self.manifest.k
+    }
+
+    pub fn get_alpha(&self) -> f64 {
+        self.manifest.alpha
+    }
+
+    /*
+     * Grouping of the models, None when there is one per record
+    */
+    pub fn get_group_by(&self) -> Option<&str> {
+        self.manifest.group_by.as_deref()
+    }
+
+    pub fn get_entries(&self) -> &[IndexEntry] {
+        &self.manifest.entries
+    }
+
+    pub fn get(&self, name: &str) -> Option<&IndexEntry> {
+        self.positions.get(name).map(|&position| &self.manifest.entries[position])
+    }
+
+    /*
+     * Whether the index was built from this version of the database file
+    */
+    pub fn is_up_to_date(&self, database_path: &str) -> bool {
+        model_saver_loader::hash_file(database_path).is_ok_and(|hash| hash == self.manifest.database_hash)
+    }
+
+    pub fn load_model(&self, entry: &IndexEntry) -> io::Result<FiniteContextModel> {
+        model_saver_loader::load_model(&self.directory.join(&entry.file).to_string_lossy())
+    }
+
+    /*
+     * Model of a single record, if the index has one trained
+     * on this sequence. None tells the caller to train it
+    */
+    pub fn model_for(&self, record: &str, sequence: &str) -> Option<FiniteContextModel> {
+        let entry = self.get(record).filter(|entry| entry.records.len() == 1 && entry.records[0] == record)?;
+        if entry.sequence_hash != model_saver_loader::hash_bytes(sequence.as_bytes()) {
+            return None;
+        }
+
+        let model = self.load_model(entry).ok()?;
+        self.loaded.fetch_add(1, Ordering::Relaxed);
+        Some(model)
+    }
+
+    /*
+     * Number of models loaded by model_for and load_all so far
+    */
+    pub fn get_loaded_count(&self) -> usize {
+        self.loaded.load(Ordering::Relaxed)
+    }
+
+    /*
+     * Every model of the index, in the order of the manifest
+    */
+    pub fn load_all(&self) -> io::Result<(Vec<String>, Vec<FiniteContextModel>)> {
+        let models = self.manifest.entries.par_iter()
+            .map(|entry| self.load_model(entry))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.loaded.fetch_add(models.len(), Ordering::Relaxed);
+        let names = self.manifest.entries.iter().map(|entry| entry.name.clone()).collect();
+        Ok((names, models))
+    }
+}
+
+/*
+ * models/<name>.fcm with the characters that are not safe in
+ * file names replaced by '_', numbered if the name is taken
+*/
+fn unique_file_name(name: &str, used: &HashSet<String>, reusable: &HashMap<&str, &IndexEntry>) -> String {
+    let safe: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect();
+    let taken = |file: &String| used.contains(file) || reusable.values().any(|entry| &entry.file == file);
+
+    let mut file = format!("{}/{}.fcm", MODEL_DIRECTORY, safe);
+    let mut number = 2;
+    while taken(&file) {
+        file = format!("{}/{}_{}.fcm", MODEL_DIRECTORY, safe, number);
+        number += 1;
+    }
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source<'a>(name: &str, sequence: &'a str) -> IndexSource<'a> {
+        IndexSource { name: name.to_string(), records: vec![name.to_string()], sequences: vec![sequence] }
+    }
+
+    #[test]
+    fn update_only_retrains_changed_records() {
+        let directory = std::env::temp_dir().join(format!("tai_index_{}", std::process::id()));
+        let database = directory.with_extension("fa");
+        let (directory, database) = (directory.to_string_lossy().to_string(), database.to_string_lossy().to_string());
+        fs::write(&database, ">a\nACGT\n>b\nTTGA\n").unwrap();
+
+        let (_, update) = ModelIndex::update(&directory, &database, None, (2, 0.1), &[source("a", "ACGT"), source("b", "TTGA")]).unwrap();
+        assert_eq!((update.get_trained(), update.get_reused(), update.get_removed()), (2, 0, 0));
+
+        // b changed, c is new and a is unchanged
+        let (index, update) = ModelIndex::update(&directory, &database, None, (2, 0.1), &[source("a", "ACGT"), source("b", "TTGG"), source("c", "CCCA")]).unwrap();
+        assert_eq!((update.get_trained(), update.get_reused(), update.get_removed()), (2, 1, 0));
+        assert!(index.model_for("b", "TTGG").is_some());
+        assert!(index.model_for("b", "TTGA").is_none());
+
+        // a removed
+        let (index, update) = ModelIndex::update(&directory, &database, None, (2, 0.1), &[source("b", "TTGG"), source("c", "CCCA")]).unwrap();
+        assert_eq!((update.get_trained(), update.get_reused(), update.get_removed()), (0, 2, 1));
+        assert!(index.get("a").is_none());
+        assert!(ModelIndex::open(&directory).unwrap().model_for("c", "CCCA").is_some());
+        assert!(index.is_up_to_date(&database));
+
+        // Another k rebuilds every model
+        let (_, update) = ModelIndex::update(&directory, &database, None, (3, 0.1), &[source("b", "TTGG"), source("c", "CCCA")]).unwrap();
+        assert_eq!((update.get_trained(), update.get_reused()), (2, 0));
+
+        fs::remove_dir_all(&directory).unwrap();
+        fs::remove_file(&database).unwrap();
+    }
+
+    #[test]
+    fn failed_update_keeps_the_models_of_the_manifest() {
+        let directory = std::env::temp_dir().join(format!("tai_index_failed_{}", std::process::id()));
+        let database = directory.with_extension("fa");
+        let (directory, database) = (directory.to_string_lossy().to_string(), database.to_string_lossy().to_string());
+        fs::write(&database, ">a\nACGT\n>b\nTTGA\n").unwrap();
+        ModelIndex::update(&directory, &database, None, (2, 0.1), &[source("a", "ACGT"), source("b", "TTGA")]).unwrap();
+
+        // The database can not be hashed, so the update fails after training
+        let missing = format!("{}.missing", database);
+        assert!(ModelIndex::update(&directory, &missing, None, (2, 0.1), &[source("b", "TTGA")]).is_err());
+
+        let index = ModelIndex::open(&directory).unwrap();
+        assert!(index.model_for("a", "ACGT").is_some());
+        assert!(!Path::new(&directory).join("manifest.json.new").exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+        fs::remove_file(&database).unwrap();
+    }
+}
@@ -1,49 +1,491 @@
-use std::{fs::File, io::{BufReader, BufWriter}, str};
+use std::{fs::{self, File}, io::{self, BufReader, BufWriter, Read}, str, time::{SystemTime, UNIX_EPOCH}};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json;
 use bson::{Bson, to_bson};
 use std::path::Path;
 use std::io::Write;
 
-use crate::finite_context_model::FiniteContextModel;
+/*
+ * Binary model format, version 1:
+ *   magic "TAIM" | version: u8 | model type: u8 | flags: u8
+ *   k: varint | alpha: f64 (little endian)
+ *   alphabet, encoded by each model type
+ *   body with the counts, zstd compressed if flags & FLAG_ZSTD
+ *
+ * Numbers in the alphabet and body are varints (LEB128)
+*/
+const MAGIC: &[u8; 4] = b"TAIM";
+const FORMAT_VERSION: u8 = 1;
+const FLAG_ZSTD: u8 = 1;
+const ZSTD_LEVEL: i32 = 3;
 
+pub(crate) const MODEL_TYPE_CHARS: u8 = 0;
+pub(crate) const MODEL_TYPE_WORDS: u8 = 1;
+pub(crate) const MODEL_TYPE_IMAGE: u8 = 2;
+pub(crate) const MODEL_TYPE_BACKOFF: u8 = 3;
 
-pub fn save_model(model: &FiniteContextModel,file_output: &str){
+#[derive(Debug, PartialEq)]
+pub enum ModelFormat {
+    Json,
+    Bson,
+    Binary,
+}
 
-    std::fs::create_dir_all(Path::new("models")).expect("Failed to create models directory");
+impl ModelFormat {
+    /*
+     * Chooses the format to save with from the file
+     * extension: .json, .bson, anything else is binary
+    */
+    pub fn from_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|extension| extension.to_str()) {
+            Some("json") => ModelFormat::Json,
+            Some("bson") => ModelFormat::Bson,
+            _ => ModelFormat::Binary,
+        }
+    }
 
-    // JSON
-    let json_file_path = Path::new("models").join(file_output).with_extension("json");
-    let json_string = serde_json::to_string_pretty(model).expect("Failed to serialize model");
+    /*
+     * Detects the format of saved model data: binary files start
+     * with the magic bytes and BSON documents with their own length
+    */
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(MAGIC) {
+            return ModelFormat::Binary;
+        }
 
-    let file = File::create(json_file_path).expect("Failed to create File");
-    let mut file_writer = BufWriter::new(file);
+        if data.len() >= 5 && data[data.len() - 1] == 0 {
+            let document_length = i32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            if document_length as usize == data.len() {
+                return ModelFormat::Bson;
+            }
+        }
 
-    writeln!(file_writer, "{}", json_string).expect("Failed to write to file");
+        ModelFormat::Json
+    }
+}
 
-    println!("Model salved as {}.json", file_output);
+/*
+ * Models that can be saved and loaded: JSON and BSON
+ * come from serde, the compact binary format is
+ * implemented by each model type
+*/
+pub trait PersistentModel: Serialize + DeserializeOwned {
+    fn to_binary(&self, compress: bool) -> io::Result<Vec<u8>>;
+    fn from_binary(data: &[u8]) -> io::Result<Self>;
+    fn describe(&self) -> ModelDescription;
+}
 
-    // Binary / BSON
-    let bson_file_path = Path::new("models").join(file_output).with_extension("bson");
-    let bson_value = to_bson(model).expect("Failed to serialize model to BSON");
-    
-    let bson_file_doc = if let Bson::Document(doc) = bson_value {
-        doc
-    } else {
-        panic!("Failed to convert model to BSON Document");
+/*
+ * What a model reports about itself to be
+ * recorded in its metadata
+*/
+pub struct ModelDescription {
+    pub model_type: &'static str,
+    pub k: usize,
+    pub alpha: f64,
+    pub smoothing: &'static str,
+    pub symbol_count: usize,                        // number of distinct symbols seen in training
+}
+
+/*
+ * Provenance of a saved model, kept next to it
+ * in <model path>.meta.json whatever its format
+*/
+#[derive(Serialize, Deserialize)]
+pub struct ModelMetadata {
+    pub model_type: String,
+    pub training_file: Option<String>,
+    pub training_hash: Option<String>,
+    pub symbol_count: usize,
+    pub k: usize,
+    pub alpha: f64,
+    pub smoothing: String,
+    pub created_at: u64,                            // seconds since the Unix epoch
+    pub crate_version: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub clusters: Vec<String>,                      // grapheme clusters of the private use symbols, see SymbolEncoder
+}
+
+impl ModelMetadata {
+
+    /*
+     * Describes the model and hashes the file it was
+     * trained on, if it was trained on a single file.
+     * Compressed files are hashed as stored
+    */
+    pub fn new<M: PersistentModel>(model: &M, training_file: Option<&str>) -> io::Result<Self> {
+        let description = model.describe();
+        let training_hash = match training_file {
+            Some(path) if path != "-" => Some(hash_file(path)?),
+            _ => None,                              // stdin can not be read again to hash it
+        };
+
+        Ok(Self {
+            model_type: description.model_type.to_string(),
+            training_file: training_file.map(String::from),
+            training_hash,
+            symbol_count: description.symbol_count,
+            k: description.k,
+            alpha: description.alpha,
+            smoothing: description.smoothing.to_string(),
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            clusters: Vec::new(),
+        })
+    }
+
+    /*
+     * Whether the file has the same contents as the
+     * one the model was trained on
+    */
+    pub fn was_trained_on(&self, path: &str) -> bool {
+        match &self.training_hash {
+            Some(training_hash) => hash_file(path).map(|hash| &hash == training_hash).unwrap_or(false),
+            None => false,
+        }
+    }
+}
+
+/*
+ * Saves the model to the given path, in the format given
+ * by its extension. `compress` applies zstd to binary files
+*/
+pub fn save_model<M: PersistentModel>(model: &M, file_output: &str, compress: bool) -> io::Result<()> {
+    if let Some(parent) = Path::new(file_output).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let data = match ModelFormat::from_path(file_output) {
+        ModelFormat::Json => serde_json::to_vec_pretty(model)?,
+        ModelFormat::Bson => {
+            let bson_value = to_bson(model).map_err(invalid_data)?;
+            let bson_file_doc = if let Bson::Document(doc) = bson_value {
+                doc
+            } else {
+                return Err(invalid_data("Failed to convert model to BSON Document"));
+            };
+            bson::to_vec(&bson_file_doc).map_err(invalid_data)?
+        }
+        ModelFormat::Binary => model.to_binary(compress)?,
     };
 
-    let file = File::create(bson_file_path).expect("Failed to create BSON file");
+    let file = File::create(file_output)?;
     let mut writer = BufWriter::new(file);
+    writer.write_all(&data)?;
+    writer.flush()
+}
+
+/*
+ * Saves the model and writes its metadata next to it
+*/
+pub fn save_model_with_metadata<M: PersistentModel>(model: &M, file_output: &str, compress: bool, metadata: &ModelMetadata) -> io::Result<()> {
+    save_model(model, file_output, compress)?;
+    let json_data = serde_json::to_string_pretty(metadata)?;
+    fs::write(metadata_path(file_output), json_data)
+}
+
+pub fn metadata_path(model_path: &str) -> String {
+    format!("{}.meta.json", model_path)
+}
+
+pub fn load_metadata(model_path: &str) -> io::Result<ModelMetadata> {
+    let data = fs::read(metadata_path(model_path))?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/*
+ * Lists the saved models of a directory, sorted by path,
+ * with their metadata when it was recorded
+*/
+pub fn list_models(directory: &str) -> io::Result<Vec<(String, Option<ModelMetadata>)>> {
+    let mut model_paths: Vec<String> = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let path_str = path.to_string_lossy().to_string();
+        if path.is_file() && !path_str.ends_with(".meta.json") {
+            model_paths.push(path_str);
+        }
+    }
+    model_paths.sort();
+
+    Ok(model_paths.into_iter()
+        .map(|path| {
+            let metadata = load_metadata(&path).ok();
+            (path, metadata)
+        })
+        .collect())
+}
+
+/*
+ * 64-bit FNV-1a hash of the file contents, in hex.
+ * Identifies training data, it is not meant to be secure
+*/
+pub fn hash_file(path: &str) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buffer = [0u8; 8192];
+    let mut hash: u64 = FNV_OFFSET;
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hash = fnv1a64(hash, &buffer[..read]);
+    }
+
+    Ok(format!("fnv1a64:{:016x}", hash))
+}
+
+/*
+ * Same hash as hash_file, of data in memory
+*/
+pub fn hash_bytes(data: &[u8]) -> String {
+    format!("fnv1a64:{:016x}", fnv1a64(FNV_OFFSET, data))
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+fn fnv1a64(mut hash: u64, data: &[u8]) -> u64 {
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/*
+ * Loads a model saved as JSON, BSON or in the binary
+ * format, detecting the format from the file contents
+*/
+pub fn load_model<M: PersistentModel>(file_input: &str) -> io::Result<M> {
+    let mut file = File::open(file_input)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    match ModelFormat::detect(&data) {
+        ModelFormat::Binary => M::from_binary(&data),
+        ModelFormat::Bson => bson::from_slice(&data).map_err(invalid_data),
+        ModelFormat::Json => Ok(serde_json::from_slice(&data)?),
+    }
+}
+
+pub(crate) struct BinaryHeader {
+    pub k: usize,
+    pub alpha: f64,
+    pub compressed: bool,
+}
 
-    let bson_data = bson::to_vec(&bson_file_doc).expect("Failed to serialize to BSON");
-    writer.write_all(&bson_data).expect("Failed to write BSON data to file");
-    
-    println!("Model salved as {}.bson", file_output);
+pub(crate) fn write_header(data: &mut Vec<u8>, model_type: u8, header: &BinaryHeader) {
+    data.extend_from_slice(MAGIC);
+    data.push(FORMAT_VERSION);
+    data.push(model_type);
+    data.push(if header.compressed { FLAG_ZSTD } else { 0 });
+    write_varint(data, header.k as u64);
+    data.extend_from_slice(&header.alpha.to_le_bytes());
 }
 
-pub fn load_model(file_input: &str) -> FiniteContextModel {
-    let file = File::open(file_input).expect("Failed to open file");
-    let reader = BufReader::new(file);
+/*
+ * Checks the magic bytes, version and model type, returning
+ * the header and a reader positioned at the alphabet
+*/
+pub(crate) fn read_header(data: &[u8], model_type: u8) -> io::Result<(BinaryHeader, ByteReader<'_>)> {
+    let mut reader = ByteReader { data, position: 0 };
 
-    serde_json::from_reader(reader).expect("Failed to deserialize model")
-}
\ No newline at end of file
+    if reader.read_bytes(MAGIC.len())? != MAGIC {
+        return Err(invalid_data("Not a binary model file"));
+    }
+
+    let version = reader.read_u8()?;
+    if version != FORMAT_VERSION {
+        return Err(invalid_data(format!("Unsupported model format version {}", version)));
+    }
+
+    let found_type = reader.read_u8()?;
+    if found_type != model_type {
+        return Err(invalid_data(format!("Expected model type {} but found {}", model_type, found_type)));
+    }
+
+    let flags = reader.read_u8()?;
+    let k = reader.read_varint()? as usize;
+    let alpha = f64::from_le_bytes(reader.read_bytes(8)?.try_into().unwrap());
+
+    Ok((BinaryHeader { k, alpha, compressed: flags & FLAG_ZSTD != 0 }, reader))
+}
+
+pub(crate) fn append_body(data: &mut Vec<u8>, body: Vec<u8>, compressed: bool) -> io::Result<()> {
+    if compressed {
+        data.extend_from_slice(&zstd::stream::encode_all(body.as_slice(), ZSTD_LEVEL)?);
+    } else {
+        data.extend_from_slice(&body);
+    }
+    Ok(())
+}
+
+/*
+ * Returns the rest of the file after the alphabet,
+ * decompressing it if needed
+*/
+pub(crate) fn read_body(reader: &ByteReader, compressed: bool) -> io::Result<Vec<u8>> {
+    if compressed {
+        zstd::stream::decode_all(reader.remaining())
+    } else {
+        Ok(reader.remaining().to_vec())
+    }
+}
+
+pub(crate) fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+pub(crate) fn write_string(data: &mut Vec<u8>, text: &str) {
+    write_varint(data, text.len() as u64);
+    data.extend_from_slice(text.as_bytes());
+}
+
+pub(crate) fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+pub(crate) struct ByteReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if length > self.data.len() - self.position {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated model file"));
+        }
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_varint(&mut self) -> io::Result<u64> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift >= 64 {
+                return Err(invalid_data("Invalid varint"));
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    /*
+     * Reads the number of entries that follow. Every entry
+     * takes at least one byte, so a count larger than the rest
+     * of the data can only come from a corrupt file, and is
+     * rejected before anything is allocated for it
+    */
+    pub fn read_count(&mut self) -> io::Result<usize> {
+        let count = self.read_varint()?;
+        if count > self.remaining().len() as u64 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated model file"));
+        }
+        Ok(count as usize)
+    }
+
+    /*
+     * Reads an index into a table of `size` entries
+    */
+    pub fn read_index(&mut self, size: usize) -> io::Result<usize> {
+        let index = self.read_varint()? as usize;
+        if index >= size {
+            return Err(invalid_data("Symbol index out of range"));
+        }
+        Ok(index)
+    }
+
+    pub fn read_string(&mut self) -> io::Result<String> {
+        let length = self.read_varint()? as usize;
+        let bytes = self.read_bytes(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(invalid_data)
+    }
+
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.position..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{finite_context_model::FiniteContextModel, finite_context_model_backoff::FiniteContextModelBackoff, finite_context_model_words::FiniteContextModelWords};
+
+    fn trained_model() -> FiniteContextModel {
+        let mut model = FiniteContextModel::new(2, 0.1);
+        "abracadabra, ñandú".chars().for_each(|symbol| model.train_char(symbol));
+        model
+    }
+
+    #[test]
+    fn binary_models_load_back_the_same() {
+        let model = trained_model();
+        for compress in [false, true] {
+            let data = model.to_binary(compress).unwrap();
+            assert_eq!(ModelFormat::detect(&data), ModelFormat::Binary);
+            let loaded = FiniteContextModel::from_binary(&data).unwrap();
+            assert_eq!(loaded.get_k(), model.get_k());
+            assert_eq!(loaded.get_symbols(), model.get_symbols());
+            for context in ["ab", "ra", "ña", "zz"] {
+                for &symbol in model.get_symbols() {
+                    assert_eq!(loaded.compute_probability(context, symbol), model.compute_probability(context, symbol));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn detects_json_and_bson() {
+        let model = trained_model();
+        assert_eq!(ModelFormat::detect(&serde_json::to_vec(&model).unwrap()), ModelFormat::Json);
+        let Bson::Document(document) = to_bson(&model).unwrap() else { panic!("not a document") };
+        assert_eq!(ModelFormat::detect(&bson::to_vec(&document).unwrap()), ModelFormat::Bson);
+    }
+
+    #[test]
+    fn rejects_counts_larger_than_the_file() {
+        let mut data = Vec::new();
+        write_header(&mut data, MODEL_TYPE_CHARS, &BinaryHeader { k: 2, alpha: 0.1, compressed: false });
+        write_varint(&mut data, u64::MAX >> 1);
+        assert!(FiniteContextModel::from_binary(&data).is_err());
+
+        let mut data = Vec::new();
+        write_header(&mut data, MODEL_TYPE_WORDS, &BinaryHeader { k: 2, alpha: 0.1, compressed: false });
+        write_varint(&mut data, 1 << 40);
+        assert!(FiniteContextModelWords::from_binary(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_a_backoff_k_that_overflows() {
+        let mut data = Vec::new();
+        write_header(&mut data, MODEL_TYPE_BACKOFF, &BinaryHeader { k: usize::MAX, alpha: 0.1, compressed: false });
+        assert!(FiniteContextModelBackoff::from_binary(&data).is_err());
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let data = trained_model().to_binary(false).unwrap();
+        for length in [0, 4, 10, data.len() / 2, data.len() - 1] {
+            assert!(FiniteContextModel::from_binary(&data[..length]).is_err());
+        }
+    }
+}
@@ -20,9 +20,7 @@ pub fn compute_ncd(
     let cy = get_compressed_size(y, compressor) as f64;
     let cxy = get_compressed_size(&format!("{}{}", x, y), compressor) as f64;
 
-    let ncd = (cxy - cx.min(cy)) as f64 / cx.max(cy) as f64;
-
-    ncd
+    (cxy - cx.min(cy)) / cx.max(cy)
 }
 
 pub fn compute_ncd_fcm(
@@ -34,7 +32,5 @@ pub fn compute_ncd_fcm(
     let cy = model.calculate_information_content( y);
     let cxy = model.calculate_information_content(&format!("{}{}", x, y));
 
-    let ncd = (cxy - cx.min(cy)) / cx.max(cy);
-
-    ncd
+    (cxy - cx.min(cy)) / cx.max(cy)
 }
\ No newline at end of file
@@ -0,0 +1,290 @@
+use std::{fs::File, io::{self, Write}, str::FromStr};
+
+use crate::distance_matrix::DistanceMatrix;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TreeMethod {
+    #[default]
+    NeighborJoining,
+    Upgma,
+}
+
+impl FromStr for TreeMethod {
+    type Err = String;
+
+    fn from_str(method: &str) -> Result<Self, Self::Err> {
+        match method {
+            "nj" => Ok(TreeMethod::NeighborJoining),
+            "upgma" => Ok(TreeMethod::Upgma),
+            _ => Err(format!("invalid tree method \"{}\", expected nj or upgma", method)),
+        }
+    }
+}
+
+struct TreeNode {
+    name: Option<String>,                           // only leaves have names
+    children: Vec<(usize, f64)>,                    // child node and branch length
+}
+
+/*
+ * Tree of the sequences of a distance matrix. Nodes
+ * are kept in a vector and refer to their children
+ * by index; the last node built is the root
+*/
+pub struct PhyloTree {
+    nodes: Vec<TreeNode>,
+    root: Option<usize>,
+}
+
+impl PhyloTree {
+
+    /*
+     * Builds the tree with the given method. Both directions
+     * of each pair are averaged, so asymmetric distances like
+     * NRC can be used as they are, and the diagonal is ignored
+    */
+    pub fn build(matrix: &DistanceMatrix, method: TreeMethod) -> Self {
+        let mut tree = Self {
+            nodes: matrix.get_names().iter()
+                .map(|name| TreeNode { name: Some(name.clone()), children: Vec::new() })
+                .collect(),
+            root: None,
+        };
+
+        let n = matrix.len();
+        let distances: Vec<Vec<f64>> = (0..n)
+            .map(|i| (0..n).map(|j| if i == j { 0.0 } else { (matrix.get(i, j) + matrix.get(j, i)) / 2.0 }).collect())
+            .collect();
+
+        tree.root = match n {
+            0 => None,
+            1 => Some(0),
+            _ => Some(match method {
+                TreeMethod::NeighborJoining => tree.neighbor_joining(distances),
+                TreeMethod::Upgma => tree.upgma(distances),
+            }),
+        };
+        tree
+    }
+
+    /*
+     * Tree in Newick format, e.g. ((a:0.1,b:0.2):0.05,c:0.3);
+    */
+    pub fn to_newick(&self) -> String {
+        let mut newick = String::new();
+        if let Some(root) = self.root {
+            self.write_node(root, &mut newick);
+        }
+        newick.push(';');
+        newick
+    }
+
+    pub fn export_newick(&self, path: &str) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        writeln!(file, "{}", self.to_newick())
+    }
+
+    /*
+     * Saitou and Nei's neighbor joining. The tree is unrooted,
+     * so the last three clusters are joined at the root
+    */
+    fn neighbor_joining(&mut self, mut distances: Vec<Vec<f64>>) -> usize {
+        let mut clusters: Vec<usize> = (0..distances.len()).collect();
+
+        while clusters.len() > 3 {
+            let n = clusters.len();
+            let totals: Vec<f64> = distances.iter().map(|row| row.iter().sum()).collect();
+
+            // Pair with the lowest Q value, ties go to the first pair found
+            let mut best = (0, 1);
+            let mut best_q = f64::INFINITY;
+            for i in 0..n {
+                for j in (i + 1)..n {
+                    let q = (n as f64 - 2.0) * distances[i][j] - totals[i] - totals[j];
+                    if q < best_q {
+                        best_q = q;
+                        best = (i, j);
+                    }
+                }
+            }
+
+            let (i, j) = best;
+            let length_i = distances[i][j] / 2.0 + (totals[i] - totals[j]) / (2.0 * (n as f64 - 2.0));
+            let length_j = distances[i][j] - length_i;
+            let joined = self.join(&[(clusters[i], length_i), (clusters[j], length_j)]);
+
+            let joined_distances: Vec<f64> = (0..n)
+                .filter(|&k| k != i && k != j)
+                .map(|k| (distances[i][k] + distances[j][k] - distances[i][j]) / 2.0)
+                .collect();
+            Self::merge(&mut distances, &mut clusters, (i, j), joined, joined_distances);
+        }
+
+        if clusters.len() == 2 {
+            let half = distances[0][1] / 2.0;
+            return self.join(&[(clusters[0], half), (clusters[1], half)]);
+        }
+
+        let (ab, ac, bc) = (distances[0][1], distances[0][2], distances[1][2]);
+        self.join(&[
+            (clusters[0], (ab + ac - bc) / 2.0),
+            (clusters[1], (ab + bc - ac) / 2.0),
+            (clusters[2], (ac + bc - ab) / 2.0),
+        ])
+    }
+
+    /*
+     * Average linkage clustering, giving a rooted tree
+     * where every leaf is at the same distance of the root
+    */
+    fn upgma(&mut self, mut distances: Vec<Vec<f64>>) -> usize {
+        let mut clusters: Vec<usize> = (0..distances.len()).collect();
+        let mut sizes: Vec<usize> = vec![1; clusters.len()];
+        let mut heights: Vec<f64> = vec![0.0; clusters.len()];
+
+        while clusters.len() > 1 {
+            let n = clusters.len();
+
+            // Closest pair, ties go to the first pair found
+            let mut best = (0, 1);
+            for i in 0..n {
+                for j in (i + 1)..n {
+                    if distances[i][j] < distances[best.0][best.1] {
+                        best = (i, j);
+                    }
+                }
+            }
+
+            let (i, j) = best;
+            let height = distances[i][j] / 2.0;
+            let joined = self.join(&[(clusters[i], height - heights[i]), (clusters[j], height - heights[j])]);
+
+            let joined_size = sizes[i] + sizes[j];
+            let joined_distances: Vec<f64> = (0..n)
+                .filter(|&k| k != i && k != j)
+                .map(|k| (sizes[i] as f64 * distances[i][k] + sizes[j] as f64 * distances[j][k]) / joined_size as f64)
+                .collect();
+            Self::merge(&mut distances, &mut clusters, (i, j), joined, joined_distances);
+
+            // The joined cluster is appended at the end, like in merge
+            sizes.remove(j);
+            sizes.remove(i);
+            sizes.push(joined_size);
+            heights.remove(j);
+            heights.remove(i);
+            heights.push(height);
+        }
+
+        clusters[0]
+    }
+
+    /*
+     * Adds an internal node over the given children. Negative
+     * branch lengths, which neighbor joining can give for
+     * distances that are not additive, are set to 0
+    */
+    fn join(&mut self, children: &[(usize, f64)]) -> usize {
+        self.nodes.push(TreeNode {
+            name: None,
+            children: children.iter().map(|&(child, length)| (child, length.max(0.0))).collect(),
+        });
+        self.nodes.len() - 1
+    }
+
+    /*
+     * Replaces clusters i and j (i < j) of the distance matrix
+     * by the joined cluster, appended as the last one
+    */
+    fn merge(
+        distances: &mut Vec<Vec<f64>>,
+        clusters: &mut Vec<usize>,
+        (i, j): (usize, usize),
+        joined: usize,
+        joined_distances: Vec<f64>,
+    ) {
+        for row in distances.iter_mut() {
+            row.remove(j);
+            row.remove(i);
+        }
+        distances.remove(j);
+        distances.remove(i);
+
+        for (row, &distance) in distances.iter_mut().zip(&joined_distances) {
+            row.push(distance);
+        }
+        let mut joined_row = joined_distances;
+        joined_row.push(0.0);
+        distances.push(joined_row);
+
+        clusters.remove(j);
+        clusters.remove(i);
+        clusters.push(joined);
+    }
+
+    fn write_node(&self, node: usize, newick: &mut String) {
+        let node = &self.nodes[node];
+        if !node.children.is_empty() {
+            newick.push('(');
+            for (index, &(child, length)) in node.children.iter().enumerate() {
+                if index > 0 {
+                    newick.push(',');
+                }
+                self.write_node(child, newick);
+                newick.push_str(&format!(":{:.6}", length));
+            }
+            newick.push(')');
+        }
+
+        if let Some(name) = &node.name {
+            newick.push_str(&newick_label(name));
+        }
+    }
+}
+
+/*
+ * Names with characters that have a meaning in
+ * Newick are quoted, doubling their own quotes
+*/
+fn newick_label(name: &str) -> String {
+    if name.chars().any(|c| c.is_whitespace() || "()[]',:;".contains(c)) {
+        format!("'{}'", name.replace('\'', "''"))
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix(values: Vec<Vec<f64>>) -> DistanceMatrix {
+        let names = ["a", "b", "c", "d"].iter().map(|name| name.to_string()).collect();
+        DistanceMatrix::new("test", names, values)
+    }
+
+    #[test]
+    fn neighbor_joining_recovers_an_additive_tree() {
+        // Unrooted tree ((a:2,b:3):2,(c:4,d:1))
+        let tree = PhyloTree::build(&matrix(vec![
+            vec![0.0, 5.0, 8.0, 5.0],
+            vec![5.0, 0.0, 9.0, 6.0],
+            vec![8.0, 9.0, 0.0, 5.0],
+            vec![5.0, 6.0, 5.0, 0.0],
+        ]), TreeMethod::NeighborJoining);
+
+        assert_eq!(tree.to_newick(), "(c:4.000000,d:1.000000,(a:2.000000,b:3.000000):2.000000);");
+    }
+
+    #[test]
+    fn upgma_recovers_an_ultrametric_tree() {
+        // Rooted tree ((a:1,b:1):2,(c:2,d:2):1), every leaf 3 from the root
+        let tree = PhyloTree::build(&matrix(vec![
+            vec![0.0, 2.0, 6.0, 6.0],
+            vec![2.0, 0.0, 6.0, 6.0],
+            vec![6.0, 6.0, 0.0, 4.0],
+            vec![6.0, 6.0, 4.0, 0.0],
+        ]), TreeMethod::Upgma);
+
+        assert_eq!(tree.to_newick(), "((a:1.000000,b:1.000000):2.000000,(c:2.000000,d:2.000000):1.000000);");
+    }
+}
@@ -0,0 +1,473 @@
+use std::{fs::File, io::{self, BufWriter, Write}};
+use rayon::prelude::*;
+
+use crate::{finite_context_model::FiniteContextModel, sequence_reader::{self, SequenceRecord}, task_tracker::TaskTracker};
+
+const UNCLASSIFIED: &str = "unclassified";
+
+// EM stops when no abundance moves by more than this, or after MAX_EM_ITERATIONS
+const EM_TOLERANCE: f64 = 1e-9;
+const MAX_EM_ITERATIONS: usize = 1000;
+
+// References with no best hit and fewer estimated reads are left out of the table
+const MIN_REPORTED_READS: f64 = 0.01;
+
+// Reads held in memory at a time when they are classified from a reader
+const READ_BATCH_SIZE: usize = 10_000;
+
+/*
+ * Best reference of one read of the sample. Every
+ * reference under the threshold is kept as a candidate,
+ * so ambiguous reads can be shared out by EM
+*/
+pub struct ReadAssignment {
+    read_id: String,
+    length: usize,
+    best: Option<usize>,                            // reference with the lowest NRC, None if unclassified
+    nrc: Option<f64>,                               // lowest NRC, None for reads no longer than k
+    candidates: Vec<(usize, f64)>,                  // reference and bits of the read under its model
+}
+
+impl ReadAssignment {
+
+    pub fn get_read_id(&self) -> &str {
+        &self.read_id
+    }
+
+    pub fn get_length(&self) -> usize {
+        self.length
+    }
+
+    pub fn get_nrc(&self) -> Option<f64> {
+        self.nrc
+    }
+
+    pub fn is_classified(&self) -> bool {
+        self.best.is_some()
+    }
+
+    /*
+     * Number of references whose NRC is under the threshold
+    */
+    pub fn get_candidate_count(&self) -> usize {
+        self.candidates.len()
+    }
+}
+
+/*
+ * Reads of one reference: those it is the best reference
+ * of, and the reads estimated to come from it, which EM
+ * shares out between the candidates of ambiguous reads
+*/
+pub struct Abundance {
+    reference: String,
+    reads: usize,
+    estimated_reads: f64,
+    relative_abundance: f64,                        // share of all reads, unclassified included
+}
+
+impl Abundance {
+
+    pub fn get_reference(&self) -> &str {
+        &self.reference
+    }
+
+    pub fn get_reads(&self) -> usize {
+        self.reads
+    }
+
+    pub fn get_estimated_reads(&self) -> f64 {
+        self.estimated_reads
+    }
+
+    pub fn get_relative_abundance(&self) -> f64 {
+        self.relative_abundance
+    }
+}
+
+pub struct AbundanceTable {
+    abundances: Vec<Abundance>,                     // from the most abundant reference
+    weights: Vec<f64>,                              // abundance of every reference among classified reads
+    unclassified: usize,
+    total_reads: usize,
+    em_iterations: Option<usize>,
+}
+
+impl AbundanceTable {
+
+    pub fn get_abundances(&self) -> &[Abundance] {
+        &self.abundances
+    }
+
+    pub fn get_unclassified_count(&self) -> usize {
+        self.unclassified
+    }
+
+    pub fn get_total_reads(&self) -> usize {
+        self.total_reads
+    }
+
+    /*
+     * Iterations run by EM, None when it was not used
+    */
+    pub fn get_em_iterations(&self) -> Option<usize> {
+        self.em_iterations
+    }
+
+    /*
+     * Probability that a read comes from its best reference,
+     * given the abundances estimated by EM, or equal
+     * abundances without EM
+    */
+    pub fn probability(&self, assignment: &ReadAssignment) -> f64 {
+        match assignment.best {
+            Some(best) => posteriors(&assignment.candidates, &self.weights).into_iter()
+                .find(|&(reference, _)| reference == best)
+                .map_or(0.0, |(_, probability)| probability),
+            None => 0.0,
+        }
+    }
+
+    /*
+     * Tab separated table with one row per reference, from
+     * the most abundant, and a last row for unclassified reads
+    */
+    pub fn export_tsv(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "reference\treads\testimated_reads\trelative_abundance")?;
+        for abundance in &self.abundances {
+            writeln!(
+                writer,
+                "{}\t{}\t{:.2}\t{:.6}",
+                abundance.reference, abundance.reads, abundance.estimated_reads, abundance.relative_abundance
+            )?;
+        }
+        writeln!(
+            writer,
+            "{}\t{}\t{:.2}\t{:.6}",
+            UNCLASSIFIED, self.unclassified, self.unclassified as f64, share(self.unclassified as f64, self.total_reads)
+        )?;
+        writer.flush()
+    }
+}
+
+/*
+ * Models of the reference sequences, each read of the sample
+ * is assigned to the one that compresses it best. Reads whose
+ * lowest NRC is not under the threshold are unclassified
+*/
+pub struct ReadClassifier {
+    names: Vec<String>,
+    models: Vec<FiniteContextModel>,
+    threshold: f64,
+    alphabet_size: usize,                           // the same for every model, so their NRCs compare
+}
+
+impl ReadClassifier {
+
+    /*
+     * Trains one model per reference on the threads of the rayon pool
+    */
+    pub fn train(names: &[String], sequences: &[&str], (k, alpha): (usize, f64), threshold: f64, alphabet_size: usize) -> Self {
+        let models = sequences.par_iter()
+            .map(|sequence| {
+                let mut model = FiniteContextModel::new(k, alpha);
+                sequence.chars().for_each(|symbol| model.train_char(symbol));
+                model
+            })
+            .collect();
+
+        Self::from_models(names.to_vec(), models, threshold, alphabet_size)
+    }
+
+    /*
+     * Classifier with models trained elsewhere, e.g. loaded from
+     * an index. names and models go together, one name per model.
+     * Every model is smoothed over the same alphabet
+    */
+    pub fn from_models(names: Vec<String>, mut models: Vec<FiniteContextModel>, threshold: f64, alphabet_size: usize) -> Self {
+        assert_eq!(names.len(), models.len(), "one name per model");
+        models.iter_mut().for_each(|model| model.set_alphabet_size(alphabet_size));
+        Self {
+            names,
+            models,
+            threshold,
+            alphabet_size,
+        }
+    }
+
+    pub fn get_names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn classify(&self, read: &SequenceRecord) -> ReadAssignment {
+        let sequence = read.get_sequence();
+        let length = sequence.chars().count();
+        let mut assignment = ReadAssignment {
+            read_id: read.get_id().to_string(),
+            length,
+            best: None,
+            nrc: None,
+            candidates: Vec::new(),
+        };
+
+        // Reads no longer than k have no symbol with a full context to score
+        let k = self.models.first().map_or(0, |model| model.get_k());
+        if length <= k {
+            return assignment;
+        }
+
+        let bits_per_symbol = sequence_reader::bits_per_symbol(self.alphabet_size);
+        let mut lowest = f64::INFINITY;
+        for (reference, model) in self.models.iter().enumerate() {
+            let bits = model.calculate_information_content(sequence);
+            let nrc = bits / (bits_per_symbol * length as f64);
+            if nrc < self.threshold {
+                assignment.candidates.push((reference, bits));
+            }
+
+            // Ties go to the first reference, the best ranked one
+            if nrc < lowest {
+                lowest = nrc;
+                if nrc < self.threshold {
+                    assignment.best = Some(reference);
+                }
+            }
+        }
+
+        assignment.nrc = Some(lowest);
+        assignment
+    }
+
+    pub fn classify_reads(&self, reads: &[SequenceRecord]) -> Vec<ReadAssignment> {
+        reads.iter().map(|read| self.classify(read)).collect()
+    }
+
+    /*
+     * Same as classify_reads, with the reads classified on the
+     * threads of the rayon pool, in the order of the sample
+    */
+    pub fn classify_reads_parallel(&self, reads: &[SequenceRecord], tracker: &TaskTracker) -> Vec<ReadAssignment> {
+        reads.par_iter()
+            .map(|read| tracker.run(|| self.classify(read)))
+            .collect()
+    }
+
+    /*
+     * Classifies the reads of a reader in batches, so that only
+     * one batch of reads is in memory at a time. With a tracker
+     * each batch is classified on the threads of the rayon pool
+    */
+    pub fn classify_batches<I>(&self, reads: I, tracker: Option<&TaskTracker>) -> io::Result<Vec<ReadAssignment>>
+    where
+        I: Iterator<Item = io::Result<SequenceRecord>>,
+    {
+        let mut reads = reads.peekable();
+        let mut assignments = Vec::new();
+        while reads.peek().is_some() {
+            let batch = reads.by_ref().take(READ_BATCH_SIZE).collect::<io::Result<Vec<_>>>()?;
+            assignments.extend(match tracker {
+                Some(tracker) => self.classify_reads_parallel(&batch, tracker),
+                None => self.classify_reads(&batch),
+            });
+        }
+        Ok(assignments)
+    }
+
+    /*
+     * Abundance of every reference. Without EM each classified
+     * read counts for its best reference. With EM each read is
+     * shared out between its candidates in proportion to their
+     * abundance times the probability of the read under their
+     * model, and the abundances are re-estimated from these
+     * shares until they no longer change
+    */
+    pub fn estimate_abundances(&self, assignments: &[ReadAssignment], em: bool) -> AbundanceTable {
+        let mut reads = vec![0; self.names.len()];
+        for best in assignments.iter().filter_map(|assignment| assignment.best) {
+            reads[best] += 1;
+        }
+        let classified: usize = reads.iter().sum();
+
+        let mut weights = vec![1.0 / self.names.len().max(1) as f64; self.names.len()];
+        let mut estimated_reads: Vec<f64> = reads.iter().map(|&count| count as f64).collect();
+        let mut em_iterations = None;
+
+        if em && classified > 0 {
+            let mut iterations = 0;
+            while iterations < MAX_EM_ITERATIONS {
+                iterations += 1;
+
+                let mut shares = vec![0.0; self.names.len()];
+                for assignment in assignments.iter().filter(|assignment| assignment.best.is_some()) {
+                    for (reference, probability) in posteriors(&assignment.candidates, &weights) {
+                        shares[reference] += probability;
+                    }
+                }
+
+                let new_weights: Vec<f64> = shares.iter().map(|share| share / classified as f64).collect();
+                let change = weights.iter().zip(&new_weights).map(|(old, new)| (old - new).abs()).fold(0.0, f64::max);
+                weights = new_weights;
+                estimated_reads = shares;
+                if change < EM_TOLERANCE {
+                    break;
+                }
+            }
+            em_iterations = Some(iterations);
+        }
+
+        let total_reads = assignments.len();
+        let mut abundances: Vec<Abundance> = self.names.iter()
+            .zip(reads.iter().zip(&estimated_reads))
+            .filter(|(_, (&reads, &estimated_reads))| reads > 0 || estimated_reads >= MIN_REPORTED_READS)
+            .map(|(name, (&reads, &estimated_reads))| Abundance {
+                reference: name.clone(),
+                reads,
+                estimated_reads,
+                relative_abundance: share(estimated_reads, total_reads),
+            })
+            .collect();
+        abundances.sort_by(|a, b| b.estimated_reads.total_cmp(&a.estimated_reads));
+
+        AbundanceTable {
+            abundances,
+            weights,
+            unclassified: total_reads - classified,
+            total_reads,
+            em_iterations,
+        }
+    }
+
+    /*
+     * Tab separated table with one row per read: its best
+     * reference, NRC, number of candidate references and the
+     * probability that it comes from its best reference
+    */
+    pub fn export_assignments(&self, assignments: &[ReadAssignment], abundances: &AbundanceTable, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "read_id\tlength\treference\tnrc\tcandidates\tprobability")?;
+        for assignment in assignments {
+            let reference = assignment.best.map_or(UNCLASSIFIED, |best| self.names[best].as_str());
+            let nrc = assignment.nrc.map_or("NA".to_string(), |nrc| format!("{:.6}", nrc));
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{:.6}",
+                assignment.read_id,
+                assignment.length,
+                reference,
+                nrc,
+                assignment.candidates.len(),
+                abundances.probability(assignment),
+            )?;
+        }
+        writer.flush()
+    }
+}
+
+/*
+ * Probability of each candidate given the read, proportional to
+ * its weight times 2^-bits. Bits are taken relative to the best
+ * candidate so that long reads do not underflow
+*/
+fn posteriors(candidates: &[(usize, f64)], weights: &[f64]) -> Vec<(usize, f64)> {
+    let fewest_bits = candidates.iter().map(|&(_, bits)| bits).fold(f64::INFINITY, f64::min);
+    let likelihoods: Vec<(usize, f64)> = candidates.iter()
+        .map(|&(reference, bits)| (reference, weights[reference] * (fewest_bits - bits).exp2()))
+        .collect();
+
+    let total: f64 = likelihoods.iter().map(|&(_, likelihood)| likelihood).sum();
+    if total > 0.0 {
+        likelihoods.into_iter().map(|(reference, likelihood)| (reference, likelihood / total)).collect()
+    } else {
+        Vec::new()
+    }
+}
+
+fn share(reads: f64, total_reads: usize) -> f64 {
+    if total_reads > 0 {
+        reads / total_reads as f64
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assignment(best: Option<usize>, candidates: Vec<(usize, f64)>) -> ReadAssignment {
+        ReadAssignment { read_id: String::new(), length: 100, best, nrc: Some(0.5), candidates }
+    }
+
+    fn classifier() -> ReadClassifier {
+        ReadClassifier::train(&["a".to_string(), "b".to_string()], &["ACGTACGT", "TTGGCCAA"], (2, 0.1), 0.9, 4)
+    }
+
+    // 6 reads of a, 2 of b, 4 that both compress as well, and 1 unclassified
+    fn assignments() -> Vec<ReadAssignment> {
+        let mut assignments = Vec::new();
+        assignments.extend((0..6).map(|_| assignment(Some(0), vec![(0, 10.0)])));
+        assignments.extend((0..2).map(|_| assignment(Some(1), vec![(1, 10.0)])));
+        assignments.extend((0..4).map(|_| assignment(Some(0), vec![(0, 10.0), (1, 10.0)])));
+        assignments.push(assignment(None, Vec::new()));
+        assignments
+    }
+
+    #[test]
+    fn best_hits_count_without_em() {
+        let abundances = classifier().estimate_abundances(&assignments(), false);
+        assert_eq!(abundances.get_em_iterations(), None);
+        assert_eq!(abundances.get_unclassified_count(), 1);
+        assert_eq!(abundances.get_abundances()[0].get_reads(), 10);
+        assert_eq!(abundances.get_abundances()[0].get_estimated_reads(), 10.0);
+        assert_eq!(abundances.get_abundances()[1].get_reads(), 2);
+    }
+
+    #[test]
+    fn em_shares_ambiguous_reads_by_abundance() {
+        // At the fixed point a has 3/4 of the classified reads: 6 of its own and 3 of the shared ones
+        let abundances = classifier().estimate_abundances(&assignments(), true);
+        assert!(abundances.get_em_iterations().is_some());
+
+        let (a, b) = (&abundances.get_abundances()[0], &abundances.get_abundances()[1]);
+        assert_eq!((a.get_reference(), b.get_reference()), ("a", "b"));
+        assert!((a.get_estimated_reads() - 9.0).abs() < 1e-6);
+        assert!((b.get_estimated_reads() - 3.0).abs() < 1e-6);
+        assert!((a.get_relative_abundance() - 9.0 / 13.0).abs() < 1e-6);
+        assert!((abundances.probability(&assignments()[8]) - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn reads_are_assigned_to_the_model_that_compresses_them_best() {
+        let classifier = classifier();
+        let reads = "@r1\nACGTACGTAC\n@r2\nTTGGCCAATT\n@r3\nAC\n";
+        let path = std::env::temp_dir().join(format!("tai_reads_{}.fa", std::process::id()));
+        std::fs::write(&path, reads).unwrap();
+
+        let reader = crate::sequence_reader::SequenceReader::open_reads(&path.to_string_lossy()).unwrap();
+        let assignments = classifier.classify_batches(reader, None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let best: Vec<Option<usize>> = assignments.iter().map(|assignment| assignment.best).collect();
+        assert_eq!(best, [Some(0), Some(1), None]);
+        assert_eq!(assignments[2].get_nrc(), None);
+    }
+
+    #[test]
+    fn references_that_saw_fewer_symbols_are_not_favoured() {
+        // Smoothed over the 2 symbols it saw, b would give G and T 1/4 each and win with 6 bits against 8
+        let classifier = ReadClassifier::train(&["a".to_string(), "b".to_string()], &["ACGT", "AC"], (0, 1.0), 10.0, 4);
+        let path = std::env::temp_dir().join(format!("tai_alphabet_reads_{}.fa", std::process::id()));
+        std::fs::write(&path, ">r\nACGT\n").unwrap();
+
+        let reader = crate::sequence_reader::SequenceReader::open_reads(&path.to_string_lossy()).unwrap();
+        let assignments = classifier.classify_batches(reader, None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // a gives every symbol 1/4, b gives A and C 1/3 and G and T 1/6
+        assert_eq!(assignments[0].best, Some(0));
+        assert!((assignments[0].get_nrc().unwrap() - 1.0).abs() < 1e-12);
+        let b_bits = classifier.models[1].calculate_information_content("ACGT");
+        assert!((b_bits - 2.0 * (3f64.log2() + 6f64.log2())).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,446 @@
+use std::{io, str::FromStr};
+
+use crate::file_reader::{self, FileReader};
+
+// FASTQ qualities are Phred scores shifted by 33 into printable ASCII
+const PHRED_OFFSET: u8 = 33;
+
+/*
+ * One record of a FASTA or FASTQ file. The header is split
+ * at its first whitespace into the ID and the description
+*/
+#[derive(Clone, Debug)]
+pub struct SequenceRecord {
+    id: String,
+    description: String,
+    sequence: String,
+    quality: Option<String>,                        // only FASTQ records have one
+}
+
+impl SequenceRecord {
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn get_sequence(&self) -> &str {
+        &self.sequence
+    }
+
+    pub fn get_quality(&self) -> Option<&str> {
+        self.quality.as_deref()
+    }
+
+    pub fn into_sequence(self) -> String {
+        self.sequence
+    }
+
+    /*
+     * Mean Phred score of the record, None
+     * for FASTA records and empty sequences
+    */
+    pub fn mean_quality(&self) -> Option<f64> {
+        let quality = self.quality.as_ref().filter(|quality| !quality.is_empty())?;
+        let total: u64 = quality.bytes().map(|score| score.saturating_sub(PHRED_OFFSET) as u64).sum();
+        Some(total as f64 / quality.len() as f64)
+    }
+}
+
+/*
+ * Which records are kept. Records without qualities
+ * (FASTA) are never removed by the quality filter
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RecordFilter {
+    pub min_length: usize,
+    pub min_mean_quality: Option<f64>,
+}
+
+impl RecordFilter {
+
+    pub fn accepts(&self, record: &SequenceRecord) -> bool {
+        if record.sequence.len() < self.min_length {
+            return false;
+        }
+
+        match (self.min_mean_quality, record.mean_quality()) {
+            (Some(min_quality), Some(quality)) => quality >= min_quality,
+            _ => true,
+        }
+    }
+}
+
+/*
+ * Kind of symbols of the sequences. The size of the alphabet
+ * sets the most bits a symbol can cost, log2 of the size,
+ * which normalises the NRC. Auto uses the alphabet observed
+ * by the model of the sample, e.g. 5 symbols for DNA with
+ * some Ns, so that every NRC of a run has the same alphabet
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SequenceType {
+    #[default]
+    Dna,
+    Rna,
+    Protein,
+    Alphabet(usize),                                // any other alphabet of this size
+    Auto,
+}
+
+impl FromStr for SequenceType {
+    type Err = String;
+
+    fn from_str(sequence_type: &str) -> Result<Self, Self::Err> {
+        match sequence_type {
+            "dna" => Ok(SequenceType::Dna),
+            "rna" => Ok(SequenceType::Rna),
+            "protein" => Ok(SequenceType::Protein),
+            "auto" => Ok(SequenceType::Auto),
+            _ => match sequence_type.strip_prefix("alphabet:").map(str::parse::<usize>) {
+                Some(Ok(size)) if size >= 2 => Ok(SequenceType::Alphabet(size)),
+                _ => Err(format!("invalid sequence type \"{}\", expected dna, rna, protein, auto or alphabet:N with N >= 2", sequence_type)),
+            },
+        }
+    }
+}
+
+impl SequenceType {
+
+    pub fn alphabet_size(&self, observed_size: usize) -> usize {
+        match self {
+            SequenceType::Dna | SequenceType::Rna => 4,
+            SequenceType::Protein => 20,
+            SequenceType::Alphabet(size) => *size,
+            SequenceType::Auto => observed_size,
+        }
+    }
+
+}
+
+/*
+ * Bits of a symbol when every symbol of the alphabet
+ * is equally likely. An alphabet of one symbol is
+ * taken as two, so the NRC is never divided by 0
+*/
+pub fn bits_per_symbol(alphabet_size: usize) -> f64 {
+    (alphabet_size.max(2) as f64).log2()
+}
+
+/*
+ * Reads FASTA (">" headers) and FASTQ ("@" headers, "+"
+ * separator and quality lines) records, with sequences over
+ * any number of lines. "@" records without a "+" line are
+ * read like FASTA, as in the databases of this project.
+ * Compressed files are decompressed by the file reader
+*/
+pub struct SequenceReader {
+    file_reader: FileReader,
+    next_header: Option<String>,                    // header already read by the previous record
+    line_number: usize,
+    unnamed_lines: bool,                            // lines without a header are records of their own
+    records_read: usize,
+}
+
+impl SequenceReader {
+
+    pub fn open(filename: &str) -> io::Result<Self> {
+        let mut file_reader = FileReader::new(filename);
+        file_reader::open_file(&mut file_reader)?;
+        Ok(Self {
+            file_reader,
+            next_header: None,
+            line_number: 0,
+            unnamed_lines: false,
+            records_read: 0,
+        })
+    }
+
+    /*
+     * Reads the reads of a sample: FASTA and FASTQ records as
+     * with open, and lines without a header, as written by
+     * metaGen, as one read each named read_<n> from read_0
+    */
+    pub fn open_reads(filename: &str) -> io::Result<Self> {
+        let mut reader = Self::open(filename)?;
+        reader.unnamed_lines = true;
+        Ok(reader)
+    }
+
+    pub fn next_record(&mut self) -> io::Result<Option<SequenceRecord>> {
+        let header = match self.next_header.take() {
+            Some(header) => header,
+            None => loop {
+                match self.read_line()? {
+                    None => return Ok(None),
+                    Some(line) if line.is_empty() || line.starts_with(';') => continue,
+                    Some(line) if is_header(&line) => break line,
+                    Some(line) if self.unnamed_lines => {
+                        let id = format!("read_{}", self.records_read);
+                        self.records_read += 1;
+                        return Ok(Some(SequenceRecord { id, description: String::new(), sequence: line.trim().to_string(), quality: None }));
+                    }
+                    Some(_) => return Err(self.invalid_data("expected a header starting with '>' or '@'")),
+                }
+            },
+        };
+        self.records_read += 1;
+
+        let (id, description) = match header[1..].trim().split_once(char::is_whitespace) {
+            Some((id, description)) => (id.to_string(), description.trim().to_string()),
+            None => (header[1..].trim().to_string(), String::new()),
+        };
+
+        let mut sequence = String::new();
+        let mut quality = None;
+
+        while let Some(line) = self.read_line()? {
+            if is_header(&line) {
+                self.next_header = Some(line);
+                break;
+            }
+
+            if header.starts_with('@') && line.starts_with('+') {
+                // Quality lines can start with '@' or '+', so they are read until they cover the sequence
+                let mut scores = String::new();
+                while scores.len() < sequence.len() {
+                    match self.read_line()? {
+                        Some(line) => scores.push_str(&line),
+                        None => break,
+                    }
+                }
+
+                if scores.len() != sequence.len() {
+                    return Err(self.invalid_data(&format!(
+                        "record {} has {} quality scores for {} bases", id, scores.len(), sequence.len()
+                    )));
+                }
+                quality = Some(scores);
+                break;
+            }
+
+            if !line.starts_with(';') {
+                sequence.push_str(&line);
+            }
+        }
+
+        Ok(Some(SequenceRecord { id, description, sequence, quality }))
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let line = file_reader::read_line(&mut self.file_reader)?;
+        if line.is_some() {
+            self.line_number += 1;
+        }
+        Ok(line)
+    }
+
+    fn invalid_data(&self, message: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} line {}: {}", self.file_reader.filename, self.line_number, message),
+        )
+    }
+}
+
+impl Iterator for SequenceReader {
+    type Item = io::Result<SequenceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/*
+ * One record of a text held in memory, with its
+ * sequence lines borrowed from the text
+*/
+pub struct TextRecord<'a> {
+    id: &'a str,
+    sequence: &'a str,                              // the lines of the sequence as in the text
+}
+
+impl<'a> TextRecord<'a> {
+
+    pub fn get_id(&self) -> &'a str {
+        self.id
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = char> + 'a {
+        self.sequence.lines()
+            .filter(|line| !line.starts_with(';'))
+            .flat_map(|line| line.trim_end().chars())
+    }
+}
+
+/*
+ * Records of a FASTA or FASTQ text held in memory, e.g. a
+ * mapped sample, read as SequenceReader reads a file but
+ * without copying the sequences. The text is not checked:
+ * a record cut short ends with the text. Lines without a
+ * header, e.g. a sample of one read per line, are all one
+ * record with the name given for them
+*/
+pub struct TextRecords<'a> {
+    text: &'a str,
+    position: usize,                                // byte offset of the next line
+    unnamed: &'a str,
+}
+
+impl<'a> TextRecords<'a> {
+
+    pub fn new(text: &'a str, unnamed: &'a str) -> Self {
+        Self { text, position: 0, unnamed }
+    }
+
+    fn peek_line(&self) -> Option<&'a str> {
+        let rest = self.text.get(self.position..).filter(|rest| !rest.is_empty())?;
+        Some(rest.split('\n').next().unwrap_or(rest))
+    }
+
+    fn next_line(&mut self) -> Option<&'a str> {
+        let line = self.peek_line()?;
+        self.position = (self.position + line.len() + 1).min(self.text.len());
+        Some(line)
+    }
+}
+
+impl<'a> Iterator for TextRecords<'a> {
+    type Item = TextRecord<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.peek_line().is_some_and(|line| line.trim_end().is_empty() || line.starts_with(';')) {
+            self.next_line();
+        }
+
+        let first_line = self.peek_line()?;
+        let id = if is_header(first_line) {
+            self.next_line();
+            first_line[1..].split_whitespace().next().unwrap_or_default()
+        } else {
+            self.unnamed
+        };
+
+        let start = self.position;
+        let mut end = start;
+        let mut sequence_length = 0;
+        while let Some(line) = self.peek_line() {
+            if is_header(line) {
+                break;
+            }
+            self.next_line();
+
+            if first_line.starts_with('@') && line.starts_with('+') {
+                // Quality lines can start with '@' or '+', so they are skipped until they cover the sequence
+                let mut scores = 0;
+                while scores < sequence_length {
+                    match self.next_line() {
+                        Some(line) => scores += line.trim_end().len(),
+                        None => break,
+                    }
+                }
+                break;
+            }
+
+            end = self.position;
+            if !line.starts_with(';') {
+                sequence_length += line.trim_end().len();
+            }
+        }
+
+        Some(TextRecord { id, sequence: &self.text[start..end] })
+    }
+}
+
+fn is_header(line: &str) -> bool {
+    line.starts_with('>') || line.starts_with('@')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(text: &str, reads: bool) -> io::Result<Vec<SequenceRecord>> {
+        // Tests run in parallel, so each file gets its own number
+        static FILES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let number = FILES.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("tai_sequences_{}_{}", std::process::id(), number));
+        std::fs::write(&path, text).unwrap();
+        let path_name = path.to_string_lossy().to_string();
+        let reader = if reads { SequenceReader::open_reads(&path_name) } else { SequenceReader::open(&path_name) };
+        let records = reader.and_then(|reader| reader.collect());
+        std::fs::remove_file(&path).unwrap();
+        records
+    }
+
+    #[test]
+    fn fasta_records_are_split_into_id_and_description() {
+        let records = read(">NC_1.2 Escherichia coli, complete\nACGT\nAC\n;comment\n>NC_2\n\nGG\n", false).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].get_id(), records[0].get_description()), ("NC_1.2", "Escherichia coli, complete"));
+        assert_eq!(records[0].get_sequence(), "ACGTAC");
+        assert_eq!(records[0].get_quality(), None);
+        assert_eq!((records[1].get_id(), records[1].get_sequence()), ("NC_2", "GG"));
+    }
+
+    #[test]
+    fn fastq_qualities_are_read_until_they_cover_the_sequence() {
+        let records = read("@r1 first\nACGTAC\n+\nIII\n@II\n@r2\nGG\n+r2\n!!\n", false).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].get_quality(), Some("III@II"));
+        assert_eq!(records[1].mean_quality(), Some(0.0));
+        assert!(read("@r1\nACGT\n+\nII\n", false).is_err());
+    }
+
+    #[test]
+    fn text_without_a_header_is_an_error() {
+        assert!(read("ACGT\n", false).is_err());
+    }
+
+    #[test]
+    fn lines_without_a_header_are_reads() {
+        let reads = read("ACGT\nTTGA\n>r\nCC\n", true).unwrap();
+        let ids: Vec<&str> = reads.iter().map(|read| read.get_id()).collect();
+        assert_eq!(ids, ["read_0", "read_1", "r"]);
+    }
+
+    #[test]
+    fn filter_removes_short_and_low_quality_records() {
+        let records = read("@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\n!!!!\n>r3\nAC\n", false).unwrap();
+        let filter = RecordFilter { min_length: 3, min_mean_quality: Some(20.0) };
+        let kept: Vec<bool> = records.iter().map(|record| filter.accepts(record)).collect();
+        assert_eq!(kept, [true, false, false]);
+    }
+
+    fn records(text: &str) -> Vec<(String, String)> {
+        TextRecords::new(text, "sample")
+            .map(|record| (record.get_id().to_string(), record.symbols().collect()))
+            .collect()
+    }
+
+    #[test]
+    fn text_records_read_multi_line_fasta() {
+        assert_eq!(
+            records(">r1 first read\nACGT\nAC\n\n>r2\n;comment\nGGTT\n"),
+            [("r1".to_string(), "ACGTAC".to_string()), ("r2".to_string(), "GGTT".to_string())]
+        );
+    }
+
+    #[test]
+    fn text_records_skip_fastq_qualities() {
+        // The second quality line starts with '@', like a header
+        assert_eq!(
+            records("@r1\nACGTAC\n+\nIII\n@II\n@r2\r\nGG\r\n+r2\r\nII\r\n"),
+            [("r1".to_string(), "ACGTAC".to_string()), ("r2".to_string(), "GG".to_string())]
+        );
+    }
+
+    #[test]
+    fn text_without_headers_is_one_record() {
+        assert_eq!(records("ACGT\nTTGA\n"), [("sample".to_string(), "ACGTTTGA".to_string())]);
+        assert!(records("\n\n").is_empty());
+    }
+}
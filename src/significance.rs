@@ -0,0 +1,258 @@
+use std::{fs::File, io::{self, BufWriter, Write}, str::FromStr};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use serde::Serialize;
+
+// Most symbols of a long sequence randomised at once
+const RANDOMISED_PART_LENGTH: usize = 1 << 20;
+
+/*
+ * Which side of the comparison is randomised: each
+ * reference, scored by the model of the sample, or the
+ * sample, whose randomised models score each reference
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NullTarget {
+    #[default]
+    Reference,
+    Sample,
+}
+
+impl FromStr for NullTarget {
+    type Err = String;
+
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        match target {
+            "reference" => Ok(NullTarget::Reference),
+            "sample" => Ok(NullTarget::Sample),
+            _ => Err(format!("invalid null target \"{}\", expected reference or sample", target)),
+        }
+    }
+}
+
+/*
+ * How a sequence is randomised. A shuffle keeps only the
+ * composition; shuffling blocks also keeps the order of
+ * the symbols inside each block, and so most contexts
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Randomisation {
+    #[default]
+    Shuffle,
+    Blocks(usize),                                  // block length in symbols
+}
+
+impl FromStr for Randomisation {
+    type Err = String;
+
+    fn from_str(randomisation: &str) -> Result<Self, Self::Err> {
+        match randomisation {
+            "shuffle" => Ok(Randomisation::Shuffle),
+            _ => match randomisation.strip_prefix("blocks:").map(str::parse::<usize>) {
+                Some(Ok(length)) if length > 0 => Ok(Randomisation::Blocks(length)),
+                _ => Err(format!("invalid randomisation \"{}\", expected shuffle or blocks:N with N >= 1", randomisation)),
+            },
+        }
+    }
+}
+
+impl Randomisation {
+
+    pub fn randomise(&self, sequence: &str, rng: &mut StdRng) -> String {
+        let mut symbols: Vec<char> = sequence.chars().collect();
+        match self {
+            Randomisation::Shuffle => {
+                symbols.shuffle(rng);
+                symbols.into_iter().collect()
+            }
+            Randomisation::Blocks(length) => {
+                let mut blocks: Vec<&[char]> = symbols.chunks(*length).collect();
+                blocks.shuffle(rng);
+                blocks.concat().into_iter().collect()
+            }
+        }
+    }
+
+    /*
+     * Randomises a sequence too long to be held in memory,
+     * e.g. a mapped sample, in consecutive parts of around
+     * a million symbols, passing each randomised symbol to
+     * emit. Symbols only move inside their part, which keeps
+     * whole blocks, so memory does not grow with the sequence
+    */
+    pub fn randomise_in_parts<I: Iterator<Item = char>, F: FnMut(char)>(&self, mut symbols: I, rng: &mut StdRng, mut emit: F) {
+        let part_length = match self {
+            Randomisation::Shuffle => RANDOMISED_PART_LENGTH,
+            Randomisation::Blocks(length) => RANDOMISED_PART_LENGTH.div_ceil(*length) * length,
+        };
+        let mut part = String::new();
+        loop {
+            part.clear();
+            part.extend(symbols.by_ref().take(part_length));
+            if part.is_empty() {
+                break;
+            }
+            self.randomise(&part, rng).chars().for_each(&mut emit);
+        }
+    }
+}
+
+/*
+ * Null distribution settings. Replicate r of the sequence
+ * at position i is randomised with seed + i (reference
+ * target) or seed + r (sample target), so results do not
+ * depend on the number of threads
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NullModel {
+    pub target: NullTarget,
+    pub randomisation: Randomisation,
+    pub replicates: usize,
+    pub seed: u64,
+}
+
+impl NullModel {
+
+    pub fn rng(&self, offset: usize) -> StdRng {
+        StdRng::seed_from_u64(self.seed.wrapping_add(offset as u64))
+    }
+}
+
+/*
+ * NRC of a sequence next to the NRC of its randomised
+ * replicates. A low p-value means that so low an NRC
+ * is unlikely to come from composition alone
+*/
+#[derive(Clone, Debug, Serialize)]
+pub struct Significance {
+    name: String,
+    nrc: f64,
+    null_mean: f64,
+    null_sd: f64,
+    z_score: f64,
+    p_value: f64,
+}
+
+impl Significance {
+
+    /*
+     * z-score against the mean and standard deviation of
+     * the null scores, and empirical p-value: the share of
+     * null scores at least as low, counting the NRC itself
+     * so it is never 0 and at least 1 / (replicates + 1)
+    */
+    pub fn from_null(name: &str, nrc: f64, null_scores: &[f64]) -> Self {
+        let count = null_scores.len() as f64;
+        let null_mean = if count > 0.0 { null_scores.iter().sum::<f64>() / count } else { nrc };
+        let null_sd = if count > 1.0 {
+            (null_scores.iter().map(|score| (score - null_mean).powi(2)).sum::<f64>() / (count - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+
+        let z_score = if null_sd > 0.0 {
+            (nrc - null_mean) / null_sd
+        } else if nrc < null_mean {
+            f64::NEG_INFINITY
+        } else if nrc > null_mean {
+            f64::INFINITY
+        } else {
+            0.0
+        };
+
+        let as_low = null_scores.iter().filter(|&&score| score <= nrc).count() as f64;
+        Self {
+            name: name.to_string(),
+            nrc,
+            null_mean,
+            null_sd,
+            z_score,
+            p_value: (as_low + 1.0) / (count + 1.0),
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_nrc(&self) -> f64 {
+        self.nrc
+    }
+
+    pub fn get_null_mean(&self) -> f64 {
+        self.null_mean
+    }
+
+    pub fn get_null_sd(&self) -> f64 {
+        self.null_sd
+    }
+
+    pub fn get_z_score(&self) -> f64 {
+        self.z_score
+    }
+
+    pub fn get_p_value(&self) -> f64 {
+        self.p_value
+    }
+}
+
+/*
+ * Saves the significance of every sequence as a tab separated table
+*/
+pub fn export_significance(significances: &[Significance], path: &str) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "sequence\tnrc\tnull_mean\tnull_sd\tz_score\tp_value")?;
+    for significance in significances {
+        writeln!(
+            writer,
+            "{}\t{:.6}\t{:.6}\t{:.6}\t{:.4}\t{:.6}",
+            significance.name,
+            significance.nrc,
+            significance.null_mean,
+            significance.null_sd,
+            significance.z_score,
+            significance.p_value,
+        )?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn p_value_counts_the_score_itself() {
+        let significance = Significance::from_null("a", 0.5, &[0.4, 0.6, 0.8, 1.0]);
+        assert!((significance.get_p_value() - 2.0 / 5.0).abs() < 1e-12);
+        assert!((significance.get_null_mean() - 0.7).abs() < 1e-12);
+        assert!((significance.get_null_sd() - (0.2f64 / 3.0).sqrt()).abs() < 1e-12);
+        assert!((significance.get_z_score() + 0.2 / (0.2f64 / 3.0).sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn lowest_score_has_the_lowest_p_value() {
+        let significance = Significance::from_null("a", 0.1, &[0.5; 9]);
+        assert!((significance.get_p_value() - 0.1).abs() < 1e-12);
+        assert_eq!(significance.get_z_score(), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn randomising_in_parts_keeps_the_composition_of_each_part() {
+        let sequence: String = "A".repeat(RANDOMISED_PART_LENGTH) + &"C".repeat(10);
+        let mut randomised = String::new();
+        Randomisation::Shuffle.randomise_in_parts(sequence.chars(), &mut StdRng::seed_from_u64(1), |symbol| randomised.push(symbol));
+        assert_eq!(randomised, sequence);
+
+        let sequence = "ACGTACGTAC";
+        let mut randomised = String::new();
+        Randomisation::Blocks(4).randomise_in_parts(sequence.chars(), &mut StdRng::seed_from_u64(1), |symbol| randomised.push(symbol));
+        assert_eq!(randomised, Randomisation::Blocks(4).randomise(sequence, &mut StdRng::seed_from_u64(1)));
+    }
+
+    #[test]
+    fn without_replicates_nothing_is_significant() {
+        let significance = Significance::from_null("a", 0.5, &[]);
+        assert_eq!(significance.get_p_value(), 1.0);
+        assert_eq!(significance.get_z_score(), 0.0);
+    }
+}
@@ -0,0 +1,389 @@
+use std::{collections::{HashMap, HashSet}, fs::File, io::{self, BufReader, BufWriter, Write}, path::Path};
+use serde::{Deserialize, Serialize};
+
+use crate::model_saver_loader;
+
+// Canonical k-mers are packed two bits per base into a u64
+pub const MAX_SKETCH_K: usize = 32;
+
+/*
+ * FracMinHash sketch of the DNA k-mers of a sequence: the
+ * hashes of its canonical k-mers (the lowest of the k-mer
+ * and its reverse complement) that fall under 1/scaled of
+ * the hash range. Sketches of the same k and scale can be
+ * compared whatever the length of the sequences, and about
+ * one k-mer in `scaled` is kept, so a sample that does not
+ * fit in memory as k-mers still gives a small sketch
+*/
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sketch {
+    name: String,
+    k: usize,
+    scaled: u64,
+    hashes: Vec<u64>,                               // sorted, without duplicates
+}
+
+impl Sketch {
+
+    /*
+     * Sketches a sequence of DNA or RNA symbols, in either
+     * case. K-mers with other symbols, e.g. N, are skipped
+    */
+    pub fn from_symbols<I: IntoIterator<Item = char>>(name: &str, symbols: I, k: usize, scaled: u64) -> Self {
+        assert!((1..=MAX_SKETCH_K).contains(&k), "sketch k must be between 1 and {}", MAX_SKETCH_K);
+        let mask = if k == MAX_SKETCH_K { u64::MAX } else { (1u64 << (2 * k)) - 1 };
+        let max_hash = u64::MAX / scaled.max(1);
+
+        let mut hashes = Vec::new();
+        let mut forward = 0u64;
+        let mut reverse = 0u64;
+        let mut valid = 0;                          // bases of the current k-mer read so far
+
+        for symbol in symbols {
+            let code = match symbol {
+                'A' | 'a' => 0,
+                'C' | 'c' => 1,
+                'G' | 'g' => 2,
+                'T' | 't' | 'U' | 'u' => 3,
+                _ => {
+                    valid = 0;
+                    continue;
+                }
+            };
+
+            forward = ((forward << 2) | code) & mask;
+            reverse = (reverse >> 2) | ((3 - code) << (2 * (k - 1)));
+            valid += 1;
+
+            if valid >= k {
+                let hash = mix(forward.min(reverse));
+                if hash <= max_hash {
+                    hashes.push(hash);
+                }
+            }
+        }
+
+        hashes.sort_unstable();
+        hashes.dedup();
+        Self {
+            name: name.to_string(),
+            k,
+            scaled,
+            hashes,
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_k(&self) -> usize {
+        self.k
+    }
+
+    pub fn get_scaled(&self) -> u64 {
+        self.scaled
+    }
+
+    pub fn get_hashes(&self) -> &[u64] {
+        &self.hashes
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /*
+     * Number of hashes in both sketches
+    */
+    pub fn shared(&self, other: &Sketch) -> usize {
+        let (mut i, mut j, mut shared) = (0, 0, 0);
+        while i < self.hashes.len() && j < other.hashes.len() {
+            match self.hashes[i].cmp(&other.hashes[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    shared += 1;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        shared
+    }
+
+    /*
+     * Estimate of the Jaccard index of the k-mer sets:
+     * shared k-mers over the k-mers of either sequence
+    */
+    pub fn jaccard(&self, other: &Sketch) -> f64 {
+        let shared = self.shared(other);
+        let union = self.len() + other.len() - shared;
+        if union > 0 {
+            shared as f64 / union as f64
+        } else {
+            0.0
+        }
+    }
+
+    /*
+     * Estimate of the share of the k-mers of this sequence
+     * found in the other one, e.g. of a reference in a sample
+    */
+    pub fn containment(&self, other: &Sketch) -> f64 {
+        if self.is_empty() {
+            0.0
+        } else {
+            self.shared(other) as f64 / self.len() as f64
+        }
+    }
+}
+
+/*
+ * How much of a reference the sample contains
+*/
+#[derive(Clone, Debug, Serialize)]
+pub struct SketchScore {
+    name: String,
+    containment: f64,                               // of the reference in the sample
+    jaccard: f64,
+    shared_hashes: usize,
+}
+
+impl SketchScore {
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_containment(&self) -> f64 {
+        self.containment
+    }
+
+    pub fn get_jaccard(&self) -> f64 {
+        self.jaccard
+    }
+
+    pub fn get_shared_hashes(&self) -> usize {
+        self.shared_hashes
+    }
+}
+
+/*
+ * Scores the references against the sample, from the one
+ * most contained in it. Ties are broken by the Jaccard
+ * index, then by the order of the references
+*/
+pub fn rank_by_containment(sample: &Sketch, references: &[Sketch]) -> Vec<SketchScore> {
+    let mut scores: Vec<SketchScore> = references.iter()
+        .map(|reference| SketchScore {
+            name: reference.name.clone(),
+            containment: reference.containment(sample),
+            jaccard: reference.jaccard(sample),
+            shared_hashes: reference.shared(sample),
+        })
+        .collect();
+    scores.sort_by(|a, b| b.containment.total_cmp(&a.containment).then(b.jaccard.total_cmp(&a.jaccard)));
+    scores
+}
+
+/*
+ * Sketch of a record with the hash of the sequence it was
+ * computed from, to know when the record has changed
+*/
+#[derive(Serialize, Deserialize)]
+struct CachedSketch {
+    sequence_hash: String,
+    sketch: Sketch,
+}
+
+/*
+ * Sketches of the records of a database, saved as JSON. A
+ * sketch is reused while its record ID has the same sequence,
+ * as in the model index, so a changed record does not throw
+ * away the others. A cache of another k or scale starts empty
+*/
+#[derive(Serialize, Deserialize)]
+pub struct SketchCache {
+    k: usize,
+    scaled: u64,
+    sketches: HashMap<String, CachedSketch>,        // by record ID
+    #[serde(skip)]
+    modified: bool,
+}
+
+impl SketchCache {
+
+    /*
+     * Cache kept in memory only, for runs without a cache file
+    */
+    pub fn new(k: usize, scaled: u64) -> Self {
+        Self {
+            k,
+            scaled,
+            sketches: HashMap::new(),
+            modified: false,
+        }
+    }
+
+    /*
+     * Reads the cache file if it exists and has the same k and
+     * scale. A cache that can not be parsed is rebuilt
+    */
+    pub fn open(path: &str, k: usize, scaled: u64) -> io::Result<Self> {
+        if Path::new(path).exists() {
+            let cached: Option<SketchCache> = serde_json::from_reader(BufReader::new(File::open(path)?)).ok();
+            if let Some(cache) = cached.filter(|cache| cache.k == k && cache.scaled == scaled) {
+                return Ok(cache);
+            }
+        }
+
+        Ok(Self {
+            modified: true,
+            ..Self::new(k, scaled)
+        })
+    }
+
+    pub fn get_k(&self) -> usize {
+        self.k
+    }
+
+    pub fn get_scaled(&self) -> u64 {
+        self.scaled
+    }
+
+    /*
+     * Hash of a sequence, as given to get and insert. It is
+     * computed once per record and run
+    */
+    pub fn sequence_hash(sequence: &str) -> String {
+        model_saver_loader::hash_bytes(sequence.as_bytes())
+    }
+
+    /*
+     * Sketch of the record, if it was computed from the sequence
+     * with this hash
+    */
+    pub fn get(&self, name: &str, sequence_hash: &str) -> Option<&Sketch> {
+        self.sketches.get(name)
+            .filter(|cached| cached.sequence_hash == sequence_hash)
+            .map(|cached| &cached.sketch)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sketches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sketches.is_empty()
+    }
+
+    /*
+     * Adds the sketch of a record, replacing the one of an
+     * older sequence with the same ID
+    */
+    pub fn insert(&mut self, sketch: Sketch, sequence_hash: String) {
+        self.sketches.insert(sketch.name.clone(), CachedSketch { sequence_hash, sketch });
+        self.modified = true;
+    }
+
+    /*
+     * Drops the sketches of records not in names, e.g. records
+     * removed from the database, so the file does not keep them
+    */
+    pub fn retain(&mut self, names: &HashSet<&str>) {
+        let len = self.sketches.len();
+        self.sketches.retain(|name, _| names.contains(name.as_str()));
+        self.modified |= self.sketches.len() != len;
+    }
+
+    /*
+     * Whether sketches were added or dropped since the cache
+     * was read
+    */
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()
+    }
+}
+
+/*
+ * SplitMix64 finaliser, so that hashes are spread evenly
+ * and the same on every run and platform
+*/
+fn mix(mut value: u64) -> u64 {
+    value ^= value >> 30;
+    value = value.wrapping_mul(0xbf58476d1ce4e5b9);
+    value ^= value >> 27;
+    value = value.wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverse_complement_gives_the_same_sketch() {
+        let forward = Sketch::from_symbols("forward", "AACCGGTTACGATC".chars(), 5, 1);
+        let reverse = Sketch::from_symbols("reverse", "GATCGTAACCGGTT".chars(), 5, 1);
+        assert_eq!(forward.get_hashes(), reverse.get_hashes());
+        assert_eq!(forward.containment(&reverse), 1.0);
+    }
+
+    #[test]
+    fn case_and_rna_do_not_change_the_kmers() {
+        let dna = Sketch::from_symbols("dna", "ACGTTGCA".chars(), 4, 1);
+        let rna = Sketch::from_symbols("rna", "acguugca".chars(), 4, 1);
+        assert_eq!(dna.get_hashes(), rna.get_hashes());
+    }
+
+    #[test]
+    fn other_symbols_break_the_kmers() {
+        // ACG, its reverse complement CGT and nothing across the N
+        let sketch = Sketch::from_symbols("n", "ACGNCGT".chars(), 3, 1);
+        assert_eq!(sketch.len(), 1);
+        assert!(Sketch::from_symbols("short", "ACNGT".chars(), 3, 1).is_empty());
+    }
+
+    #[test]
+    fn cached_sketches_follow_their_sequence() {
+        let mut cache = SketchCache::new(3, 1);
+        let hash = SketchCache::sequence_hash;
+        cache.insert(Sketch::from_symbols("a", "ACGTAC".chars(), 3, 1), hash("ACGTAC"));
+        cache.insert(Sketch::from_symbols("b", "TTGACA".chars(), 3, 1), hash("TTGACA"));
+
+        assert!(cache.get("a", &hash("ACGTAC")).is_some());
+        assert!(cache.get("b", &hash("TTGACG")).is_none());
+        assert!(cache.get("c", &hash("ACGTAC")).is_none());
+
+        cache.insert(Sketch::from_symbols("b", "TTGACG".chars(), 3, 1), hash("TTGACG"));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("b", &hash("TTGACG")).is_some());
+    }
+
+    #[test]
+    fn removed_records_are_dropped_from_the_cache() {
+        let mut cache = SketchCache::new(3, 1);
+        cache.insert(Sketch::from_symbols("a", "ACGTAC".chars(), 3, 1), SketchCache::sequence_hash("ACGTAC"));
+        cache.insert(Sketch::from_symbols("b", "TTGACA".chars(), 3, 1), SketchCache::sequence_hash("TTGACA"));
+        cache.modified = false;
+
+        cache.retain(&HashSet::from(["a", "b"]));
+        assert!(!cache.is_modified());
+
+        cache.retain(&HashSet::from(["a"]));
+        assert!(cache.is_modified());
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("b", &SketchCache::sequence_hash("TTGACA")).is_none());
+    }
+}
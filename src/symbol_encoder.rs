@@ -0,0 +1,244 @@
+use std::{collections::HashMap, str::FromStr};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Supplementary Private Use Area-A, where grapheme clusters get their symbols
+const FIRST_CLUSTER_SYMBOL: u32 = 0xF0000;
+const LAST_CLUSTER_SYMBOL: u32 = 0xFFFFD;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Normalization {
+    #[default]
+    None,
+    Nfc,                                            // composed, e.g. "é" is one scalar value
+    Nfd,                                            // decomposed, e.g. "é" is "e" + U+0301
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(normalization: &str) -> Result<Self, Self::Err> {
+        match normalization {
+            "none" => Ok(Normalization::None),
+            "nfc" => Ok(Normalization::Nfc),
+            "nfd" => Ok(Normalization::Nfd),
+            _ => Err(format!("invalid normalization \"{}\", expected none, nfc or nfd", normalization)),
+        }
+    }
+}
+
+/*
+ * How the text is turned into the symbols
+ * that character models are trained on
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SymbolOptions {
+    pub normalization: Normalization,
+    pub case_fold: bool,
+    pub graphemes: bool,                            // one symbol per grapheme cluster instead of per scalar value
+}
+
+/*
+ * Turns text into model symbols. Character models work on
+ * chars, so a grapheme cluster of several scalar values is
+ * given a char of the private use area and mapped back by
+ * decode. Chars of that area found in the text get a symbol
+ * of their own too, so decode never mistakes them for a
+ * cluster. Text is encoded in chunks: the last cluster of a
+ * chunk may continue in the next one, so it is held back
+ * until more text arrives or finish is called
+*/
+pub struct SymbolEncoder {
+    options: SymbolOptions,
+    pending: String,
+    clusters: Vec<String>,                          // clusters[i] has the symbol FIRST_CLUSTER_SYMBOL + i
+    cluster_symbols: HashMap<String, char>,
+}
+
+impl SymbolEncoder {
+
+    pub fn new(options: SymbolOptions) -> Self {
+        Self {
+            options,
+            pending: String::new(),
+            clusters: Vec::new(),
+            cluster_symbols: HashMap::new(),
+        }
+    }
+
+    /*
+     * Whether encoding leaves the text unchanged
+    */
+    pub fn is_identity(&self) -> bool {
+        self.options.normalization == Normalization::None && !self.options.case_fold && !self.options.graphemes
+    }
+
+    /*
+     * Encodes the complete clusters of the text seen so far
+    */
+    pub fn encode(&mut self, text: &str) -> String {
+        self.pending.push_str(text);
+
+        // Composition and case folding never cross a grapheme cluster boundary
+        let split = match self.pending.grapheme_indices(true).next_back() {
+            Some((index, _)) => index,
+            None => return String::new(),
+        };
+        let complete: String = self.pending.drain(..split).collect();
+        self.encode_complete(&complete)
+    }
+
+    /*
+     * Encodes the text held back at the end of the input
+    */
+    pub fn finish(&mut self) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        self.encode_complete(&rest)
+    }
+
+    pub fn encode_all(&mut self, text: &str) -> String {
+        let mut encoded = self.encode(text);
+        encoded.push_str(&self.finish());
+        encoded
+    }
+
+    /*
+     * Maps cluster symbols back to their clusters
+    */
+    pub fn decode(&self, symbols: &str) -> String {
+        symbols.chars()
+            .map(|symbol| {
+                if is_cluster_symbol(symbol) {
+                    if let Some(cluster) = self.clusters.get((symbol as u32 - FIRST_CLUSTER_SYMBOL) as usize) {
+                        return cluster.clone();
+                    }
+                }
+                symbol.to_string()
+            })
+            .collect()
+    }
+
+    /*
+     * Number of grapheme clusters of several scalar
+     * values that were given their own symbol
+    */
+    pub fn get_cluster_count(&self) -> usize {
+        self.clusters.len()
+    }
+
+    /*
+     * Clusters in the order of their symbols, to be saved
+     * with a model trained on them
+    */
+    pub fn get_clusters(&self) -> &[String] {
+        &self.clusters
+    }
+
+    /*
+     * Gives the clusters of a saved model the symbols
+     * they had when it was trained
+    */
+    pub fn set_clusters(&mut self, clusters: Vec<String>) {
+        self.cluster_symbols = clusters.iter()
+            .enumerate()
+            .filter_map(|(i, cluster)| char::from_u32(FIRST_CLUSTER_SYMBOL + i as u32).map(|symbol| (cluster.clone(), symbol)))
+            .collect();
+        self.clusters = clusters;
+    }
+
+    fn encode_complete(&mut self, text: &str) -> String {
+        // Case folding can produce decomposed sequences, so normalization comes after it
+        let folded: String = if self.options.case_fold {
+            text.chars().flat_map(char::to_lowercase).collect()
+        } else {
+            text.to_string()
+        };
+
+        let normalized: String = match self.options.normalization {
+            Normalization::None => folded,
+            Normalization::Nfc => folded.nfc().collect(),
+            Normalization::Nfd => folded.nfd().collect(),
+        };
+
+        if !self.options.graphemes {
+            return normalized;
+        }
+
+        let mut encoded = String::with_capacity(normalized.len());
+        for cluster in normalized.graphemes(true) {
+            let mut chars = cluster.chars();
+            match (chars.next(), chars.next()) {
+                (Some(symbol), None) if !is_cluster_symbol(symbol) => encoded.push(symbol),
+                _ => match self.cluster_symbol(cluster) {
+                    Some(symbol) => encoded.push(symbol),
+                    None => encoded.push_str(cluster), // no symbols left, keep its scalar values
+                },
+            }
+        }
+        encoded
+    }
+
+    fn cluster_symbol(&mut self, cluster: &str) -> Option<char> {
+        if let Some(&symbol) = self.cluster_symbols.get(cluster) {
+            return Some(symbol);
+        }
+
+        let symbol = char::from_u32(FIRST_CLUSTER_SYMBOL + self.clusters.len() as u32)
+            .filter(|&symbol| symbol as u32 <= LAST_CLUSTER_SYMBOL)?;
+        self.clusters.push(cluster.to_string());
+        self.cluster_symbols.insert(cluster.to_string(), symbol);
+        Some(symbol)
+    }
+}
+
+fn is_cluster_symbol(symbol: char) -> bool {
+    (FIRST_CLUSTER_SYMBOL..=LAST_CLUSTER_SYMBOL).contains(&(symbol as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grapheme_encoder() -> SymbolEncoder {
+        SymbolEncoder::new(SymbolOptions { graphemes: true, ..Default::default() })
+    }
+
+    #[test]
+    fn clusters_round_trip_through_their_symbols() {
+        let mut encoder = grapheme_encoder();
+        let text = "cafe\u{301} na\u{308}ive cafe\u{301}";
+        let encoded = encoder.encode_all(text);
+        assert_eq!(encoded.chars().count(), text.chars().count() - 3);
+        assert_eq!(encoder.get_cluster_count(), 2);
+        assert_eq!(encoder.decode(&encoded), text);
+    }
+
+    #[test]
+    fn private_use_input_is_not_mistaken_for_a_cluster() {
+        let mut encoder = grapheme_encoder();
+        let text = "e\u{301} \u{F0000} \u{F0001}";
+        let encoded = encoder.encode_all(text);
+        assert_eq!(encoder.decode(&encoded), text);
+    }
+
+    #[test]
+    fn restored_clusters_decode_a_saved_model() {
+        let mut encoder = grapheme_encoder();
+        let encoded = encoder.encode_all("a\u{308}o\u{308}");
+
+        let mut restored = grapheme_encoder();
+        restored.set_clusters(encoder.get_clusters().to_vec());
+        assert_eq!(restored.decode(&encoded), "a\u{308}o\u{308}");
+        assert_eq!(restored.encode_all("o\u{308}"), encoder.encode_all("o\u{308}"));
+    }
+
+    #[test]
+    fn clusters_split_between_chunks_are_held_back() {
+        let mut encoder = grapheme_encoder();
+        let mut encoded = encoder.encode("cafe");
+        encoded.push_str(&encoder.encode("\u{301}!"));
+        encoded.push_str(&encoder.finish());
+        assert_eq!(encoded.chars().count(), 5);
+        assert_eq!(encoder.decode(&encoded), "cafe\u{301}!");
+    }
+}
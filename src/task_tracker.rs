@@ -0,0 +1,108 @@
+use std::{io::Write, sync::atomic::{AtomicU64, AtomicUsize, Ordering}, time::{Duration, Instant}};
+
+// Tasks between two updates of the progress when their number is not known
+const UNKNOWN_TOTAL_STEP: usize = 1000;
+
+/*
+ * Follows tasks run on any number of threads: counts the
+ * finished ones to print the progress and adds up the time
+ * spent in them, about the time they would take one after
+ * the other. Over the wall-clock time it estimates the speedup
+*/
+pub struct TaskTracker {
+    label: String,
+    total: usize,
+    show_progress: bool,
+    finished: AtomicUsize,
+    busy_nanos: AtomicU64,
+    started: Instant,
+}
+
+impl TaskTracker {
+
+    /*
+     * Tracker of total tasks, 0 when the number is not known
+     * in advance, e.g. reads classified as they are read
+    */
+    pub fn new(label: &str, total: usize, show_progress: bool) -> Self {
+        Self {
+            label: label.to_string(),
+            total,
+            show_progress,
+            finished: AtomicUsize::new(0),
+            busy_nanos: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /*
+     * Runs one task, timing it and reporting the progress
+    */
+    pub fn run<T, F: FnOnce() -> T>(&self, task: F) -> T {
+        let task_start = Instant::now();
+        let result = task();
+        self.busy_nanos.fetch_add(task_start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+
+        let finished = self.finished.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.show_progress {
+            self.print_progress(finished);
+        }
+        result
+    }
+
+    /*
+     * Ends the progress line
+    */
+    pub fn finish(&self) {
+        if self.show_progress && self.get_finished() > 0 {
+            eprintln!();
+        }
+    }
+
+    pub fn get_finished(&self) -> usize {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    pub fn get_wall_time(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /*
+     * Time spent in the tasks, added over all threads
+    */
+    pub fn get_busy_time(&self) -> Duration {
+        Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed))
+    }
+
+    /*
+     * Busy time over wall-clock time: the speedup over running
+     * the tasks one after the other, taking each task as long
+     * as it took here. Tasks slowed down by sharing memory or
+     * cores make it higher than the real speedup
+    */
+    pub fn estimated_speedup(&self) -> f64 {
+        let wall = self.get_wall_time().as_secs_f64();
+        if wall > 0.0 {
+            self.get_busy_time().as_secs_f64() / wall
+        } else {
+            1.0
+        }
+    }
+
+    fn print_progress(&self, finished: usize) {
+        if self.total == 0 {
+            if finished.is_multiple_of(UNKNOWN_TOTAL_STEP) {
+                eprint!("\r{}: {}", self.label, finished);
+                let _ = std::io::stderr().flush();
+            }
+            return;
+        }
+
+        // Around a hundred updates, whatever the number of tasks
+        let step = (self.total / 100).max(1);
+        if finished.is_multiple_of(step) || finished == self.total {
+            eprint!("\r{}: {}/{} ({:.0}%)", self.label, finished, self.total, 100.0 * finished as f64 / self.total.max(1) as f64);
+            let _ = std::io::stderr().flush();
+        }
+    }
+}
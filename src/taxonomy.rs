@@ -0,0 +1,382 @@
+use std::{collections::HashMap, fs::File, io::{self, BufRead, BufReader}, str::FromStr, sync::OnceLock};
+use serde::Serialize;
+
+const UNCLASSIFIED: &str = "unclassified";
+
+/*
+ * What the hits of the database are grouped by
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub enum GroupBy {
+    Accession,                                      // e.g. NC_045512.2
+    Organism,                                       // description up to the first comma, or the [organism] of the header
+    Field(usize),                                   // n-th field of the header (from 1), split at whitespace and '|'
+}
+
+impl FromStr for GroupBy {
+    type Err = String;
+
+    fn from_str(group_by: &str) -> Result<Self, Self::Err> {
+        match group_by {
+            "accession" => Ok(GroupBy::Accession),
+            "organism" => Ok(GroupBy::Organism),
+            _ => match group_by.strip_prefix("field:").map(str::parse::<usize>) {
+                Some(Ok(field)) if field > 0 => Ok(GroupBy::Field(field)),
+                _ => Err(format!("invalid grouping \"{}\", expected accession, organism or field:N with N >= 1", group_by)),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for GroupBy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GroupBy::Accession => write!(f, "accession"),
+            GroupBy::Organism => write!(f, "organism"),
+            GroupBy::Field(field) => write!(f, "field:{}", field),
+        }
+    }
+}
+
+impl GroupBy {
+
+    /*
+     * Group of a record, from its ID and description
+    */
+    pub fn key(&self, id: &str, description: &str) -> String {
+        let header = format!("{} {}", id, description);
+        match self {
+            GroupBy::Accession => extract_accession(&header),
+            GroupBy::Organism => extract_organism(description).unwrap_or_else(|| id.to_string()),
+            GroupBy::Field(field) => header.split(|c: char| c.is_whitespace() || c == '|')
+                .filter(|part| !part.is_empty())
+                .nth(field - 1)
+                .unwrap_or(UNCLASSIFIED)
+                .to_string(),
+        }
+    }
+}
+
+/*
+ * NRC of the sequences of one group. A low
+ * NRC means the group is likely in the sample
+*/
+#[derive(Clone, Debug, Serialize)]
+pub struct GroupScore {
+    group: String,
+    best_nrc: f64,
+    best_sequence: String,
+    mean_nrc: f64,
+    sequence_count: usize,
+}
+
+impl GroupScore {
+
+    pub fn get_group(&self) -> &str {
+        &self.group
+    }
+
+    pub fn get_best_nrc(&self) -> f64 {
+        self.best_nrc
+    }
+
+    pub fn get_best_sequence(&self) -> &str {
+        &self.best_sequence
+    }
+
+    pub fn get_mean_nrc(&self) -> f64 {
+        self.mean_nrc
+    }
+
+    pub fn get_sequence_count(&self) -> usize {
+        self.sequence_count
+    }
+}
+
+/*
+ * Scores of every taxon of one rank, e.g. every genus
+*/
+#[derive(Clone, Debug, Serialize)]
+pub struct RankScores {
+    rank: String,
+    groups: Vec<GroupScore>,
+}
+
+impl RankScores {
+
+    pub fn get_rank(&self) -> &str {
+        &self.rank
+    }
+
+    pub fn get_groups(&self) -> &[GroupScore] {
+        &self.groups
+    }
+}
+
+/*
+ * Local taxonomy, read from a tab separated file whose
+ * header names the ranks after the accession column:
+ *
+ *   accession    species                genus            family
+ *   NC_045512.2  Severe acute ...       Betacoronavirus  Coronaviridae
+ *
+ * Accessions are matched with and without their version.
+ * A taxon missing from a lineage is inherited through its
+ * child: it is the parent that the taxon of the rank below
+ * has in another lineage, so the ranks stay nested
+*/
+pub struct TaxonomyMap {
+    ranks: Vec<String>,
+    lineages: HashMap<String, Vec<String>>,         // taxa by accession, in the order of ranks
+}
+
+impl TaxonomyMap {
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?), path)
+    }
+
+    /*
+     * Taxonomy read from any reader, source names it in errors
+    */
+    pub fn from_reader<R: BufRead>(reader: R, source: &str) -> io::Result<Self> {
+        let mut lines = reader.lines();
+
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let ranks: Vec<String> = header.split('\t').skip(1).map(|rank| rank.trim().to_string()).collect();
+        if ranks.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: the header must name the accession column and at least one rank", source),
+            ));
+        }
+
+        let mut lineages = HashMap::new();
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split('\t').map(str::trim);
+            let accession = fields.next().unwrap_or_default().to_string();
+            let lineage: Vec<String> = (0..ranks.len())
+                .map(|_| match fields.next() {
+                    Some(taxon) if !taxon.is_empty() => taxon.to_string(),
+                    _ => UNCLASSIFIED.to_string(),
+                })
+                .collect();
+
+            // Also found without the version, unless another version is listed without it
+            if let Some((unversioned, _)) = accession.split_once('.') {
+                lineages.entry(unversioned.to_string()).or_insert_with(|| lineage.clone());
+            }
+            lineages.insert(accession, lineage);
+        }
+
+        inherit_missing_taxa(&mut lineages, ranks.len());
+        Ok(Self { ranks, lineages })
+    }
+
+    pub fn get_ranks(&self) -> &[String] {
+        &self.ranks
+    }
+
+    pub fn get_lineage(&self, accession: &str) -> Option<&Vec<String>> {
+        self.lineages.get(accession).or_else(|| {
+            let (unversioned, _) = accession.split_once('.')?;
+            self.lineages.get(unversioned)
+        })
+    }
+
+    /*
+     * Taxon of an accession at one rank of the map
+    */
+    pub fn taxon(&self, accession: &str, rank: usize) -> String {
+        self.get_lineage(accession)
+            .and_then(|lineage| lineage.get(rank).cloned())
+            .unwrap_or_else(|| UNCLASSIFIED.to_string())
+    }
+}
+
+/*
+ * Fills the unclassified taxa of the lineages, from the lowest
+ * rank up, with the parent of their child taxon given by the
+ * first lineage, in the order of the accessions, that has both
+*/
+fn inherit_missing_taxa(lineages: &mut HashMap<String, Vec<String>>, rank_count: usize) {
+    let mut accessions: Vec<String> = lineages.keys().cloned().collect();
+    accessions.sort();
+
+    for rank in 1..rank_count {
+        let mut parents: HashMap<String, String> = HashMap::new();
+        for accession in &accessions {
+            let lineage = &lineages[accession];
+            if lineage[rank - 1] != UNCLASSIFIED && lineage[rank] != UNCLASSIFIED {
+                parents.entry(lineage[rank - 1].clone()).or_insert_with(|| lineage[rank].clone());
+            }
+        }
+
+        for lineage in lineages.values_mut() {
+            if lineage[rank] == UNCLASSIFIED {
+                if let Some(parent) = parents.get(&lineage[rank - 1]) {
+                    lineage[rank] = parent.clone();
+                }
+            }
+        }
+    }
+}
+
+/*
+ * Groups NRC scores by the key of each sequence, from the
+ * best group. Ties keep the order of the scores, so ranked
+ * scores give the same groups on every run
+*/
+pub fn group_scores<F: Fn(&str) -> String>(nrc_scores: &[(String, f64)], key: F) -> Vec<GroupScore> {
+    let mut groups: Vec<GroupScore> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for (name, nrc_score) in nrc_scores {
+        let group = key(name);
+        match positions.get(&group) {
+            Some(&position) => {
+                let score = &mut groups[position];
+                if *nrc_score < score.best_nrc {
+                    score.best_nrc = *nrc_score;
+                    score.best_sequence = name.clone();
+                }
+                score.mean_nrc += nrc_score;
+                score.sequence_count += 1;
+            }
+            None => {
+                positions.insert(group.clone(), groups.len());
+                groups.push(GroupScore {
+                    group,
+                    best_nrc: *nrc_score,
+                    best_sequence: name.clone(),
+                    mean_nrc: *nrc_score,
+                    sequence_count: 1,
+                });
+            }
+        }
+    }
+
+    for score in groups.iter_mut() {
+        score.mean_nrc /= score.sequence_count as f64;
+    }
+    groups.sort_by(|a, b| a.best_nrc.total_cmp(&b.best_nrc));
+    groups
+}
+
+/*
+ * Groups NRC scores at every rank of the taxonomy, from
+ * the lowest rank of the file (e.g. species) to the highest
+*/
+pub fn rollup<F: Fn(&str) -> String>(nrc_scores: &[(String, f64)], taxonomy: &TaxonomyMap, accession: F) -> Vec<RankScores> {
+    taxonomy.get_ranks().iter()
+        .enumerate()
+        .map(|(rank_index, rank)| RankScores {
+            rank: rank.clone(),
+            groups: group_scores(nrc_scores, |name| taxonomy.taxon(&accession(name), rank_index)),
+        })
+        .collect()
+}
+
+/*
+ * Accession of a header: an NCBI-like NC_/OR_ identifier,
+ * the ID of a ref|ID| header or else its first word
+*/
+pub fn extract_accession(header: &str) -> String {
+    static ACCESSION: OnceLock<regex::Regex> = OnceLock::new();
+    let re = ACCESSION.get_or_init(|| regex::Regex::new(r"(NC|OR|Super)[_\d]+(?:\.\d+)?").unwrap());
+
+    if let Some(captures) = re.find(header) {
+        return captures.as_str().to_string();
+    }
+
+    // Fallback: look for ref|ID| format
+    if let Some(start) = header.find("ref|") {
+        if let Some(end) = header[start + 4..].find('|') {
+            return header[start + 4..start + 4 + end].to_string();
+        }
+    }
+
+    // Otherwise, fallback to first word
+    header.trim_start_matches(['@', '>'])
+        .split_whitespace()
+        .next()
+        .unwrap_or("Unknown")
+        .to_string()
+}
+
+/*
+ * Organism named by a description, e.g. "Human coronavirus
+ * NL63, complete genome" or "... protein [Escherichia coli]"
+*/
+fn extract_organism(description: &str) -> Option<String> {
+    if let (Some(start), Some(end)) = (description.rfind('['), description.rfind(']')) {
+        if start < end {
+            return Some(description[start + 1..end].trim().to_string());
+        }
+    }
+
+    let organism = description.split(',').next()?.trim();
+    if organism.is_empty() {
+        None
+    } else {
+        Some(organism.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAXONOMY: &str = "accession\tspecies\tgenus\tfamily\n\
+        # comment\n\
+        NC_045512.2\tSARS-CoV-2\tBetacoronavirus\tCoronaviridae\n\
+        NC_004718.3\tSARS-CoV-2\t\t\n\
+        NC_005831.2\tHCoV-NL63\n\
+        \n";
+
+    fn taxonomy() -> TaxonomyMap {
+        TaxonomyMap::from_reader(TAXONOMY.as_bytes(), "test").unwrap()
+    }
+
+    #[test]
+    fn ranks_and_lineages_are_read() {
+        let taxonomy = taxonomy();
+        assert_eq!(taxonomy.get_ranks(), ["species", "genus", "family"]);
+        assert_eq!(taxonomy.taxon("NC_045512.2", 0), "SARS-CoV-2");
+        assert_eq!(taxonomy.taxon("NC_045512", 1), "Betacoronavirus");
+        assert_eq!(taxonomy.taxon("NC_045512.9", 2), "Coronaviridae");
+        assert_eq!(taxonomy.taxon("NC_000001.1", 0), UNCLASSIFIED);
+    }
+
+    #[test]
+    fn missing_taxa_are_inherited_through_their_child() {
+        let taxonomy = taxonomy();
+        assert_eq!(taxonomy.get_lineage("NC_004718.3").unwrap(), &["SARS-CoV-2", "Betacoronavirus", "Coronaviridae"]);
+        assert_eq!(taxonomy.get_lineage("NC_005831.2").unwrap(), &["HCoV-NL63", UNCLASSIFIED, UNCLASSIFIED]);
+    }
+
+    #[test]
+    fn a_header_without_ranks_is_an_error() {
+        assert!(TaxonomyMap::from_reader("accession\n".as_bytes(), "test").is_err());
+    }
+
+    #[test]
+    fn rollup_groups_every_rank() {
+        let scores = vec![("a".to_string(), 0.2), ("b".to_string(), 0.4), ("c".to_string(), 0.3)];
+        let accessions: HashMap<&str, &str> = [("a", "NC_045512.2"), ("b", "NC_004718.3"), ("c", "NC_005831.2")].into();
+        let rollup = rollup(&scores, &taxonomy(), |name| accessions[name].to_string());
+
+        let genus = rollup[1].get_groups();
+        assert_eq!(genus.len(), 2);
+        assert_eq!(genus[0].get_group(), "Betacoronavirus");
+        assert_eq!(genus[0].get_best_sequence(), "a");
+        assert_eq!(genus[0].get_sequence_count(), 2);
+        assert!((genus[0].get_mean_nrc() - 0.3).abs() < 1e-12);
+        assert_eq!(genus[1].get_group(), UNCLASSIFIED);
+    }
+}
@@ -1,44 +1,158 @@
-use crate::{finite_context_model::FiniteContextModel, finite_context_model_words::FiniteContextModelWords};
-use std::collections::HashMap;
+use crate::{finite_context_model_backoff::FiniteContextModelBackoff, finite_context_model_words::FiniteContextModelWords};
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt, hash::Hash};
+use rand::Rng;
 
 extern crate rand;
 
-pub fn generate_text(models: HashMap<usize, FiniteContextModel>, seed: &str, length: usize, original_k: usize) -> String {
-    let mut model: &FiniteContextModel;
-    if seed.len() < original_k{
-        model = models.get(&seed.len()).unwrap();
-    }else {
-        model = models.get(&original_k).unwrap();
+/*
+ * Conditions that end character generation early
+ * and restrictions on the symbols it may emit
+*/
+#[derive(Default)]
+pub struct GenerationConstraints {
+    pub stop_sequences: Vec<String>,                // stop once the output ends with any of these
+    pub max_sentences: Option<usize>,               // stop after this many '.', '!' or '?'
+    pub max_lines: Option<usize>,                   // stop after this many '\n'
+    pub forbidden_symbols: HashSet<char>,           // symbols that are never emitted
+    pub alphabet: Option<HashSet<char>>,            // if set, only these symbols are emitted
+}
+
+impl GenerationConstraints {
+    fn allows(&self, symbol: char) -> bool {
+        !self.forbidden_symbols.contains(&symbol)
+            && self.alphabet.as_ref().is_none_or(|alphabet| alphabet.contains(&symbol))
     }
+}
+
+/*
+ * Why constrained generation stopped
+*/
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    Length,
+    StopSequence(String),
+    MaxSentences,
+    MaxLines,
+    NoAllowedSymbol,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Length => write!(f, "reached the requested length"),
+            StopReason::StopSequence(sequence) => write!(f, "generated the stop sequence {:?}", sequence),
+            StopReason::MaxSentences => write!(f, "reached the maximum number of sentences"),
+            StopReason::MaxLines => write!(f, "reached the maximum number of lines"),
+            StopReason::NoAllowedSymbol => write!(f, "no allowed symbol can follow the context"),
+        }
+    }
+}
+
+pub struct GenerationResult {
+    pub text: String,
+    pub stop_reason: StopReason,
+}
+
+/*
+ * Weights of each model in a mixture, either fixed
+ * or interpolated linearly over the output length
+*/
+pub enum WeightSchedule {
+    Fixed(Vec<f64>),
+    Linear { start: Vec<f64>, end: Vec<f64> },
+}
+
+impl WeightSchedule {
+    /*
+     * Normalised weights to use when generating
+     * the symbol at position `step` of `length`
+    */
+    pub fn weights_at(&self, step: usize, length: usize) -> Vec<f64> {
+        let weights: Vec<f64> = match self {
+            WeightSchedule::Fixed(weights) => weights.clone(),
+            WeightSchedule::Linear { start, end } => {
+                let t = if length > 1 { step as f64 / (length - 1) as f64 } else { 0.0 };
+                start.iter().zip(end).map(|(s, e)| s + (e - s) * t).collect()
+            }
+        };
+
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return vec![1.0 / weights.len() as f64; weights.len()];
+        }
+        weights.iter().map(|w| w / total).collect()
+    }
+}
+
+/*
+ * A continuation of the prior found by beam search,
+ * with the total information (in bits) the model
+ * assigns to the generated symbols
+*/
+pub struct Continuation {
+    pub text: String,
+    pub bits: f64,
+}
+
+pub fn generate_text(model: &FiniteContextModelBackoff, seed: &str, length: usize) -> String {
+    generate_text_constrained(model, seed, length, &GenerationConstraints::default()).text
+}
+
+/*
+ * Generates at most `length` characters after the seed,
+ * stopping early when any of the constraints is met. A
+ * maximum of 0 sentences or lines stops before the first one
+*/
+pub fn generate_text_constrained(model: &FiniteContextModelBackoff, seed: &str, length: usize, constraints: &GenerationConstraints) -> GenerationResult {
     let mut generated_text = String::from(seed);
-    let mut context: String;
     let k = model.get_k();
-    if seed.len() < k {
-        context = seed.to_string(); // Return the whole string if it's too short
-    } else {
-        context = seed[seed.len() - k..].to_string();
+    let mut context: Vec<char> = seed.chars().collect();
+    if context.len() > k {
+        context.drain(..context.len() - k); // Keep only the last k chars of the seed
     }
-    
-    println!("context is {}",context);
-    println!("model loaded is {}",model.get_k());
+
+    if constraints.max_sentences == Some(0) {
+        return GenerationResult { text: generated_text, stop_reason: StopReason::MaxSentences };
+    }
+    if constraints.max_lines == Some(0) {
+        return GenerationResult { text: generated_text, stop_reason: StopReason::MaxLines };
+    }
+
+    let mut sentences = 0;
+    let mut lines = 0;
 
     for _ in 0..length {
-        let next_char = model.sample_next_char(&context);
+        let next_char = match model.sample_next_char_where(&context.iter().collect::<String>(), |symbol| constraints.allows(symbol)) {
+            Some(symbol) => symbol,
+            None => return GenerationResult { text: generated_text, stop_reason: StopReason::NoAllowedSymbol },
+        };
         generated_text.push(next_char);
-        if generated_text.len() == original_k{
-            model = models.get(&original_k).unwrap();
-            println!("Changed to model {}",model.get_k());
-            context = generated_text[generated_text.len() - model.get_k()..].to_string();
-            println!("context length is {}", context.len());
-        }
         context.push(next_char);
 
-        if context.len() > model.get_k() {
+        if context.len() > k {
             context.remove(0); // Keep the context length at k
         }
+
+        if let Some(stop_sequence) = constraints.stop_sequences.iter().find(|stop| !stop.is_empty() && generated_text.ends_with(stop.as_str())) {
+            return GenerationResult { text: generated_text, stop_reason: StopReason::StopSequence(stop_sequence.clone()) };
+        }
+
+        if matches!(next_char, '.' | '!' | '?') {
+            sentences += 1;
+            if constraints.max_sentences.is_some_and(|max| sentences >= max) {
+                return GenerationResult { text: generated_text, stop_reason: StopReason::MaxSentences };
+            }
+        }
+
+        if next_char == '\n' {
+            lines += 1;
+            if constraints.max_lines.is_some_and(|max| lines >= max) {
+                return GenerationResult { text: generated_text, stop_reason: StopReason::MaxLines };
+            }
+        }
     }
 
-    generated_text
+    GenerationResult { text: generated_text, stop_reason: StopReason::Length }
 }
 
 pub fn generate_text_words(models: HashMap<usize, FiniteContextModelWords>, seed: &str, length: usize, original_k: usize) -> String {
@@ -48,7 +162,8 @@ pub fn generate_text_words(models: HashMap<usize, FiniteContextModelWords>, seed
         context.push(String::from(word));
     };
     if context.len() < original_k{
-        model = models.get(&context.len()).unwrap();
+        // A loaded model comes without the shorter order, so the full order is used from the start
+        model = models.get(&context.len()).unwrap_or_else(|| models.get(&original_k).unwrap());
     }else {
         model = models.get(&original_k).unwrap();
     }
@@ -57,9 +172,7 @@ pub fn generate_text_words(models: HashMap<usize, FiniteContextModelWords>, seed
     while context.len() > k {
         context.remove(0);
     }
-    
-    println!("context is {}",&context.join(" "));
-    println!("model loaded is {}",model.get_k());
+
     let mut generated_text_length = seed.split(' ').count();
 
     for _ in 0..length {
@@ -69,7 +182,6 @@ pub fn generate_text_words(models: HashMap<usize, FiniteContextModelWords>, seed
         generated_text_length += 1;
         if generated_text_length == original_k {
             model = models.get(&original_k).unwrap();
-            println!("Changed to model {}",model.get_k());
             context = generated_text.split(' ').map(String::from).collect();
         }
 
@@ -81,4 +193,266 @@ pub fn generate_text_words(models: HashMap<usize, FiniteContextModelWords>, seed
     }
 
     generated_text
-}
\ No newline at end of file
+}
+
+/*
+ * Finds the `top_n` most probable continuations of `length`
+ * characters after the seed, keeping at most `beam_width`
+ * partial candidates at each step. Each symbol is scored by
+ * the longest context of the chain that has counts
+*/
+pub fn beam_search(model: &FiniteContextModelBackoff, seed: &str, length: usize, beam_width: usize, top_n: usize) -> Vec<Continuation> {
+    let mut symbols: Vec<char> = model.get_model(0).map_or_else(Vec::new, |order_0| order_0.get_symbols().iter().copied().collect());
+    symbols.sort();
+    let k = model.get_k();
+
+    let beams = beam_search_symbols(seed.chars().collect(), length, beam_width.max(top_n), |sequence: &[char]| {
+        let context: String = sequence[sequence.len().saturating_sub(k)..].iter().collect();
+        let (order_model, suffix) = model.longest_known_context(&context);
+        symbols.iter()
+            .map(|&symbol| (symbol, -order_model.compute_probability(&suffix, symbol).log2()))
+            .collect()
+    });
+
+    let seed_length = seed.chars().count();
+    beams.into_iter()
+        .take(top_n)
+        .map(|(sequence, bits)| Continuation {
+            text: sequence[seed_length..].iter().collect(),
+            bits,
+        })
+        .collect()
+}
+
+/*
+ * Finds the `top_n` most probable continuations of `length`
+ * words after the seed, keeping at most `beam_width`
+ * partial candidates at each step
+*/
+pub fn beam_search_words(model: &FiniteContextModelWords, seed: &str, length: usize, beam_width: usize, top_n: usize) -> Vec<Continuation> {
+    let mut symbols: Vec<String> = model.get_symbols().clone();
+    symbols.sort();
+    let k = model.get_k();
+
+    let seed_words: Vec<String> = seed.split_whitespace().map(String::from).collect();
+    let seed_length = seed_words.len();
+
+    let beams = beam_search_symbols(seed_words, length, beam_width.max(top_n), |sequence: &[String]| {
+        let context = sequence[sequence.len().saturating_sub(k)..].join(" ");
+        symbols.iter()
+            .map(|symbol| (symbol.clone(), -model.compute_probability(&context, symbol).log2()))
+            .collect()
+    });
+
+    beams.into_iter()
+        .take(top_n)
+        .map(|(sequence, bits)| Continuation {
+            text: sequence[seed_length..].join(" "),
+            bits,
+        })
+        .collect()
+}
+
+/*
+ * Beam search over sequences of symbols: every candidate is
+ * extended with each symbol returned by `next_symbols` and only
+ * the `beam_width` cheapest candidates (in bits) are kept.
+ * Symbols with a zero or undefined probability (e.g. alpha = 0)
+ * have no finite cost and are never used
+*/
+fn beam_search_symbols<S, F>(seed: Vec<S>, length: usize, beam_width: usize, next_symbols: F) -> Vec<(Vec<S>, f64)>
+where
+    S: Clone + Ord,
+    F: Fn(&[S]) -> Vec<(S, f64)>,
+{
+    let mut beams: Vec<(Vec<S>, f64)> = vec![(seed, 0.0)];
+
+    for _ in 0..length {
+        let mut candidates: Vec<(Vec<S>, f64)> = Vec::new();
+
+        for (sequence, bits) in &beams {
+            for (symbol, symbol_bits) in next_symbols(sequence).into_iter().filter(|(_, symbol_bits)| symbol_bits.is_finite()) {
+                let mut extended = sequence.clone();
+                extended.push(symbol);
+                candidates.push((extended, bits + symbol_bits));
+            }
+        }
+
+        if candidates.is_empty() {
+            break; // The model has no symbols to extend with
+        }
+
+        // Ties are broken by the sequence itself so results are deterministic
+        candidates.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        candidates.truncate(beam_width);
+        beams = candidates;
+    }
+
+    beams
+}
+
+
+/*
+ * Generates `length` characters after the seed, sampling each
+ * one from the weighted mixture of the models' next-character
+ * distributions (e.g. to blend the style of two authors)
+*/
+pub fn generate_text_mixture(models: &[FiniteContextModelBackoff], schedule: &WeightSchedule, seed: &str, length: usize) -> String {
+    let mut generated_text = String::from(seed);
+    let k = models.iter().map(|model| model.get_k()).max().unwrap_or(0);
+    let mut context: Vec<char> = seed.chars().collect();
+    if context.len() > k {
+        context.drain(..context.len() - k); // Keep only the last k chars of the seed
+    }
+
+    for step in 0..length {
+        let context_str: String = context.iter().collect();
+        let distributions: Vec<HashMap<char, f64>> = models.iter()
+            .map(|model| model.next_char_distribution(&context_str))
+            .collect();
+
+        let next_char = match sample_mixture(&distributions, &schedule.weights_at(step, length)) {
+            Some(symbol) => symbol,
+            None => break, // None of the models was trained
+        };
+        generated_text.push(next_char);
+        context.push(next_char);
+
+        if context.len() > k {
+            context.remove(0); // Keep the context length at k
+        }
+    }
+
+    generated_text
+}
+
+/*
+ * Generates `length` words after the seed, sampling each
+ * one from the weighted mixture of the models' next-word
+ * distributions
+*/
+pub fn generate_text_words_mixture(models: &[FiniteContextModelWords], schedule: &WeightSchedule, seed: &str, length: usize) -> String {
+    let mut generated_text = String::from(seed);
+    let k = models.iter().map(|model| model.get_k()).max().unwrap_or(0);
+    let mut context: VecDeque<String> = seed.split_whitespace().map(String::from).collect();
+    while context.len() > k {
+        context.pop_front(); // Keep only the last k words of the seed
+    }
+
+    for step in 0..length {
+        let distributions: Vec<HashMap<String, f64>> = models.iter()
+            .map(|model| {
+                let start = context.len().saturating_sub(model.get_k());
+                let words: Vec<&str> = context.range(start..).map(String::as_str).collect();
+                model.next_word_distribution(&words.join(" "))
+            })
+            .collect();
+
+        let next_word = match sample_mixture(&distributions, &schedule.weights_at(step, length)) {
+            Some(word) => word,
+            None => {
+                // No model has seen the context, pick any known word
+                let symbols: Vec<&String> = models.iter().flat_map(|model| model.get_symbols()).collect();
+                if symbols.is_empty() {
+                    break;
+                }
+                symbols[rand::rng().random_range(0..symbols.len())].clone()
+            }
+        };
+        generated_text += " ";
+        generated_text += next_word.as_str();
+        context.push_back(next_word);
+
+        if context.len() > k {
+            context.pop_front(); // Keep the context length at the highest k
+        }
+    }
+
+    generated_text
+}
+
+/*
+ * Samples a symbol from the weighted mixture of the
+ * distributions. Models with an empty distribution
+ * give their weight to the others
+*/
+fn sample_mixture<S: Clone + Eq + Hash>(distributions: &[HashMap<S, f64>], weights: &[f64]) -> Option<S> {
+    let mut mixture: HashMap<S, f64> = HashMap::new();
+    let mut total_weight = 0.0;
+
+    for (distribution, &weight) in distributions.iter().zip(weights) {
+        if distribution.is_empty() || weight <= 0.0 {
+            continue;
+        }
+        total_weight += weight;
+        for (symbol, probability) in distribution {
+            *mixture.entry(symbol.clone()).or_insert(0.0) += weight * probability;
+        }
+    }
+
+    if total_weight == 0.0 {
+        return None;
+    }
+
+    let threshold = rand::rng().random::<f64>() * total_weight;
+    let mut cumulative_probability = 0.0;
+    let mut last = None;
+
+    for (symbol, probability) in mixture {
+        cumulative_probability += probability;
+        if threshold <= cumulative_probability {
+            return Some(symbol);
+        }
+        last = Some(symbol);
+    }
+
+    last // Rounding fallback
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trained(text: &str, k: usize, alpha: f64) -> FiniteContextModelBackoff {
+        let mut model = FiniteContextModelBackoff::new(k, alpha);
+        text.chars().for_each(|symbol| model.train_char(symbol));
+        model
+    }
+
+    #[test]
+    fn beam_search_backs_off_from_unseen_contexts() {
+        // Without smoothing the unseen context "ca" has no defined probability at order 2
+        let model = trained("abababab", 2, 0.0);
+        let results = beam_search(&model, "ca", 3, 4, 1);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].text, "bab");
+        assert!(results[0].bits.is_finite());
+    }
+
+    #[test]
+    fn zero_sentences_stops_before_generating() {
+        let model = trained("one. two. three.", 2, 0.01);
+        let constraints = GenerationConstraints { max_sentences: Some(0), ..Default::default() };
+        let result = generate_text_constrained(&model, "on", 50, &constraints);
+        assert_eq!(result.text, "on");
+        assert_eq!(result.stop_reason, StopReason::MaxSentences);
+    }
+
+    #[test]
+    fn stops_after_the_requested_sentences() {
+        let model = trained("one. two. three.", 2, 0.01);
+        let constraints = GenerationConstraints { max_sentences: Some(2), ..Default::default() };
+        let result = generate_text_constrained(&model, "on", 500, &constraints);
+        assert_eq!(result.stop_reason, StopReason::MaxSentences);
+        assert_eq!(result.text.matches('.').count(), 2);
+        assert!(result.text.ends_with('.'));
+    }
+
+    #[test]
+    fn never_emits_symbols_outside_the_alphabet() {
+        let model = trained("acgtnacgtnacgt", 1, 0.01);
+        let constraints = GenerationConstraints { alphabet: Some("acgt".chars().collect()), ..Default::default() };
+        let result = generate_text_constrained(&model, "a", 200, &constraints);
+        assert!(result.text.chars().all(|symbol| "acgt".contains(symbol)));
+    }
+}